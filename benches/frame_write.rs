@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hbtp::socks::msg::tcps;
+use ruisutil::asyncs::{
+    net::{TcpListener, TcpStream},
+    task, AsyncReadExt,
+};
+use ruisutil::bytes;
+
+async fn pipe() -> TcpStream {
+    let lsr = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = lsr.local_addr().unwrap();
+    task::spawn(async move {
+        if let Ok((mut conn, _)) = lsr.accept().await {
+            let mut buf = vec![0u8; 1024 * 64];
+            while let Ok(n) = conn.read(&mut buf).await {
+                if n <= 0 {
+                    break;
+                }
+            }
+        }
+    });
+    let conn = TcpStream::connect(addr).await.unwrap();
+    let _ = conn.set_nodelay(true);
+    conn
+}
+
+//旧实现: 每段单独 write
+async fn send_pieces(
+    ctx: &ruisutil::asyncs::Context,
+    conn: &mut TcpStream,
+    cmds: &str,
+    hds: &[u8],
+    bds: &[u8],
+) -> std::io::Result<()> {
+    let mut info = hbtp::socks::msg::entity::MsgInfo::new();
+    info.version = 1;
+    info.control = 1;
+    info.len_cmd = cmds.len() as u16;
+    info.len_head = hds.len() as u32;
+    info.len_body = bds.len() as u32;
+    ruisutil::write_all_async(ctx, conn, &[0x8du8, 0x8fu8]).await?;
+    ruisutil::write_all_async(ctx, conn, &info.encode()[..]).await?;
+    ruisutil::write_all_async(ctx, conn, cmds.as_bytes()).await?;
+    ruisutil::write_all_async(ctx, conn, hds).await?;
+    ruisutil::write_all_async(ctx, conn, bds).await?;
+    ruisutil::write_all_async(ctx, conn, &[0x8eu8, 0x8fu8]).await
+}
+
+fn frame_write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_write");
+    group.measurement_time(Duration::from_secs(5));
+    for size in [16usize, 1024, 1024 * 256] {
+        let hds = bytes::Bytes::from(vec![1u8; 32]);
+        let bds = vec![2u8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("pieces", size), &size, |b, _| {
+            let ctx = ruisutil::asyncs::Context::new();
+            let mut conn = ruisutil::asyncs::current_block_on(pipe());
+            b.iter(|| {
+                ruisutil::asyncs::current_block_on(send_pieces(
+                    &ctx,
+                    &mut conn,
+                    "bench",
+                    &hds[..],
+                    &bds[..],
+                ))
+                .unwrap()
+            });
+        });
+        group.bench_with_input(BenchmarkId::new("vectored", size), &size, |b, _| {
+            let ctx = ruisutil::asyncs::Context::new();
+            let mut conn = ruisutil::asyncs::current_block_on(pipe());
+            b.iter(|| {
+                ruisutil::asyncs::current_block_on(tcps::send_msg(
+                    &ctx,
+                    &mut conn,
+                    1,
+                    Some("bench".into()),
+                    &Some(hds.clone()),
+                    Some(&bds[..]),
+                ))
+                .unwrap()
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, frame_write);
+criterion_main!(benches);
@@ -0,0 +1,181 @@
+use std::{io, time::Duration};
+
+use ruisutil::asyncs::AsyncReadExt;
+
+use crate::socks::msg::compress::Compress;
+
+/*
+  v3 扩展区, 紧跟 "HBTP" 之后, 小端序:
+  0 flags   u16
+  2 len_ext u16
+  4 tlv...  type(1) + len(2) + value
+  type 最高位为 1 的扩展必须识别, 否则拒绝; 其余不认识的跳过
+*/
+pub const EXT_HEAD_LEN: usize = 4;
+pub const EXT_MAX: usize = 1024 * 8;
+
+pub const EXT_REQ_ID: u8 = 1;
+pub const EXT_COMPRESS: u8 = 2;
+pub const EXT_ENCODING: u8 = 3;
+pub const EXT_DEADLINE: u8 = 4;
+pub const EXT_TRACE_PARENT: u8 = 5;
+pub const EXT_TRACE_STATE: u8 = 6;
+pub const EXT_CRITICAL: u8 = 0x80;
+
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct MsgExt {
+    pub flags: u16,
+    pub req_id: Option<u64>,
+    //body 的压缩算法
+    pub compress: Option<Compress>,
+    pub encoding: Option<String>,
+    //发送时剩余的毫秒数: 相对时长, 不依赖两端时钟一致, 接收端换算成本地单调时钟
+    pub deadline: Option<u64>,
+    pub trace_parent: Option<String>,
+    pub trace_state: Option<String>,
+}
+impl MsgExt {
+    pub fn is_empty(&self) -> bool {
+        self.eq(&Self::default())
+    }
+    pub fn deadline_in(&self) -> Option<Duration> {
+        self.deadline.map(Duration::from_millis)
+    }
+    pub fn set_deadline_in(&mut self, tm: Duration) {
+        self.deadline = Some(tm.as_millis() as u64);
+    }
+
+    fn tlv(buf: &mut Vec<u8>, tp: u8, v: &[u8]) -> io::Result<()> {
+        if v.len() > u16::MAX as usize {
+            return Err(ruisutil::ioerr(format!("ext {} len err", tp), None));
+        }
+        buf.push(tp);
+        buf.extend_from_slice(&(v.len() as u16).to_le_bytes());
+        buf.extend_from_slice(v);
+        Ok(())
+    }
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut tlvs = Vec::new();
+        if let Some(v) = self.req_id {
+            Self::tlv(&mut tlvs, EXT_REQ_ID, &v.to_le_bytes())?;
+        }
+        if let Some(v) = self.compress {
+            if v != Compress::None {
+                Self::tlv(&mut tlvs, EXT_COMPRESS | EXT_CRITICAL, &[v.flags()])?;
+            }
+        }
+        if let Some(v) = &self.encoding {
+            Self::tlv(&mut tlvs, EXT_ENCODING, v.as_bytes())?;
+        }
+        if let Some(v) = self.deadline {
+            Self::tlv(&mut tlvs, EXT_DEADLINE, &v.to_le_bytes())?;
+        }
+        if let Some(v) = &self.trace_parent {
+            Self::tlv(&mut tlvs, EXT_TRACE_PARENT, v.as_bytes())?;
+        }
+        if let Some(v) = &self.trace_state {
+            Self::tlv(&mut tlvs, EXT_TRACE_STATE, v.as_bytes())?;
+        }
+        if tlvs.len() > EXT_MAX {
+            return Err(ruisutil::ioerr("ext out limit!!", None));
+        }
+        let mut rt = Vec::with_capacity(EXT_HEAD_LEN + tlvs.len());
+        rt.extend_from_slice(&self.flags.to_le_bytes());
+        rt.extend_from_slice(&(tlvs.len() as u16).to_le_bytes());
+        rt.extend_from_slice(&tlvs[..]);
+        Ok(rt)
+    }
+
+    //返回 flags 和 tlv 区长度
+    pub fn decode_head(bts: &[u8]) -> io::Result<(u16, usize)> {
+        if bts.len() < EXT_HEAD_LEN {
+            return Err(ruisutil::ioerr(
+                "MsgExt len err",
+                Some(io::ErrorKind::UnexpectedEof),
+            ));
+        }
+        let ln = u16::from_le_bytes([bts[2], bts[3]]) as usize;
+        if ln > EXT_MAX {
+            return Err(ruisutil::ioerr("ext out limit!!", None));
+        }
+        Ok((u16::from_le_bytes([bts[0], bts[1]]), ln))
+    }
+    pub fn decode_tlvs(flags: u16, mut bts: &[u8]) -> io::Result<Self> {
+        let mut rt = Self::default();
+        rt.flags = flags;
+        while !bts.is_empty() {
+            if bts.len() < 3 {
+                return Err(ruisutil::ioerr("ext tlv err", None));
+            }
+            let tp = bts[0];
+            let ln = u16::from_le_bytes([bts[1], bts[2]]) as usize;
+            if bts.len() < 3 + ln {
+                return Err(ruisutil::ioerr("ext tlv err", None));
+            }
+            let v = &bts[3..3 + ln];
+            bts = &bts[3 + ln..];
+            match tp & !EXT_CRITICAL {
+                EXT_REQ_ID => rt.req_id = Some(Self::u64_parse(tp, v)?),
+                EXT_COMPRESS => {
+                    if v.len() != 1 {
+                        return Err(ruisutil::ioerr("ext compress err", None));
+                    }
+                    rt.compress = Some(Compress::from_flags(v[0])?);
+                }
+                EXT_ENCODING => rt.encoding = Some(Self::str_parse(tp, v)?),
+                EXT_DEADLINE => rt.deadline = Some(Self::u64_parse(tp, v)?),
+                EXT_TRACE_PARENT => rt.trace_parent = Some(Self::str_parse(tp, v)?),
+                EXT_TRACE_STATE => rt.trace_state = Some(Self::str_parse(tp, v)?),
+                _ => {
+                    if tp & EXT_CRITICAL != 0 {
+                        return Err(ruisutil::ioerr(
+                            format!("unknown critical ext:{}", tp),
+                            Some(io::ErrorKind::InvalidData),
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(rt)
+    }
+    pub fn decode(bts: &[u8]) -> io::Result<Self> {
+        let (flags, ln) = Self::decode_head(bts)?;
+        if bts.len() < EXT_HEAD_LEN + ln {
+            return Err(ruisutil::ioerr(
+                "MsgExt len err",
+                Some(io::ErrorKind::UnexpectedEof),
+            ));
+        }
+        Self::decode_tlvs(flags, &bts[EXT_HEAD_LEN..EXT_HEAD_LEN + ln])
+    }
+    pub(crate) async fn read<R: AsyncReadExt + Unpin>(
+        ctx: &ruisutil::asyncs::Context,
+        conn: &mut R,
+    ) -> io::Result<Self> {
+        let bts = crate::iovec::read_full(ctx, conn, EXT_HEAD_LEN).await?;
+        let (flags, ln) = Self::decode_head(&bts[..])?;
+        if ln == 0 {
+            return Ok(Self {
+                flags: flags,
+                ..Default::default()
+            });
+        }
+        let bts = crate::iovec::read_full(ctx, conn, ln).await?;
+        Self::decode_tlvs(flags, &bts[..])
+    }
+
+    fn u64_parse(tp: u8, v: &[u8]) -> io::Result<u64> {
+        if v.len() != 8 {
+            return Err(ruisutil::ioerr(format!("ext {} len err", tp), None));
+        }
+        let mut bts = [0u8; 8];
+        bts.copy_from_slice(v);
+        Ok(u64::from_le_bytes(bts))
+    }
+    fn str_parse(tp: u8, v: &[u8]) -> io::Result<String> {
+        match std::str::from_utf8(v) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => Err(ruisutil::ioerr(format!("ext {} utf8 err", tp), None)),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::{io, time::Duration};
+
+use ruisutil::bytes::{self, ByteSteamBuf};
+
+use crate::{
+    res,
+    socks::msg::{tcps, udps, MAX_BODYS},
+};
+
+/*
+  供 fuzz/ 下的 cargo-fuzz 目标调用: 以内存数据代替连接驱动各解析器,
+  解析错误是正常结果, 只关心 panic 和超大分配
+*/
+fn block<F: std::future::Future<Output = io::Result<()>>>(f: F) {
+    let _ = ruisutil::asyncs::current_block_on(async move {
+        let _ = f.await;
+    });
+}
+
+//走 Context::parse_conn 的完整路径, 包括不支持版本时的应答
+pub fn parse_conn(data: &[u8]) {
+    block(async {
+        let ctx = ruisutil::asyncs::Context::new();
+        let egn = crate::Engine::new("");
+        //写入全部数据后关闭写端, 不足时读到 EOF 而不是等超时; 容量足够放下应答
+        let (mut cli, serv) = crate::duplex(data.len() + 1024);
+        crate::iovec::write_all_vectored(&ctx, &mut cli, &[data]).await?;
+        cli.close_wr();
+        let c = crate::Context::parse_conn(&ctx, &egn, Box::new(serv)).await?;
+        let _ = c.get_bodys(&None).await;
+        Ok(())
+    });
+}
+
+//首字节选择请求所用的版本
+pub fn parse_response(data: &[u8]) {
+    if data.len() < 1 {
+        return;
+    }
+    block(async {
+        let ctx = ruisutil::asyncs::Context::new();
+        let req = crate::Request::new("", 1);
+        let ver = (data[0] % crate::HBTP_VER_MAX as u8) as u16 + 1;
+        let mut rd = &data[1..];
+        let (info, _, ext) = req.parse_head(ver, &mut rd).await?;
+        let bts = crate::iovec::read_full(&ctx, &mut rd, info.len_body as usize).await?;
+        res::body_decompress(&ext, bts, crate::LmtMaxConfig::default().max_bodys)?;
+        Ok(())
+    });
+}
+
+pub fn parse_msg(data: &[u8]) {
+    block(async {
+        let ctx = ruisutil::asyncs::Context::new();
+        let mut rd = data;
+        loop {
+            tcps::parse_msg(&ctx, &mut rd).await?;
+        }
+    });
+}
+
+//首字节非 0 时出错后走 resync
+pub fn parse_steam_msg(data: &[u8]) {
+    if data.len() < 1 {
+        return;
+    }
+    block(async {
+        let ctx = ruisutil::asyncs::Context::new();
+        let buf = ByteSteamBuf::new(&ctx, 1024, Duration::from_millis(100));
+        if data.len() > 1 {
+            buf.push(bytes::Bytes::from(data[1..].to_vec())).await?;
+        }
+        buf.close();
+        let resync = data[0] != 0;
+        let mut win = Vec::new();
+        let mut dropped = 0;
+        loop {
+            if !win.is_empty() {
+                tcps::resync_steam_msg(&ctx, &buf, &mut win, &mut dropped).await?;
+                continue;
+            }
+            match tcps::parse_steam_msg_max(&ctx, &buf, MAX_BODYS, resync).await {
+                Ok(_) => {}
+                Err(e) if resync && e.kind() == io::ErrorKind::InvalidData => {
+                    tcps::resync_steam_msg(&ctx, &buf, &mut win, &mut dropped).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    });
+}
+
+pub fn packet_parse(data: &[u8]) {
+    if let Ok(v) = udps::packet_parse(bytes::Bytes::from(data.to_vec())) {
+        let _ = udps::msg_parse(v.data);
+    }
+}
+
+pub fn msg_parse(data: &[u8]) {
+    let _ = udps::msg_parse(bytes::Bytes::from(data.to_vec()));
+}
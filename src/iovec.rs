@@ -0,0 +1,130 @@
+use std::{
+    io::{self, IoSlice},
+    time::Duration,
+};
+
+use ruisutil::asyncs::{AsyncReadExt, AsyncWriteExt};
+
+pub(crate) const READ_CHUNK: usize = 64 * 1024;
+
+//将多段数据合并为 write_vectored 写出, 避免逐段 write 的系统调用
+pub(crate) async fn write_all_vectored<W: AsyncWriteExt + Unpin>(
+    ctx: &ruisutil::asyncs::Context,
+    conn: &mut W,
+    bufs: &[&[u8]],
+) -> io::Result<()> {
+    let mut idx = 0;
+    let mut off = 0;
+    ctx.wait_futs(async {
+        loop {
+            skip_empty(bufs, &mut idx, &mut off);
+            if idx >= bufs.len() {
+                return Ok(());
+            }
+            let ios = io_slices(bufs, idx, off);
+            let n = conn.write_vectored(&ios[..]).await?;
+            advance(bufs, &mut idx, &mut off, n)?;
+        }
+    })
+    .await
+}
+
+//分阶段超时: phases 为 (超时, 该阶段的数据段), 写到某阶段时才以 ctx 创建该阶段的 child_timeout,
+//与逐段 write_all 的超时语义一致; 每次仍把剩余所有数据段合并写出
+pub(crate) async fn write_all_phases<W: AsyncWriteExt + Unpin>(
+    ctx: &ruisutil::asyncs::Context,
+    conn: &mut W,
+    phases: &[(Duration, Vec<&[u8]>)],
+) -> io::Result<()> {
+    let mut bufs: Vec<&[u8]> = Vec::new();
+    let mut phs = Vec::new();
+    for (i, (_, ls)) in phases.iter().enumerate() {
+        for it in ls {
+            bufs.push(*it);
+            phs.push(i);
+        }
+    }
+    let mut idx = 0;
+    let mut off = 0;
+    let mut cur: Option<(usize, ruisutil::asyncs::Context)> = None;
+    loop {
+        skip_empty(&bufs[..], &mut idx, &mut off);
+        if idx >= bufs.len() {
+            return Ok(());
+        }
+        let ph = phs[idx];
+        if cur.as_ref().map(|v| v.0) != Some(ph) {
+            cur = Some((ph, ctx.child_timeout(phases[ph].0)));
+        }
+        let ios = io_slices(&bufs[..], idx, off);
+        let n = match &cur {
+            Some((_, ctxs)) => ctxs.wait_futs(conn.write_vectored(&ios[..])).await?,
+            None => 0,
+        };
+        advance(&bufs[..], &mut idx, &mut off, n)?;
+    }
+}
+
+fn skip_empty(bufs: &[&[u8]], idx: &mut usize, off: &mut usize) {
+    while *idx < bufs.len() && *off >= bufs[*idx].len() {
+        *idx += 1;
+        *off = 0;
+    }
+}
+fn io_slices<'a>(bufs: &[&'a [u8]], idx: usize, off: usize) -> Vec<IoSlice<'a>> {
+    let mut ios = Vec::with_capacity(bufs.len() - idx);
+    ios.push(IoSlice::new(&bufs[idx][off..]));
+    for it in &bufs[idx + 1..] {
+        if it.len() > 0 {
+            ios.push(IoSlice::new(it));
+        }
+    }
+    ios
+}
+fn advance(bufs: &[&[u8]], idx: &mut usize, off: &mut usize, n: usize) -> io::Result<()> {
+    if n <= 0 {
+        return Err(ruisutil::ioerr(
+            "write vectored size=0 err!!",
+            Some(io::ErrorKind::WriteZero),
+        ));
+    }
+    let mut n = n;
+    while n > 0 && *idx < bufs.len() {
+        let ln = bufs[*idx].len() - *off;
+        if n < ln {
+            *off += n;
+            n = 0;
+        } else {
+            n -= ln;
+            *idx += 1;
+            *off = 0;
+        }
+    }
+    Ok(())
+}
+
+//读满 n 字节; 按块扩容, 内存随实际收到的数据增长, 不按对端声明的长度一次分配
+pub(crate) async fn read_full<R: AsyncReadExt + Unpin>(
+    ctx: &ruisutil::asyncs::Context,
+    conn: &mut R,
+    n: usize,
+) -> io::Result<Vec<u8>> {
+    let mut rt = Vec::with_capacity(n.min(READ_CHUNK));
+    ctx.wait_futs(async {
+        while rt.len() < n {
+            let ln = rt.len();
+            rt.resize(ln + (n - ln).min(READ_CHUNK), 0);
+            let sz = conn.read(&mut rt[ln..]).await?;
+            rt.truncate(ln + sz);
+            if sz == 0 {
+                return Err(ruisutil::ioerr(
+                    "read size=0 err!!",
+                    Some(io::ErrorKind::UnexpectedEof),
+                ));
+            }
+        }
+        Ok(())
+    })
+    .await?;
+    Ok(rt)
+}
@@ -6,25 +6,32 @@
 
 use std::{collections::HashMap, io, time::Duration};
 
-use ruisutil::asyncs::{
-    net::{TcpListener, TcpStream},
-    sync::RwLock,
-    task,
-};
+use ruisutil::asyncs::{net::TcpListener, sync::RwLock, task};
 use ruisutil::asyncs::{BoxFuture, Future};
 
+pub use ext::MsgExt;
 pub use maps::{ArraJMaps, JMaps};
+pub use pipe::{duplex, Pipe};
 pub use qstring::QString;
 pub use req::Request;
 pub use req::Response;
 pub use res::Context;
-pub use res::{LmtMaxConfig, LmtTmConfig};
+pub use res::{LmtMaxConfig, LmtTmConfig, HBTP_VER_MAX};
+pub use trace::{TraceContext, TraceParent};
+pub use transport::{AsyncRead, AsyncWrite, BoxStream, Listener, PeerCred, Stream};
 
+mod ext;
+mod iovec;
 mod maps;
+mod pipe;
 mod req;
 mod res;
+mod trace;
+mod transport;
 
 pub mod socks;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
 
 #[cfg(test)]
 mod tests {
@@ -76,72 +83,1073 @@ mod tests {
         thread::sleep_ms(500);
     } */
 
+    //----------------------------------conformance
+    async fn conf_echo(c: crate::Context) -> std::io::Result<()> {
+        let hds = c.get_heads().clone();
+        let bds = c.get_bodys(&None).await.clone();
+        c.response(
+            crate::ResCodeOk,
+            hds.as_ref().map(|v| &v[..]),
+            bds.as_ref().map(|v| &v[..]),
+        )
+        .await
+    }
+    async fn conf_info(c: crate::Context) -> std::io::Result<()> {
+        let bds = c.body_str().await.unwrap_or_default();
+        let v = serde_json::json!({
+            "cmd": c.command(),
+            "arg": c.get_arg("hehe"),
+            "ver": c.version(),
+            "req_id": c.ext().req_id,
+            "encoding": c.ext().encoding,
+            "remaining": c.remaining().map(|v| v.as_millis() as u64),
+            "body": bds,
+        });
+        c.res_json(crate::ResCodeOk, &v).await
+    }
+    async fn conf_err(_: crate::Context) -> std::io::Result<()> {
+        Err(ruisutil::ioerr("conf err", None))
+    }
+    async fn conf_sleep(c: crate::Context) -> std::io::Result<()> {
+        ruisutil::asyncs::sleep(Duration::from_millis(1000)).await;
+        c.res_string(crate::ResCodeOk, "wake").await
+    }
+    async fn conf_bytes(c: crate::Context) -> std::io::Result<()> {
+        c.res_bytes(crate::ResCodeOk, vec![7u8; 1024]).await
+    }
+    static SLOW_DONE: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    async fn conf_slow(c: crate::Context) -> std::io::Result<()> {
+        ruisutil::asyncs::sleep(Duration::from_millis(800)).await;
+        SLOW_DONE.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        c.res_string(crate::ResCodeOk, "slow").await
+    }
+    async fn conf_trace(c: crate::Context) -> std::io::Result<()> {
+        let v = serde_json::json!({
+            "trace": c.trace().map(|v| v.parent.to_string()),
+            "state": c.trace().and_then(|v| v.state.clone()),
+            "span": c.span().parent.to_string(),
+        });
+        c.res_json(crate::ResCodeOk, &v).await
+    }
+    static WATCH_DONE: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    async fn conf_watch(c: crate::Context) -> std::io::Result<()> {
+        c.watch_peer().await;
+        let bds = c.body_str().await.unwrap_or_default();
+        ruisutil::asyncs::sleep(Duration::from_millis(800)).await;
+        WATCH_DONE.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        c.res_string(crate::ResCodeOk, bds).await
+    }
+    async fn conf_egn() -> Engine {
+        let egn = Engine::new("127.0.0.1:0");
+        egn.reg_fun(1, conf_echo, None).await;
+        egn.reg_fun(2, conf_info, None).await;
+        egn.reg_fun(3, conf_err, None).await;
+        egn.reg_fun(4, conf_sleep, None).await;
+        egn.reg_fun(5, conf_bytes, None).await;
+        let lmt = crate::LmtMaxConfig {
+            max_ohther: 8,
+            max_heads: 8,
+            max_bodys: 64,
+        };
+        egn.reg_fun(6, conf_echo, Some(lmt)).await;
+        egn.reg_fun(7, conf_slow, None).await;
+        egn.reg_fun(8, conf_watch, None).await;
+        egn.reg_fun(9, conf_trace, None).await;
+        egn
+    }
+    async fn conf_serv() -> (Engine, String) {
+        use ruisutil::asyncs::net::TcpListener;
+        let egn = conf_egn().await;
+        let lsr = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = lsr.local_addr().unwrap().to_string();
+        let c = egn.clone();
+        ruisutil::asyncs::task::spawn(async move {
+            let _ = c.run_listener(lsr).await;
+        });
+        (egn, addr)
+    }
+    //发送原始字节, 返回响应头
+    async fn conf_raw(addr: &str, bts: &[u8]) -> std::io::Result<crate::res::ResInfoV1> {
+        let ctx = ruisutil::asyncs::Context::new_timeout(Duration::from_secs(2));
+        let mut conn = ruisutil::asyncs::net::TcpStream::connect(addr).await?;
+        crate::iovec::write_all_vectored(&ctx, &mut conn, &[bts]).await?;
+        let bts = ruisutil::read_all_async(&ctx, &mut conn, crate::res::ResInfoV1::SIZE).await?;
+        crate::res::ResInfoV1::decode(&bts[..])
+    }
+    fn conf_frame(ver: u16, magic: bool, ext: &[u8], bds: &[u8]) -> Vec<u8> {
+        let mut info = crate::res::MsgInfo::new();
+        info.version = ver;
+        info.control = 1;
+        info.len_body = bds.len() as u32;
+        let mut rt = info.encode().to_vec();
+        if magic {
+            rt.extend_from_slice(&crate::res::HBTP_MAGIC);
+        }
+        rt.extend_from_slice(ext);
+        rt.extend_from_slice(bds);
+        rt
+    }
     #[test]
-    fn hbtp_server() {
-        let serv = Engine::new("0.0.0.0:7030");
-        println!("hbtp serv start!!!");
-        // let cb = move |ctx: &mut crate::Context| testFun(ctx);
-        // let fun = Box::new(cb);
-        // let func = |ctx| Box::pin(testFun(ctx));
-        let _ = ruisutil::asyncs::current_block_on(async move {
-            serv.reg_fun(1, testFun, None).await;
-            if let Err(e) = serv.run().await {
-                println!("serv run err:{}", e);
+    fn hbtp_roundtrip() {
+        ruisutil::asyncs::current_block_on(async {
+            let (egn, addr) = conf_serv().await;
+            for ver in 1..=crate::HBTP_VER_MAX {
+                let mut req = Request::new(&addr, 1);
+                req.set_use_version(ver);
+                let res = req.dors(Some(b"hds"), Some(b"bds")).await.unwrap();
+                assert_eq!(res.get_code(), crate::ResCodeOk);
+                assert_eq!(
+                    res.get_heads().as_ref().map(|v| v[..].to_vec()),
+                    Some(b"hds".to_vec())
+                );
+                assert_eq!(res.body_str().await.unwrap(), "bds");
+
+                let mut req = Request::new(&addr, 1);
+                req.set_use_version(ver);
+                let res = req.dors(None, None).await.unwrap();
+                assert_eq!(res.get_code(), crate::ResCodeOk);
+                assert!(res.get_heads().is_none());
+                assert_eq!(res.body_len(), 0);
+
+                let mut req = Request::newcmd(&addr, 2, "hello");
+                req.set_use_version(ver);
+                req.add_arg("hehe", "123");
+                let res = req.do_string(None, "dede").await.unwrap();
+                let v: serde_json::Value = res.body_json().await.unwrap();
+                assert_eq!(v["cmd"], "hello");
+                assert_eq!(v["arg"], "123");
+                assert_eq!(v["ver"], ver);
+                assert_eq!(v["body"], "dede");
+
+                let mut req = Request::new(&addr, 1);
+                req.set_use_version(ver);
+                let js = serde_json::json!({"a": 1, "b": "c"});
+                let res = req.do_json(Some(b"h"), &js).await.unwrap();
+                let v: serde_json::Value = res.body_json().await.unwrap();
+                assert_eq!(v, js);
+
+                let mut req = Request::new(&addr, 5);
+                req.set_use_version(ver);
+                let res = req.do_bytes(None, &[1, 2, 3]).await.unwrap();
+                let bds = res.get_bodys(&None).await.clone().unwrap();
+                assert_eq!(&bds[..], &[7u8; 1024][..]);
+
+                let mut req = Request::new(&addr, 1);
+                req.set_use_version(ver);
+                req.donrs(None, Some(b"later")).await.unwrap();
+                let res = req.res().await.unwrap();
+                assert_eq!(res.body_str().await.unwrap(), "later");
+            }
+
+            //扩展需显式使用 v3, 响应回显 req_id
+            let mut req = Request::newcmd(&addr, 2, "ext");
+            req.set_req_id(9);
+            assert!(req.dors(None, None).await.is_err());
+            let mut req = Request::newcmd(&addr, 2, "ext");
+            req.set_use_version(3);
+            req.set_req_id(9);
+            req.set_encoding("json");
+            let res = req.do_string(None, "dede").await.unwrap();
+            assert_eq!(res.ext().req_id, Some(9));
+            let v: serde_json::Value = res.body_json().await.unwrap();
+            assert_eq!(v["ver"], 3);
+            assert_eq!(v["req_id"], 9);
+            assert_eq!(v["encoding"], "json");
+
+            if let Some(cmp) = crate::socks::msg::Compress::supported().first() {
+                let mut req = Request::new(&addr, 2);
+                req.set_use_version(3);
+                req.set_compress(*cmp, 0);
+                let s = "dede".repeat(1000);
+                let res = req.do_string(None, &s).await.unwrap();
+                let v: serde_json::Value = res.body_json().await.unwrap();
+                assert_eq!(v["body"], s.as_str());
             }
+
+            let mut req = Request::new(&addr, 1);
+            req.set_use_version(2);
+            req.set_req_id(1);
+            assert!(req.dors(None, None).await.is_err());
+            egn.stop();
         });
     }
-    async fn testFun(c: crate::Context) -> std::io::Result<()> {
-        println!(
-            "testFun ctrl:{},cmd:{},ishell:{},arg hello1:{}",
-            c.control(),
-            c.command(),
-            c.command() == "hello",
-            c.get_arg("hehe1").unwrap().as_str()
-        );
-        if let Ok(bs) = c.body_str().await {
-            println!("req body data:{}", &bs)
-        }
-        /* if let Some(v) = c.get() {
-            let cs = v.read().await;
-            cs.get_bodys();
-        } */
-        // panic!("whats?");
-        c.res_string(crate::ResCodeOk, "hello,there is rust!!")
-            .await?;
-        Ok(())
+    #[test]
+    fn hbtp_dispatch() {
+        ruisutil::asyncs::current_block_on(async {
+            //不监听端口, 请求经内存管道直接进入处理链
+            let egn = conf_egn().await;
+            let mut req = Request::newcmd("", 2, "pipe");
+            req.add_arg("hehe", "123");
+            req.set_use_version(3);
+            req.set_req_id(3);
+            let res = egn.dispatch(req, None, Some(b"dede")).await.unwrap();
+            assert_eq!(res.get_code(), crate::ResCodeOk);
+            assert_eq!(res.ext().req_id, Some(3));
+            let v: serde_json::Value = res.body_json().await.unwrap();
+            assert_eq!(v["cmd"], "pipe");
+            assert_eq!(v["arg"], "123");
+            assert_eq!(v["body"], "dede");
+
+            let res = egn
+                .dispatch(Request::new("", 3), None, None)
+                .await
+                .unwrap();
+            assert_eq!(res.get_code(), crate::ResCodeErr);
+            let res = egn
+                .dispatch(Request::new("", 99), None, None)
+                .await
+                .unwrap();
+            assert_eq!(res.body_str().await.unwrap(), "Unknown");
+
+            //大于管道缓冲的 body, 并发请求互不干扰
+            let oks = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            for i in 0..8 {
+                let egn = egn.clone();
+                let oks = oks.clone();
+                ruisutil::asyncs::task::spawn(async move {
+                    let bds = vec![i as u8; 200 * 1024];
+                    let res = egn
+                        .dispatch(Request::new("", 1), Some(b"hds"), Some(&bds[..]))
+                        .await
+                        .unwrap();
+                    let rbds = res.get_bodys(&None).await.clone().unwrap();
+                    if rbds[..] == bds[..] {
+                        oks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    }
+                });
+            }
+            for _ in 0..100 {
+                if oks.load(std::sync::atomic::Ordering::SeqCst) >= 8 {
+                    break;
+                }
+                ruisutil::asyncs::sleep(Duration::from_millis(20)).await;
+            }
+            assert_eq!(oks.load(std::sync::atomic::Ordering::SeqCst), 8);
+
+            let mut req = Request::new_conn(egn.pipe(), 1);
+            req.set_use_version(1);
+            let res = req.do_string(None, "v1").await.unwrap();
+            assert_eq!(res.body_str().await.unwrap(), "v1");
+        });
     }
     #[test]
-    fn hbtp_request() {
+    fn hbtp_deadline() {
+        use std::sync::atomic::Ordering;
         ruisutil::asyncs::current_block_on(async {
-            let mut req = Request::new("localhost:7030", 1);
-            req.command("hello");
-            req.add_arg("hehe1", "123456789");
-            match req.do_string(None, "dedededede").await {
-                Err(e) => println!("do err:{}", e),
-                Ok(res) => {
-                    println!("res code:{}", res.get_code());
-                    if let Ok(bs) = res.body_str().await {
-                        println!("res data:{}", &bs)
+            let egn = conf_egn().await;
+            let mut req = Request::new("", 2);
+            req.timeout(Duration::from_secs(2));
+            req.set_use_version(3);
+            req.set_send_deadline(true);
+            let res = egn.dispatch(req, None, None).await.unwrap();
+            let v: serde_json::Value = res.body_json().await.unwrap();
+            assert_eq!(v["ver"], 3);
+            let ms = v["remaining"].as_u64().unwrap();
+            assert!(ms > 0 && ms <= 2000);
+
+            //v2 不带 deadline, v3 也只在 set_send_deadline 后才带
+            let res = egn.dispatch(Request::new("", 2), None, None).await.unwrap();
+            let v: serde_json::Value = res.body_json().await.unwrap();
+            assert!(v["remaining"].is_null());
+            let mut req = Request::new("", 2);
+            req.set_use_version(3);
+            let res = egn.dispatch(req, None, None).await.unwrap();
+            let v: serde_json::Value = res.body_json().await.unwrap();
+            assert!(v["remaining"].is_null());
+
+            //客户端超时后服务端中止处理
+            let mut req = Request::new("", 7);
+            req.timeout(Duration::from_millis(300));
+            req.set_use_version(3);
+            req.set_send_deadline(true);
+            assert!(egn.dispatch(req, None, None).await.is_err());
+            ruisutil::asyncs::sleep(Duration::from_millis(1000)).await;
+            assert_eq!(SLOW_DONE.load(Ordering::SeqCst), 0);
+
+            let res = egn.dispatch(Request::new("", 7), None, None).await.unwrap();
+            assert_eq!(res.body_str().await.unwrap(), "slow");
+            assert_eq!(SLOW_DONE.load(Ordering::SeqCst), 1);
+        });
+    }
+    #[test]
+    fn hbtp_peer_closed() {
+        use std::sync::atomic::Ordering;
+        ruisutil::asyncs::current_block_on(async {
+            let egn = conf_egn().await;
+            //不带 deadline, 客户端超时断开后服务端靠读端 EOF 中止处理
+            let mut req = Request::new("", 8);
+            req.timeout(Duration::from_millis(300));
+            assert!(egn.dispatch(req, None, Some(b"gone")).await.is_err());
+            ruisutil::asyncs::sleep(Duration::from_millis(1000)).await;
+            assert_eq!(WATCH_DONE.load(Ordering::SeqCst), 0);
+
+            let res = egn
+                .dispatch(Request::new("", 8), None, Some(b"stay"))
+                .await
+                .unwrap();
+            assert_eq!(res.body_str().await.unwrap(), "stay");
+            assert_eq!(WATCH_DONE.load(Ordering::SeqCst), 1);
+        });
+    }
+    #[test]
+    fn trace_parent() {
+        use crate::TraceParent;
+        let s = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let tp = TraceParent::parse(s).unwrap();
+        assert_eq!(tp.to_string(), s);
+        assert!(tp.sampled());
+        assert_eq!(tp.span_id, [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]);
+        let ch = tp.child();
+        assert_eq!(ch.trace_id, tp.trace_id);
+        assert_ne!(ch.span_id, tp.span_id);
+        //未来版本忽略多出的字段
+        let tp = TraceParent::parse("cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-x")
+            .unwrap();
+        assert_eq!(tp.version, 0xcc);
+        assert!(!tp.sampled());
+        for s in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        ] {
+            assert!(TraceParent::parse(s).is_err(), "{}", s);
+        }
+        let a = TraceParent::new_root();
+        let b = TraceParent::new_root();
+        assert_ne!(a.trace_id, b.trace_id);
+        assert_eq!(TraceParent::parse(&a.to_string()).unwrap(), a);
+
+        //Messager 消息经 heads 前缀携带, 帧头置 FLAG_TRACE
+        use crate::socks::msg::{tcps, Messages};
+        let mut tc = crate::TraceContext::new_root();
+        tc.state = Some(String::from("k=v"));
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::asyncs::Context::new();
+            for hds in [None, Some(b"hds".to_vec()), Some(b"HBTP\0\0\0\0".to_vec())] {
+                for trace in [false, true] {
+                    let mut msgs = Messages {
+                        control: 1,
+                        cmds: None,
+                        heads: hds.clone().map(ruisutil::bytes::Bytes::from),
+                        bodys: None,
+                        bodybuf: None,
+                        ..Default::default()
+                    };
+                    if trace {
+                        msgs.set_trace(&tc).unwrap();
                     }
+                    let mut buf: Vec<u8> = Vec::new();
+                    tcps::send_msgs(&ctx, &mut buf, msgs).await.unwrap();
+                    let mut msg = tcps::parse_msg(&ctx, &mut &buf[..]).await.unwrap();
+                    assert_eq!(msg.is_trace(), trace);
+                    //未置位时不解析 heads, 原样保留
+                    let want = if trace { Some(tc.clone()) } else { None };
+                    assert_eq!(msg.take_trace().unwrap(), want);
+                    assert_eq!(msg.heads.as_ref().map(|v| v[..].to_vec()), hds);
+                    assert_eq!(msg.take_trace().unwrap(), None);
                 }
+            }
+        });
+    }
+    #[test]
+    fn hbtp_trace() {
+        ruisutil::asyncs::current_block_on(async {
+            let egn = conf_egn().await;
+            let mut tc = crate::TraceContext::new_root();
+            tc.state = Some(String::from("hbtp=1"));
+            let mut req = Request::new("", 9);
+            req.set_use_version(3);
+            req.set_trace(&tc);
+            let res = egn.dispatch(req, None, None).await.unwrap();
+            //响应回显服务端 span: 同一 trace, 新的 span_id
+            let span = res.trace().unwrap();
+            assert_eq!(span.parent.trace_id, tc.parent.trace_id);
+            assert_ne!(span.parent.span_id, tc.parent.span_id);
+            assert_eq!(span.state, tc.state);
+            let v: serde_json::Value = res.body_json().await.unwrap();
+            assert_eq!(v["trace"], tc.parent.to_string());
+            assert_eq!(v["state"], "hbtp=1");
+            assert_eq!(v["span"], span.parent.to_string());
+
+            //没有或不合法的 traceparent: 另起新 trace, 不回显
+            let res = egn.dispatch(Request::new("", 9), None, None).await.unwrap();
+            assert!(res.trace().is_none());
+            let v: serde_json::Value = res.body_json().await.unwrap();
+            assert!(v["trace"].is_null());
+            assert!(v["span"].is_string());
+            let mut req = Request::new("", 9);
+            req.set_use_version(3);
+            req.ext_mut().trace_parent = Some(String::from("00-xx"));
+            let res = egn.dispatch(req, None, None).await.unwrap();
+            assert_eq!(res.get_code(), crate::ResCodeOk);
+            assert!(res.trace().is_none());
+        });
+    }
+    #[test]
+    fn hbtp_errors() {
+        ruisutil::asyncs::current_block_on(async {
+            let (egn, addr) = conf_serv().await;
+            let mut req = Request::new(&addr, 3);
+            let res = req.dors(None, None).await.unwrap();
+            assert_eq!(res.get_code(), crate::ResCodeErr);
+            assert!(res.body_str().await.unwrap().contains("conf err"));
+
+            let mut req = Request::new(&addr, 99);
+            let res = req.dors(None, None).await.unwrap();
+            assert_eq!(res.get_code(), crate::ResCodeErr);
+            assert_eq!(res.body_str().await.unwrap(), "Unknown");
+
+            //按 control 的限制
+            let mut req = Request::newcmd(&addr, 6, "0123456789");
+            assert!(req.dors(None, None).await.is_err());
+            let mut req = Request::new(&addr, 6);
+            assert!(req.dors(Some(&[0u8; 9]), None).await.is_err());
+            let mut req = Request::newcmd(&addr, 6, "cmd");
+            let res = req.dors(Some(&[0u8; 8]), None).await.unwrap();
+            assert_eq!(res.get_code(), crate::ResCodeOk);
+            let mut req = Request::new(&addr, 6);
+            assert!(req.dors(None, Some(&[0u8; 65])).await.is_err());
+            //压缩后很小, 解压超出 max_bodys 时丢弃
+            if let Some(cmp) = crate::socks::msg::Compress::supported().first() {
+                let mut req = Request::new(&addr, 6);
+                req.set_use_version(3);
+                req.set_compress(*cmp, 0);
+                let res = req.do_bytes(None, &[0u8; 4096]).await.unwrap();
+                assert!(res.get_bodys(&None).await.is_none());
+            }
+
+            //客户端限制响应头
+            let mut req = Request::new(&addr, 1);
+            req.set_lmt_max(crate::LmtMaxConfig {
+                max_ohther: 8,
+                max_heads: 4,
+                max_bodys: 64,
+            });
+            assert!(req.dors(Some(&[0u8; 8]), None).await.is_err());
+
+            //超时
+            let mut req = Request::new(&addr, 4);
+            req.timeout(Duration::from_millis(300));
+            let tm = std::time::Instant::now();
+            assert!(req.dors(None, None).await.is_err());
+            assert!(tm.elapsed() < Duration::from_millis(900));
+            egn.stop();
+        });
+    }
+    #[test]
+    fn hbtp_malformed() {
+        ruisutil::asyncs::current_block_on(async {
+            let (egn, addr) = conf_serv().await;
+            //v1 无 magic, v2 有 magic
+            let res = conf_raw(&addr, &conf_frame(1, false, &[], b"ab"))
+                .await
+                .unwrap();
+            assert_eq!((res.code, res.len_body), (crate::ResCodeOk, 2));
+            let res = conf_raw(&addr, &conf_frame(2, true, &[], b"ab"))
+                .await
+                .unwrap();
+            assert_eq!((res.code, res.len_body), (crate::ResCodeOk, 2));
+            let res = conf_raw(&addr, &conf_frame(3, true, &[0, 0, 0, 0], b"ab"))
+                .await
+                .unwrap();
+            assert_eq!((res.code, res.len_body), (crate::ResCodeOk, 2));
+
+            for ver in [0, 4, 0xffff] {
+                let res = conf_raw(&addr, &conf_frame(ver, true, &[], b""))
+                    .await
+                    .unwrap();
+                assert_eq!(res.code, crate::ResCodeVersion);
+            }
+            assert!(conf_raw(&addr, &conf_frame(2, false, &[], b"abcd"))
+                .await
+                .is_err());
+            assert!(
+                conf_raw(&addr, &conf_frame(3, true, &[0, 0, 3, 0, 0xa0, 0, 0], b""))
+                    .await
+                    .is_err()
+            );
+            let frm = conf_frame(2, true, &[], b"");
+            assert!(conf_raw(&addr, &frm[..10]).await.is_err());
+
+            let mut req = Request::new(&addr, 1);
+            let res = req.do_string(None, "ok").await.unwrap();
+            assert_eq!(res.body_str().await.unwrap(), "ok");
+            egn.stop();
+        });
+    }
+    struct CountLsr(
+        ruisutil::asyncs::net::TcpListener,
+        std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    );
+    impl crate::Listener for CountLsr {
+        fn accept(
+            &self,
+        ) -> ruisutil::asyncs::BoxFuture<'_, std::io::Result<crate::BoxStream>> {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            crate::Listener::accept(&self.0)
+        }
+    }
+    #[test]
+    fn hbtp_listener() {
+        use crate::Stream;
+        use std::sync::{atomic::AtomicUsize, Arc};
+        ruisutil::asyncs::current_block_on(async {
+            let egn = Engine::new("");
+            egn.reg_fun(1, conf_echo, None).await;
+            let lsr = ruisutil::asyncs::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = lsr.local_addr().unwrap();
+            let cnt = Arc::new(AtomicUsize::new(0));
+            let c = egn.clone();
+            let lsr = CountLsr(lsr, cnt.clone());
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run_listener(lsr).await;
+            });
+
+            //任意 Stream 都可作为请求连接, 已装箱的不再二次装箱
+            let conn = ruisutil::asyncs::net::TcpStream::connect(addr).await.unwrap();
+            let conn: crate::BoxStream = Box::new(conn);
+            let mut req = Request::new_conn(conn, 1);
+            let res = req.do_string(None, "boxed").await.unwrap();
+            assert_eq!(res.body_str().await.unwrap(), "boxed");
+            assert_eq!(res.get_conn().peer_addr().unwrap(), addr);
+            assert!(cnt.load(std::sync::atomic::Ordering::SeqCst) >= 1);
+            egn.stop();
+        });
+    }
+    async fn conf_cred(c: crate::Context) -> std::io::Result<()> {
+        match c.peer_cred() {
+            Ok(v) => c.res_string(crate::ResCodeOk, v.uid.to_string()).await,
+            Err(e) => c.res_string(crate::ResCodeErr, e.to_string()).await,
+        }
+    }
+    #[cfg(unix)]
+    #[test]
+    fn hbtp_unix() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        ruisutil::asyncs::current_block_on(async {
+            let path = std::env::temp_dir().join(format!("hbtp_{}.sock", std::process::id()));
+            //已存在的普通文件不会被当作残留 socket 删除
+            std::fs::write(&path, b"x").unwrap();
+            let egn = Engine::new("");
+            assert!(egn.run_unix(&path).await.is_err());
+            std::fs::remove_file(&path).unwrap();
+
+            egn.reg_fun(1, conf_echo, None).await;
+            egn.reg_fun(7, conf_cred, None).await;
+            egn.set_unix_mode(Some(0o600));
+            let c = egn.clone();
+            let p = path.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run_unix(&p).await;
+            });
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let meta = std::fs::metadata(&path).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+            //仍在监听的 socket 不会被当作残留删除
+            let e = Engine::new("").run_unix(&path).await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+            assert!(path.exists());
+
+            let paths = path.to_str().unwrap();
+            let mut req = Request::new_unix(paths, 1);
+            let res = req.do_string(None, "unix").await.unwrap();
+            assert_eq!(res.body_str().await.unwrap(), "unix");
+            let mut req = Request::new(&format!("unix:{}", paths), 7);
+            let res = req.dors(None, None).await.unwrap();
+            #[cfg(feature = "unix-cred")]
+            assert_eq!(res.body_str().await.unwrap(), meta.uid().to_string());
+            #[cfg(not(feature = "unix-cred"))]
+            assert_eq!(res.get_code(), crate::ResCodeErr);
+
+            egn.stop();
+            ruisutil::asyncs::sleep(Duration::from_millis(200)).await;
+            assert!(!path.exists());
+        });
+    }
+    struct MsgRecv(std::sync::Arc<std::sync::Mutex<Vec<(i32, String, usize)>>>);
+    impl crate::socks::MessageRecv for MsgRecv {
+        fn on_check(&self) -> ruisutil::asyncs::BoxFuture<'static, ()> {
+            Box::pin(async {})
+        }
+        fn on_msg(
+            &self,
+            msg: crate::socks::msg::Message,
+        ) -> ruisutil::asyncs::BoxFuture<'static, std::io::Result<()>> {
+            let v = (msg.control, msg.cmds.clone(), msg.bodys.len());
+            self.0.lock().unwrap().push(v);
+            Box::pin(async { Ok(()) })
+        }
+    }
+    #[test]
+    fn msger_heartbeat() {
+        use crate::socks::{msg, Messager};
+        use ruisutil::asyncs::net::{TcpListener, TcpStream};
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            for stream_buf in [false, true] {
+                let ctx = ruisutil::asyncs::Context::new();
+                let lsr = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = lsr.local_addr().unwrap();
+                let conn = TcpStream::connect(addr).await.unwrap();
+                let (sconn, _) = lsr.accept().await.unwrap();
+                let sls = Arc::new(Mutex::new(Vec::new()));
+                let cls = Arc::new(Mutex::new(Vec::new()));
+                let (serv, _) = Messager::new(&ctx, sconn, Box::new(MsgRecv(sls.clone())), 0);
+                let (cli, _) = Messager::new(&ctx, conn, Box::new(MsgRecv(cls.clone())), 0);
+                let c = serv.clone();
+                ruisutil::asyncs::task::spawn(async move { c.run(true, stream_buf).await });
+                let c = cli.clone();
+                ruisutil::asyncs::task::spawn(async move { c.run(false, stream_buf).await });
+                let newmsg = |ctrl: i32, ln: usize| msg::Messages {
+                    control: ctrl,
+                    cmds: Some(format!("m{}", ctrl)),
+                    heads: None,
+                    bodys: if ln > 0 {
+                        Some(vec![1u8; ln].into())
+                    } else {
+                        None
+                    },
+                    bodybuf: None,
+                    ..Default::default()
+                };
+                cli.send(newmsg(1, 100)).await.unwrap();
+                cli.send(newmsg(2, 0)).await.unwrap();
+                serv.send(newmsg(3, 5000)).await.unwrap();
+                ruisutil::asyncs::sleep(Duration::from_millis(500)).await;
+
+                //心跳由 Messager 处理, 不交给 on_msg; 双方据此协商出相同的压缩算法
+                let mut ls = sls.lock().unwrap().clone();
+                ls.sort();
+                assert_eq!(
+                    ls,
+                    vec![(1, "m1".to_string(), 100), (2, "m2".to_string(), 0)]
+                );
+                assert_eq!(*cls.lock().unwrap(), vec![(3, "m3".to_string(), 5000)]);
+                let sup = msg::Compress::supported();
+                let cmp = msg::Compress::negotiate(&sup[..], &sup[..]);
+                assert_eq!(cli.compress(), cmp);
+                assert_eq!(serv.compress(), cmp);
+                ctx.cancel();
+                ruisutil::asyncs::sleep(Duration::from_millis(200)).await;
+            }
+        });
+    }
+    #[test]
+    fn msger_sendq() {
+        use crate::socks::msg::Messages;
+        use crate::socks::tcp::sendq::SendQueue;
+        use crate::socks::{MsgPriority, QueuePolicy, SendConfig};
+        use std::sync::Arc;
+        let newmsg = |ctrl: i32, ln: usize| Messages {
+            control: ctrl,
+            cmds: None,
+            heads: None,
+            bodys: Some(vec![0u8; ln].into()),
+            bodybuf: None,
+            ..Default::default()
+        };
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::asyncs::Context::new();
+            let (wsx, _wrx) = ruisutil::asyncs::make_channel(1);
+            let mut cfg = SendConfig::default();
+            cfg.cap_normal = 2;
+            cfg.frag_size = 100;
+            let q = Arc::new(SendQueue::new(cfg, wsx));
+            assert_eq!(q.classify(&newmsg(0, 0)), MsgPriority::Control);
+            assert_eq!(q.classify(&newmsg(1, 100)), MsgPriority::Normal);
+            assert_eq!(q.classify(&newmsg(1, 101)), MsgPriority::Bulk);
+
+            //Control 先于 Normal, 同优先级先进先出
+            let pl = QueuePolicy::Error;
+            q.push(&ctx, MsgPriority::Bulk, newmsg(9, 0), pl).await.unwrap();
+            q.push(&ctx, MsgPriority::Normal, newmsg(1, 0), pl).await.unwrap();
+            q.push(&ctx, MsgPriority::Normal, newmsg(2, 0), pl).await.unwrap();
+            q.push(&ctx, MsgPriority::Control, newmsg(0, 0), pl).await.unwrap();
+            let ls: Vec<i32> = std::iter::from_fn(|| q.pop_urgent()).map(|v| v.control).collect();
+            assert_eq!(ls, vec![0, 1, 2]);
+            assert_eq!(q.pop(MsgPriority::Bulk).map(|v| v.control), Some(9));
+
+            //Error: 满时立即返回 WouldBlock
+            q.push(&ctx, MsgPriority::Normal, newmsg(1, 0), pl).await.unwrap();
+            q.push(&ctx, MsgPriority::Normal, newmsg(2, 0), pl).await.unwrap();
+            let e = q.push(&ctx, MsgPriority::Normal, newmsg(3, 0), pl).await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock);
+
+            //DropOldest: 丢掉最早的一条
+            let pl = QueuePolicy::DropOldest;
+            q.push(&ctx, MsgPriority::Normal, newmsg(3, 0), pl).await.unwrap();
+            assert_eq!(q.dropped(), 1);
+            assert_eq!(q.depth(MsgPriority::Normal), 2);
+            assert_eq!(q.pop(MsgPriority::Normal).map(|v| v.control), Some(2));
+
+            //Block: 等到 pop 腾出空位, 由 pop 唤醒而不是轮询
+            q.push(&ctx, MsgPriority::Normal, newmsg(4, 0), pl).await.unwrap();
+            let c = q.clone();
+            let cx = ctx.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+                c.pop(MsgPriority::Normal);
+            });
+            let tm = std::time::Instant::now();
+            let pl = QueuePolicy::Block;
+            q.push(&cx, MsgPriority::Normal, newmsg(5, 0), pl).await.unwrap();
+            assert!(tm.elapsed() >= Duration::from_millis(50));
+            let ls: Vec<i32> = std::iter::from_fn(|| q.pop_urgent()).map(|v| v.control).collect();
+            assert_eq!(ls, vec![4, 5]);
+
+            //Block 等待中 ctx 取消时返回错误
+            q.push(&ctx, MsgPriority::Normal, newmsg(1, 0), pl).await.unwrap();
+            q.push(&ctx, MsgPriority::Normal, newmsg(2, 0), pl).await.unwrap();
+            let c = ctx.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+                c.cancel();
+            });
+            assert!(q.push(&ctx, MsgPriority::Normal, newmsg(3, 0), pl).await.is_err());
+        });
+    }
+    #[test]
+    fn msger_frag() {
+        use crate::socks::{msg, Messager, SendConfig};
+        use ruisutil::asyncs::net::{TcpListener, TcpStream};
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::asyncs::Context::new();
+            let lsr = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = lsr.local_addr().unwrap();
+            let conn = TcpStream::connect(addr).await.unwrap();
+            let (sconn, _) = lsr.accept().await.unwrap();
+            let sls = Arc::new(Mutex::new(Vec::new()));
+            let (serv, _) = Messager::new(&ctx, sconn, Box::new(MsgRecv(sls.clone())), 0);
+            let (cli, _) = Messager::new(&ctx, conn, Box::new(MsgRecv(sls.clone())), 0);
+            let mut cfg = SendConfig::default();
+            cfg.frag_size = 1000;
+            cli.set_send_config(cfg);
+            let c = serv.clone();
+            ruisutil::asyncs::task::spawn(async move { c.run(true, true).await });
+            let c = cli.clone();
+            ruisutil::asyncs::task::spawn(async move { c.run(false, true).await });
+            //等心跳协商出对端支持分片
+            ruisutil::asyncs::sleep(Duration::from_millis(300)).await;
+            //5500 字节按 1000 分为 6 片, 对端合并为一条消息; 期间的普通消息照常送达
+            let bds: Vec<u8> = (0..5500u32).map(|v| v as u8).collect();
+            let mut big = msg::Messages {
+                control: 2,
+                cmds: Some("big".into()),
+                heads: Some(b"hd".to_vec().into()),
+                bodys: Some(bds.clone().into()),
+                bodybuf: None,
+                ..Default::default()
+            };
+            big.set_trace(&crate::TraceContext::new_root()).unwrap();
+            cli.send(big).await.unwrap();
+            let small = msg::Messages {
+                control: 1,
+                cmds: Some("small".into()),
+                heads: None,
+                bodys: Some(vec![1u8; 10].into()),
+                bodybuf: None,
+                ..Default::default()
             };
+            cli.send(small).await.unwrap();
+            ruisutil::asyncs::sleep(Duration::from_millis(500)).await;
+            let mut ls = sls.lock().unwrap().clone();
+            ls.sort();
+            assert_eq!(
+                ls,
+                vec![(1, "small".to_string(), 10), (2, "big".to_string(), 5500)]
+            );
+            ctx.cancel();
         });
     }
     #[test]
-    fn hbtp_request_tmp() {
+    fn msger_frag_stale() {
+        use crate::socks::msg::{entity, tcps};
+        use crate::socks::Messager;
+        use ruisutil::asyncs::net::{TcpListener, TcpStream};
+        use std::sync::{Arc, Mutex};
         ruisutil::asyncs::current_block_on(async {
-            let mut req = Request::new("192.168.1.7:7000", 1);
-            req.command("hello");
-            req.add_arg("hehe1", "123456789");
-            match req.do_string(None, "dedededede").await {
-                Err(e) => println!("do err:{}", e),
-                Ok(res) => {
-                    println!("res code:{}", res.get_code());
-                    if let Some(bs) = res.get_bodys(&None).await {
-                        println!("res data:{}", std::str::from_utf8(&bs[..]).unwrap())
-                    }
+            let ctx = ruisutil::asyncs::Context::new();
+            let lsr = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = lsr.local_addr().unwrap();
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            let (sconn, _) = lsr.accept().await.unwrap();
+            let sls = Arc::new(Mutex::new(Vec::new()));
+            let (serv, _) = Messager::new(&ctx, sconn, Box::new(MsgRecv(sls.clone())), 0);
+            let c = serv.clone();
+            ruisutil::asyncs::task::spawn(async move { c.run(true, true).await });
+            let frag = |id: u32, ind: u32, ln: usize| {
+                let mut rt = ruisutil::i2_byte(id as i64, 4);
+                rt.extend(ruisutil::i2_byte(ind as i64, 4));
+                rt.extend(vec![7u8; ln]);
+                rt
+            };
+            let opts = entity::MsgOpts::default();
+            //消息1 只发了首片就中断, 消息2 的首片到达时丢弃消息1
+            let ls = [
+                (1, 0, entity::MSG_VER_FRAG, Some("a"), 100),
+                (2, 0, entity::MSG_VER_FRAG, Some("b"), 50),
+                (2, 1, entity::MSG_VER_FRAG_END, None, 30),
+                //序号不连续的分片被丢弃
+                (3, 0, entity::MSG_VER_FRAG, Some("c"), 10),
+                (3, 2, entity::MSG_VER_FRAG_END, None, 10),
+            ];
+            for (id, ind, ver, cmds, ln) in ls {
+                let cmds = cmds.map(|v: &str| v.to_string());
+                let bds = frag(id, ind, ln);
+                tcps::send_msg_opt(&ctx, &mut conn, ver, &opts, 2, cmds, &None, Some(&bds[..]))
+                    .await
+                    .unwrap();
+            }
+            ruisutil::asyncs::sleep(Duration::from_millis(300)).await;
+            assert_eq!(sls.lock().unwrap().clone(), vec![(2, "b".to_string(), 80)]);
+            ctx.cancel();
+        });
+    }
+    #[test]
+    fn msg_framing() {
+        use crate::socks::msg::{self, entity, tcps, udps, Compress};
+        use ruisutil::asyncs::net::{TcpListener, TcpStream};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::asyncs::Context::new();
+            let lsr = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = lsr.local_addr().unwrap();
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            let (mut sconn, _) = lsr.accept().await.unwrap();
+            let mut cmps = vec![Compress::None];
+            cmps.extend(Compress::supported());
+            let hds = Some(ruisutil::bytes::Bytes::from(b"hd".to_vec()));
+            let bds = vec![3u8; 2048];
+            for cmp in cmps.iter() {
+                for crc in [false, true] {
+                    let mut opts = entity::MsgOpts::default();
+                    opts.cmp = *cmp;
+                    opts.crc = crc;
+                    let cmds = Some("cmd".to_string());
+                    tcps::send_msg_opt(
+                        &ctx,
+                        &mut conn,
+                        entity::MSG_VER,
+                        &opts,
+                        7,
+                        cmds,
+                        &hds,
+                        Some(&bds[..]),
+                    )
+                    .await
+                    .unwrap();
+                    let v = tcps::parse_msg(&ctx, &mut sconn).await.unwrap();
+                    assert_eq!(
+                        (v.version, v.control, v.cmds.as_str()),
+                        (entity::MSG_VER, 7, "cmd")
+                    );
+                    assert_eq!(
+                        v.heads.as_ref().map(|v| v[..].to_vec()),
+                        Some(b"hd".to_vec())
+                    );
+                    assert_eq!(v.body_box().unwrap()[..].to_vec(), bds);
                 }
+
+                let m = msg::Messageus {
+                    control: 8,
+                    cmds: Some("u".into()),
+                    heads: hds.clone(),
+                    bodys: msg::MsgBody::Bytes(bds.clone().into()),
+                };
+                let buf = udps::msg_fmts_cmp(m, *cmp, 0).unwrap();
+                let v = udps::msg_parse(buf.to_bytes()).unwrap();
+                assert_eq!((v.control, v.cmds.as_str()), (8, "u"));
+                assert_eq!(v.bodys.unwrap()[..].to_vec(), bds);
+                //未压缩时按 body 长度拒绝, 压缩时按解压后的长度拒绝
+                let m = msg::Messageus {
+                    control: 8,
+                    cmds: None,
+                    heads: None,
+                    bodys: msg::MsgBody::Bytes(bds.clone().into()),
+                };
+                let buf = udps::msg_fmts_cmp(m, *cmp, 0).unwrap();
+                assert!(udps::msg_parse_max(buf.to_bytes(), 1024).is_err());
+            }
+
+            let mut buf =
+                udps::packet_fmts_ver(entity::PACKET_VER_CRC, 12, &Some("tk".into())).unwrap();
+            buf.push(vec![1, 2, 3]);
+            udps::packet_crc(&mut buf);
+            let bts = buf.to_bytes();
+            let p = udps::packet_parse(bts.clone()).unwrap();
+            assert_eq!((p.ctrl, p.token.as_deref()), (12, Some("tk")));
+            assert_eq!(&p.data[..], &[1u8, 2, 3][..]);
+            let mut bad = bts[..].to_vec();
+            bad[8] ^= 0xff;
+            assert!(udps::packet_parse(bad.into()).is_err());
+            assert!(udps::packet_parse(bts[..2].to_vec().into()).is_err());
+            ctx.cancel();
+        });
+    }
+    #[test]
+    fn msg_crc_compressed() {
+        use crate::socks::msg::{entity, tcps, ChecksumError, Compress};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::asyncs::Context::new();
+            let hds = Some(ruisutil::bytes::Bytes::from(b"hd".to_vec()));
+            let bds = vec![3u8; 2048];
+            for cmp in Compress::supported() {
+                let mut opts = entity::MsgOpts::default();
+                opts.cmp = cmp;
+                opts.crc = true;
+                let mut buf: Vec<u8> = Vec::new();
+                let cmds = Some("cmd".to_string());
+                tcps::send_msg_opt(
+                    &ctx,
+                    &mut buf,
+                    entity::MSG_VER,
+                    &opts,
+                    7,
+                    cmds,
+                    &hds,
+                    Some(&bds[..]),
+                )
+                .await
+                .unwrap();
+                //帧头(2) + MsgInfo + cmd(3) + heads(2) 之后为压缩后的 body
+                let pos = 2 + entity::MsgInfo::SIZE + 3 + 2 + 1;
+                buf[pos] ^= 0xff;
+                //先校验再解压: 篡改的压缩数据报校验错误, 而不是解压错误
+                let e = tcps::parse_msg(&ctx, &mut &buf[..]).await.unwrap_err();
+                assert!(ChecksumError::from_ioerr(&e).is_some(), "{:?}: {}", cmp, e);
+            }
+            ctx.cancel();
+        });
+    }
+    #[test]
+    fn iovec_phases() {
+        use crate::socks::msg::tcps;
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::asyncs::Context::new();
+            let tm = Duration::from_secs(1);
+            let phases: Vec<(Duration, Vec<&[u8]>)> = vec![
+                (tm, vec![&b"ab"[..], &b""[..], &b"c"[..]]),
+                (tm, vec![]),
+                (tm, vec![&b"de"[..]]),
+            ];
+            let mut buf: Vec<u8> = Vec::new();
+            crate::iovec::write_all_phases(&ctx, &mut buf, &phases[..]).await.unwrap();
+            assert_eq!(buf, b"abcde".to_vec());
+
+            // ByteBoxBuf 多段 body 与帧头一并写出
+            let mut bds = ruisutil::bytes::ByteBoxBuf::new();
+            bds.push(vec![1u8; 10]);
+            bds.push(vec![2u8; 20]);
+            let mut buf: Vec<u8> = Vec::new();
+            tcps::send_msg_buf(&ctx, &mut buf, 3, Some("c".into()), &None, Some(&bds))
+                .await
+                .unwrap();
+            let msg = tcps::parse_msg(&ctx, &mut &buf[..]).await.unwrap();
+            assert_eq!(msg.control, 3);
+            let body = match msg.bodys {
+                crate::socks::msg::MsgBody::Bytes(v) => v,
+                crate::socks::msg::MsgBody::BoxBuf(v) => v.to_bytes(),
+                crate::socks::msg::MsgBody::None => panic!("body nil"),
+            };
+            assert_eq!(body.len(), 30);
+            assert_eq!(&body[..10], &[1u8; 10][..]);
+            assert_eq!(&body[10..], &[2u8; 20][..]);
+
+            //bodybuf 带 CRC 发送, 改一个 body 字节后校验失败
+            let mut opts = crate::socks::msg::entity::MsgOpts::default();
+            opts.crc = true;
+            let m = crate::socks::msg::Messages {
+                control: 4,
+                cmds: None,
+                heads: None,
+                bodys: None,
+                bodybuf: Some(std::sync::Arc::new(bds)),
+                ..Default::default()
             };
+            let mut buf: Vec<u8> = Vec::new();
+            tcps::send_msgs_opt(&ctx, &mut buf, m, &opts).await.unwrap();
+            let msg = tcps::parse_msg(&ctx, &mut &buf[..]).await.unwrap();
+            assert_eq!((msg.control, msg.bodys.len()), (4, 30));
+            let n = buf.len() - 10;
+            buf[n] ^= 0xff;
+            assert!(tcps::parse_msg(&ctx, &mut &buf[..]).await.is_err());
+            ctx.cancel();
+        });
+    }
+    #[test]
+    fn msg_resync() {
+        use crate::socks::msg::tcps;
+        use ruisutil::bytes::ByteSteamBuf;
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::asyncs::Context::new();
+            let mut frame: Vec<u8> = Vec::new();
+            tcps::send_msg(&ctx, &mut frame, 5, Some("c".into()), &None, Some(&[9u8; 8][..]))
+                .await
+                .unwrap();
+            let cases: Vec<(Vec<u8>, u64)> = vec![
+                //帧前的垃圾字节, 包括单独的 0x8d
+                (vec![1, 2, 0x8d, 3, 4], 5),
+                //假帧头紧挨真帧头: 候选失败后从下一字节继续扫描
+                (vec![0x8d, 0x8f], 2),
+                (vec![0x8d, 0x8f, 0x8d], 3),
+            ];
+            for (garbage, want) in cases {
+                let buf = ByteSteamBuf::new(&ctx, 1024, Duration::from_millis(100));
+                let mut data = garbage.clone();
+                data.extend_from_slice(&frame[..]);
+                data.extend_from_slice(&frame[..]);
+                buf.push(ruisutil::bytes::Bytes::from(data)).await.unwrap();
+                buf.close();
+                let mut win = Vec::new();
+                let mut dropped = 0;
+                let v = tcps::resync_steam_msg(&ctx, &buf, &mut win, &mut dropped)
+                    .await
+                    .unwrap();
+                assert_eq!((v.control, v.cmds.as_str()), (5, "c"));
+                assert_eq!(dropped, want, "{:?}", garbage);
+                //预读的数据和流中剩余的数据都不丢
+                let v = if win.is_empty() {
+                    tcps::parse_steam_msg(&ctx, &buf).await.unwrap()
+                } else {
+                    tcps::resync_steam_msg(&ctx, &buf, &mut win, &mut dropped)
+                        .await
+                        .unwrap()
+                };
+                assert_eq!(v.control, 5);
+                assert_eq!(dropped, want);
+            }
+
+            //未知压缩算法按帧错误处理
+            let mut bad = frame.clone();
+            bad[3] = 0x0f;
+            let buf = ByteSteamBuf::new(&ctx, 1024, Duration::from_millis(100));
+            buf.push(ruisutil::bytes::Bytes::from(bad)).await.unwrap();
+            buf.close();
+            let e = tcps::parse_steam_msg(&ctx, &buf).await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+            //body 长度损坏: resync 模式下读 body 前即返回, 不等待数据
+            let mut bad = frame.clone();
+            bad[14..18].copy_from_slice(&(1024u32 * 1024 * 100).to_le_bytes());
+            bad.extend_from_slice(&frame[..]);
+            let buf = ByteSteamBuf::new(&ctx, 1024, Duration::from_millis(100));
+            buf.push(ruisutil::bytes::Bytes::from(bad)).await.unwrap();
+            let max = crate::socks::msg::MAX_BODYS;
+            let e = tcps::parse_steam_msg_max(&ctx, &buf, max, true).await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+            let mut win = Vec::new();
+            let mut dropped = 0;
+            let v = tcps::resync_steam_msg(&ctx, &buf, &mut win, &mut dropped)
+                .await
+                .unwrap();
+            assert_eq!(v.control, 5);
+            ctx.cancel();
         });
     }
     #[test]
@@ -152,6 +1160,1060 @@ mod tests {
         println!("val:{},s:{}", val, qs.to_string());
     }
     #[test]
+    fn crc32c_test() {
+        use crate::socks::msg::checksum;
+        assert_eq!(checksum::crc32c(b"123456789"), 0xe3069283);
+        let crc = checksum::crc32c_append(checksum::crc32c(b"12345"), b"6789");
+        assert_eq!(crc, 0xe3069283);
+    }
+    struct UdpRecv(std::sync::Arc<std::sync::Mutex<Vec<i32>>>);
+    impl crate::socks::IUMsgerServ for UdpRecv {
+        fn packet_err(&self, _: &std::net::SocketAddr) -> ruisutil::asyncs::BoxFuture<'static, ()> {
+            Box::pin(async {})
+        }
+        fn check_token(
+            &self,
+            _: &std::net::SocketAddr,
+            _: &Option<String>,
+        ) -> ruisutil::asyncs::BoxFuture<'static, bool> {
+            Box::pin(async { true })
+        }
+        fn on_bts(
+            &self,
+            _: &std::net::SocketAddr,
+            _: ruisutil::bytes::Bytes,
+        ) -> ruisutil::asyncs::BoxFuture<'static, std::io::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+        fn on_msg(
+            &self,
+            _: &std::net::SocketAddr,
+            msg: crate::socks::msg::Messageu,
+        ) -> ruisutil::asyncs::BoxFuture<'static, std::io::Result<()>> {
+            self.0.lock().unwrap().push(msg.control);
+            Box::pin(async { Ok(()) })
+        }
+        fn on_call(
+            &self,
+            _: &std::net::SocketAddr,
+            msg: crate::socks::msg::Messageu,
+        ) -> ruisutil::asyncs::BoxFuture<'static, std::io::Result<crate::socks::msg::Messageus>>
+        {
+            self.0.lock().unwrap().push(msg.control);
+            Box::pin(async move {
+                if msg.control < 0 {
+                    return Err(ruisutil::ioerr("bad control", None));
+                }
+                Ok(crate::socks::msg::Messageus {
+                    control: msg.control + 1000,
+                    cmds: None,
+                    heads: None,
+                    bodys: crate::socks::msg::MsgBody::None,
+                })
+            })
+        }
+        fn on_session_open(
+            &self,
+            _: &crate::socks::UdpSession,
+        ) -> ruisutil::asyncs::BoxFuture<'static, ()> {
+            self.0.lock().unwrap().push(-1);
+            Box::pin(async {})
+        }
+        fn on_session_close(
+            &self,
+            _: &crate::socks::UdpSession,
+            _: crate::socks::SessionClose,
+        ) -> ruisutil::asyncs::BoxFuture<'static, ()> {
+            self.0.lock().unwrap().push(-2);
+            Box::pin(async {})
+        }
+    }
+    impl crate::socks::IUMsgerCli for UdpRecv {
+        fn packet_err(&self, _: &std::net::SocketAddr) -> ruisutil::asyncs::BoxFuture<'static, ()> {
+            Box::pin(async {})
+        }
+        fn check_token(
+            &self,
+            _: &std::net::SocketAddr,
+            _: &Option<String>,
+        ) -> ruisutil::asyncs::BoxFuture<'static, bool> {
+            Box::pin(async { true })
+        }
+        fn on_bts(
+            &self,
+            _: &std::net::SocketAddr,
+            _: ruisutil::bytes::Bytes,
+        ) -> ruisutil::asyncs::BoxFuture<'static, std::io::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+        fn on_msg(
+            &self,
+            _: &std::net::SocketAddr,
+            msg: crate::socks::msg::Messageu,
+        ) -> ruisutil::asyncs::BoxFuture<'static, std::io::Result<()>> {
+            self.0.lock().unwrap().push(msg.control);
+            Box::pin(async { Ok(()) })
+        }
+    }
+    #[test]
+    fn udp_client() {
+        use crate::socks::{msg, UMsgerClient, UMsgerServ};
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let sls = Arc::new(Mutex::new(Vec::new()));
+            let cls = Arc::new(Mutex::new(Vec::new()));
+            let serv = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(sls.clone()));
+            let c = serv.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let saddr = serv.local_addr().unwrap();
+            let cli = UMsgerClient::new(
+                &ctx,
+                saddr.to_string(),
+                "127.0.0.1:0".into(),
+                UdpRecv(cls.clone()),
+            );
+            cli.set_token(Some("tks".into()));
+            cli.connect().await.unwrap();
+            let c = cli.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            let newmsg = |ctrl: i32, ln: usize| msg::Messageus {
+                control: ctrl,
+                cmds: Some("test".into()),
+                heads: None,
+                bodys: msg::MsgBody::Bytes(vec![1u8; ln].into()),
+            };
+            cli.send1msg(newmsg(1, 10)).await.unwrap();
+            cli.send_msg(newmsg(2, 5000)).await.unwrap();
+            let caddr = cli.local_addr().unwrap();
+            serv.send1msg(newmsg(3, 10), &None, Some(&caddr)).await.unwrap();
+            ruisutil::asyncs::sleep(Duration::from_millis(300)).await;
+            ctx.stop();
+            let mut ls = sls.lock().unwrap().clone();
+            ls.sort();
+            assert_eq!(ls, vec![1, 2]);
+            assert_eq!(*cls.lock().unwrap(), vec![3]);
+        });
+    }
+    #[test]
+    fn udp_session() {
+        use crate::socks::{UMsgerClient, UMsgerServ, UdpConfig};
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let sls = Arc::new(Mutex::new(Vec::new()));
+            let serv = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(sls.clone()));
+            let mut cfg = UdpConfig::default();
+            cfg.sess_idle = Duration::from_millis(600);
+            cfg.sess_required = true;
+            serv.set_config(cfg);
+            let c = serv.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let saddr = serv.local_addr().unwrap();
+            let cli = UMsgerClient::new(
+                &ctx,
+                saddr.to_string(),
+                "127.0.0.1:0".into(),
+                UdpRecv(Arc::new(Mutex::new(Vec::new()))),
+            );
+            cli.connect().await.unwrap();
+            let c = cli.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            let caddr = cli.local_addr().unwrap();
+
+            // 未建立会话的数据包被拒绝
+            cli.send1bts(vec![1u8; 10].into()).await.unwrap();
+            let id = cli.open_session(Duration::from_secs(2)).await.unwrap();
+            let ls = serv.sessions().await;
+            assert_eq!(ls.len(), 1);
+            assert_eq!(ls[0].id, id);
+            assert_eq!(ls[0].addrs, caddr);
+            assert_eq!(cli.open_session(Duration::from_secs(2)).await.unwrap(), id);
+
+            assert!(serv.close_session(&caddr).await.unwrap());
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            assert!(cli.session_id().is_none());
+            assert!(serv.session(&caddr).await.is_none());
+
+            // 空闲超时, 服务端通知客户端关闭
+            let id2 = cli.open_session(Duration::from_secs(2)).await.unwrap();
+            assert_ne!(id2, id);
+            for _ in 0..40 {
+                if cli.session_id().is_none() {
+                    break;
+                }
+                ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            }
+            ctx.stop();
+            assert!(cli.session_id().is_none());
+            assert!(serv.sessions().await.is_empty());
+            assert_eq!(*sls.lock().unwrap(), vec![-1, -2, -1, -2]);
+        });
+    }
+    #[test]
+    fn udp_session_restart() {
+        use crate::socks::udp::{session, udps};
+        use crate::socks::{msg, UMsgerServ, UdpConfig};
+        use ruisutil::asyncs::net::UdpSocket;
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let sls = Arc::new(Mutex::new(Vec::new()));
+            let serv = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(sls.clone()));
+            let mut cfg = UdpConfig::default();
+            cfg.sess_required = true;
+            serv.set_config(cfg);
+            let c = serv.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let saddr = serv.local_addr().unwrap();
+            let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let pkt = |ctrl: u16, sess: Option<u64>, data: &[u8]| {
+                let ver = msg::entity::PACKET_VER;
+                let mut bts = msg::udps::packet_fmts_sess(ver, ctrl, &None, sess).unwrap();
+                bts.push(data.to_vec());
+                bts.to_bytes()
+            };
+            let open = |nonce: u64| {
+                let sock = &sock;
+                let bts = pkt(session::CTRL_OPEN, None, &session::open_fmts(nonce, &[])[..]);
+                async move {
+                    sock.send_to(&bts[..], &saddr).await.unwrap();
+                    let mut buf = vec![0u8; 1500];
+                    let (n, _) = sock.recv_from(&mut buf[..]).await.unwrap();
+                    let rt = msg::udps::packet_parse(ruisutil::bytes::bytes_with_len(buf, n));
+                    let rt = rt.unwrap();
+                    assert_eq!(rt.ctrl, session::CTRL_ACK);
+                    session::id_parse(rt.data).unwrap()
+                }
+            };
+            let newmsg = |ctrl: i32| {
+                let m = msg::Messageus {
+                    control: ctrl,
+                    cmds: None,
+                    heads: None,
+                    bodys: msg::MsgBody::Bytes(vec![1u8; 100].into()),
+                };
+                msg::udps::msg_fmts(m).unwrap().to_bytes()
+            };
+            let frag = |ind: u16, data: &[u8]| {
+                let hd = udps::FragHead {
+                    id: 7,
+                    ind: ind,
+                    count: 2,
+                };
+                let mut rt = udps::frag_fmts(&hd);
+                rt.extend_from_slice(data);
+                rt
+            };
+
+            let id1 = open(1).await;
+            assert_eq!(open(1).await, id1);
+            // 旧会话收到一半的分片
+            let m = newmsg(1);
+            let half = m.len() / 2;
+            let p = pkt(21, Some(id1), &frag(0, &m[..half])[..]);
+            sock.send_to(&p[..], &saddr).await.unwrap();
+            ruisutil::asyncs::sleep(Duration::from_millis(50)).await;
+
+            // 同一地址以新 nonce 重新打开, 旧会话的状态被清理
+            let id2 = open(2).await;
+            assert_ne!(id2, id1);
+            assert_eq!(serv.session(&sock.local_addr().unwrap()).await.unwrap().id, id2);
+            let p = pkt(21, Some(id2), &frag(1, &m[half..])[..]);
+            sock.send_to(&p[..], &saddr).await.unwrap();
+            // 带旧会话 id 的包丢弃
+            let p = pkt(12, Some(id1), &newmsg(2)[..]);
+            sock.send_to(&p[..], &saddr).await.unwrap();
+            let p = pkt(12, Some(id2), &newmsg(3)[..]);
+            sock.send_to(&p[..], &saddr).await.unwrap();
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            ctx.stop();
+            assert_eq!(*sls.lock().unwrap(), vec![-1, -2, -1, 3]);
+        });
+    }
+    #[test]
+    fn udp_frag_budget() {
+        use crate::socks::udp::udps::{frag_evict, FragHead, UdpMsgParse};
+        use crate::socks::UdpConfig;
+        use std::collections::HashMap;
+        let bt = |n: usize| ruisutil::bytes::Bytes::from(vec![1u8; n]);
+        let hd = |id: u32, ind: u16| FragHead {
+            id: id,
+            ind: ind,
+            count: 3,
+        };
+        let mut cfg = UdpConfig::default();
+        cfg.frag_timeout = Duration::from_millis(100);
+
+        // 缺少分片的消息不返回, 超时后清理
+        let a: std::net::SocketAddr = "127.0.0.1:1001".parse().unwrap();
+        let mut v = UdpMsgParse::new(a.clone());
+        assert!(v.push(&cfg, hd(1, 0), bt(100)).unwrap().is_none());
+        assert!(v.push(&cfg, hd(1, 2), bt(100)).unwrap().is_none());
+        assert_eq!(v.bytes(), 200);
+        assert_eq!(v.clean(&cfg), 0);
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(v.clean(&cfg), 1);
+        assert!(v.is_empty());
+        assert_eq!(v.bytes(), 0);
+
+        // 总量超限时跨来源丢弃最早的消息
+        let b: std::net::SocketAddr = "127.0.0.1:1002".parse().unwrap();
+        let mut peers = HashMap::new();
+        let mut va = UdpMsgParse::new(a.clone());
+        va.push(&cfg, hd(1, 0), bt(100)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let mut vb = UdpMsgParse::new(b.clone());
+        vb.push(&cfg, hd(1, 0), bt(100)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        va.push(&cfg, hd(2, 0), bt(100)).unwrap();
+        peers.insert(a.clone(), va);
+        peers.insert(b.clone(), vb);
+        assert_eq!(frag_evict(&mut peers, 300), 0);
+        assert_eq!(frag_evict(&mut peers, 250), 1);
+        assert_eq!(peers[&a].bytes(), 100);
+        assert_eq!(peers[&b].bytes(), 100);
+        assert_eq!(frag_evict(&mut peers, 100), 1);
+        assert!(peers[&b].is_empty());
+        assert_eq!(peers[&a].bytes(), 100);
+        // 未被丢弃的消息收齐后完整返回
+        let va = peers.get_mut(&a).unwrap();
+        assert!(va.push(&cfg, hd(2, 1), bt(100)).unwrap().is_none());
+        let rt = va.push(&cfg, hd(2, 2), bt(100)).unwrap().unwrap();
+        assert_eq!(rt.len(), 300);
+        assert_eq!(frag_evict(&mut peers, 0), 0);
+    }
+    #[test]
+    fn udp_msg_packets() {
+        use crate::socks::msg;
+        use crate::socks::udp::udps::{self, UdpMsgParse};
+        use crate::socks::UdpConfig;
+        let cfg = UdpConfig::default();
+        let msgid = std::sync::atomic::AtomicU32::new(1);
+        let tks = Some("tks".to_string());
+        let newmsg = |ln: usize| msg::Messageus {
+            control: 5,
+            cmds: Some("test".into()),
+            heads: None,
+            bodys: msg::MsgBody::Bytes(vec![3u8; ln].into()),
+        };
+        let a: std::net::SocketAddr = "127.0.0.1:1001".parse().unwrap();
+        for (crc, sess) in [(false, None), (true, Some(0x1234_5678_9abc_def0u64))] {
+            let datas = msg::udps::msg_fmts(newmsg(10)).unwrap();
+            let pkts = udps::msg_packets(&cfg, crc, &tks, sess, datas, 500, &msgid).unwrap();
+            assert_eq!(pkts.len(), 1);
+            let pckt = msg::udps::packet_parse(pkts[0].clone()).unwrap();
+            assert_eq!(pckt.ctrl, 12);
+            assert_eq!(pckt.token, tks);
+            assert_eq!(pckt.sess, sess);
+
+            let datas = msg::udps::msg_fmts(newmsg(2000)).unwrap();
+            let pkts = udps::msg_packets(&cfg, crc, &tks, sess, datas, 500, &msgid).unwrap();
+            assert_eq!(pkts.len(), 5);
+            let mut merge = UdpMsgParse::new(a.clone());
+            let mut rt = None;
+            for it in pkts.iter().rev() {
+                assert!(it.len() <= 500 + udps::packet_overhead(&tks, sess.is_some(), crc, false));
+                let pckt = msg::udps::packet_parse(it.clone()).unwrap();
+                assert_eq!(pckt.ctrl, 21);
+                assert_eq!(pckt.sess, sess);
+                let mut data = pckt.data.clone();
+                let hd = udps::frag_parse(&mut data).unwrap();
+                rt = merge.push(&cfg, hd, data).unwrap();
+            }
+            let m = msg::udps::msg_parse(rt.unwrap()).unwrap();
+            assert_eq!(m.control, 5);
+            assert_eq!(m.bodys.unwrap().len(), 2000);
+        }
+        let datas = msg::udps::msg_fmts(newmsg(2000)).unwrap();
+        let rt = udps::msg_packets(&cfg, false, &tks, None, datas, udps::FRAG_HEAD_LEN, &msgid);
+        assert!(rt.is_err());
+    }
+    #[test]
+    fn udp_compress_negotiate() {
+        use crate::socks::msg::{self, Compress};
+        use crate::socks::udp::session;
+        use crate::socks::{UMsgerClient, UMsgerServ};
+        use std::sync::{Arc, Mutex};
+        let all = [Compress::Zstd, Compress::Lz4, Compress::Deflate];
+        let bts = session::cmps_fmts(&all);
+        assert_eq!(session::cmps_parse(&bts[..]), all.to_vec());
+        // 旧版本 OPEN/ACK 不带算法列表, 截断时只取已读到的部分
+        assert!(session::cmps_parse(&[]).is_empty());
+        assert_eq!(session::cmps_parse(&bts[..2]), vec![Compress::Zstd]);
+        assert!(session::cmps_parse(&[2, 0, 9]).is_empty());
+        assert_eq!(session::cmp_for(Compress::Zstd, &[]), Compress::None);
+        assert_eq!(session::cmp_for(Compress::None, &all), Compress::None);
+        for it in all.iter() {
+            let want = if it.is_supported() { *it } else { Compress::None };
+            assert_eq!(session::cmp_for(*it, &all), want);
+        }
+
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let sls = Arc::new(Mutex::new(Vec::new()));
+            let cls = Arc::new(Mutex::new(Vec::new()));
+            let serv = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(sls.clone()));
+            let c = serv.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let saddr = serv.local_addr().unwrap();
+            let cli = UMsgerClient::new(
+                &ctx,
+                saddr.to_string(),
+                "127.0.0.1:0".into(),
+                UdpRecv(cls.clone()),
+            );
+            cli.connect().await.unwrap();
+            let c = cli.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            let caddr = cli.local_addr().unwrap();
+            // 未编译压缩算法时按 zstd 设置, 协商结果为不压缩
+            let cmp = Compress::supported().first().cloned().unwrap_or(Compress::Zstd);
+            cli.set_compress(cmp, 0);
+            serv.set_compress(cmp, 0);
+            let newmsg = |ctrl: i32| msg::Messageus {
+                control: ctrl,
+                cmds: Some("test".into()),
+                heads: None,
+                bodys: msg::MsgBody::Bytes(vec![7u8; 3000].into()),
+            };
+
+            // 未建立会话, 双方均不压缩仍可收到
+            cli.send_msg(newmsg(1)).await.unwrap();
+            serv.send_msg(newmsg(2), &None, Some(&caddr)).await.unwrap();
+            cli.open_session(Duration::from_secs(2)).await.unwrap();
+            let ls = serv.sessions().await;
+            assert_eq!(ls[0].cmps, Compress::supported());
+            cli.send_msg(newmsg(3)).await.unwrap();
+            serv.send_msg(newmsg(4), &None, Some(&caddr)).await.unwrap();
+            ruisutil::asyncs::sleep(Duration::from_millis(300)).await;
+            ctx.stop();
+            let mut ls = sls.lock().unwrap().clone();
+            ls.sort();
+            assert_eq!(ls, vec![-1, 1, 3]);
+            let mut ls = cls.lock().unwrap().clone();
+            ls.sort();
+            assert_eq!(ls, vec![2, 4]);
+        });
+    }
+    #[cfg(feature = "udp-seal")]
+    #[test]
+    fn udp_seal() {
+        use crate::socks::{msg, UMsgerClient, UMsgerServ};
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let sls = Arc::new(Mutex::new(Vec::new()));
+            let serv = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(sls.clone()));
+            serv.set_psk(Some(b"0123456789abcdef")).unwrap();
+            let c = serv.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let saddr = serv.local_addr().unwrap();
+            let newcli = |psk: Option<&[u8]>| {
+                let cli = UMsgerClient::new(
+                    &ctx,
+                    saddr.to_string(),
+                    "127.0.0.1:0".into(),
+                    UdpRecv(Arc::new(Mutex::new(Vec::new()))),
+                );
+                cli.set_psk(psk).unwrap();
+                cli
+            };
+            let newmsg = |ctrl: i32, ln: usize| msg::Messageus {
+                control: ctrl,
+                cmds: None,
+                heads: None,
+                bodys: msg::MsgBody::Bytes(vec![1u8; ln].into()),
+            };
+            let cli = newcli(Some(b"0123456789abcdef"));
+            cli.connect().await.unwrap();
+            cli.send1msg(newmsg(1, 10)).await.unwrap();
+            cli.send_msg(newmsg(2, 5000)).await.unwrap();
+            // 明文和错误密钥的包被丢弃
+            let plain = newcli(None);
+            plain.connect().await.unwrap();
+            plain.send1msg(newmsg(3, 10)).await.unwrap();
+            let wrong = newcli(Some(b"fedcba9876543210"));
+            wrong.connect().await.unwrap();
+            wrong.send1msg(newmsg(4, 10)).await.unwrap();
+            ruisutil::asyncs::sleep(Duration::from_millis(300)).await;
+            ctx.stop();
+            let mut ls = sls.lock().unwrap().clone();
+            ls.sort();
+            assert_eq!(ls, vec![1, 2]);
+        });
+    }
+    #[cfg(feature = "udp-seal")]
+    #[test]
+    fn udp_seal_forged() {
+        use crate::socks::udp::seal::UdpSeal;
+        let serv = UdpSeal::new(b"0123456789abcdef").unwrap();
+        let cli = UdpSeal::new(b"0123456789abcdef").unwrap();
+        let src: std::net::SocketAddr = "127.0.0.1:1".parse().unwrap();
+        //伪造 salt 的包认证失败, 不占用 peers
+        for i in 0..8u8 {
+            let mut p = cli.seal(b"hello").unwrap();
+            p[3] = i;
+            p[4] ^= 0x5a;
+            assert!(serv.open(&src, &p[..], 2).is_err());
+        }
+        let p = cli.seal(b"hello").unwrap();
+        assert_eq!(serv.open(&src, &p[..], 2).unwrap(), b"hello".to_vec());
+        assert!(serv.open(&src, &p[..], 2).is_err());
+        //篡改的包不推进重放窗口
+        let q = cli.seal(b"x").unwrap();
+        let mut t = q.clone();
+        let ln = t.len();
+        t[ln - 1] ^= 1;
+        assert!(serv.open(&src, &t[..], 2).is_err());
+        assert_eq!(serv.open(&src, &q[..], 2).unwrap(), b"x".to_vec());
+    }
+    #[test]
+    fn udp_payload_size() {
+        use crate::socks::{msg, UMsgerClient, UMsgerServ, UdpConfig};
+        use std::sync::{Arc, Mutex};
+        let mut cfg = UdpConfig::default();
+        assert_eq!(cfg.payload_size(None, false, 7), 1200);
+        //1280 - 48 - 7 超过 payload_max, 取 payload_max
+        assert_eq!(cfg.payload_size(Some(1280), true, 7), 1200);
+        assert_eq!(cfg.payload_size(Some(576), false, 11), 576 - 28 - 11);
+        cfg.payload_max = 1400;
+        assert_eq!(cfg.payload_size(Some(1280), true, 7), 1280 - 48 - 7);
+        assert_eq!(cfg.payload_size(Some(1280), false, 7), 1280 - 28 - 7);
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let sls = Arc::new(Mutex::new(Vec::new()));
+            let mut cfg = UdpConfig::default();
+            cfg.dgram_max = 8972;
+            cfg.payload_max = 8900;
+            cfg.mtu = 9000;
+            cfg.rcvbuf = 1024 * 1024;
+            cfg.sndbuf = 1024 * 1024;
+            let serv = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(sls.clone()));
+            serv.set_config(cfg.clone());
+            let c = serv.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let saddr = serv.local_addr().unwrap();
+            let cli = UMsgerClient::new(
+                &ctx,
+                saddr.to_string(),
+                "127.0.0.1:0".into(),
+                UdpRecv(Arc::new(Mutex::new(Vec::new()))),
+            );
+            cli.connect().await.unwrap();
+            let newmsg = |ctrl: i32, ln: usize| msg::Messageus {
+                control: ctrl,
+                cmds: None,
+                heads: None,
+                bodys: msg::MsgBody::Bytes(vec![1u8; ln].into()),
+            };
+            // 默认配置单包上限 1200
+            assert!(cli.send1msg(newmsg(1, 6000)).await.is_err());
+            cli.set_config(cfg);
+            cli.send1msg(newmsg(2, 6000)).await.unwrap();
+            // 小 MTU 隧道按路径 MTU 分片
+            cli.set_path_mtu(Some(576));
+            assert_eq!(cli.payload_size(), 576 - 28 - 7);
+            cli.send_msg(newmsg(3, 3000)).await.unwrap();
+            ruisutil::asyncs::sleep(Duration::from_millis(300)).await;
+            ctx.stop();
+            let mut ls = sls.lock().unwrap().clone();
+            ls.sort();
+            assert_eq!(ls, vec![2, 3]);
+        });
+    }
+    #[test]
+    fn udp_recv_workers() {
+        use crate::socks::{msg, UMsgerClient, UMsgerServ, UdpConfig};
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let sls = Arc::new(Mutex::new(Vec::new()));
+            let mut cfg = UdpConfig::default();
+            cfg.recv_batch = 16;
+            cfg.recv_workers = 2;
+            cfg.recv_queue = 256;
+            let serv = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(sls.clone()));
+            serv.set_config(cfg);
+            let c = serv.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let saddr = serv.local_addr().unwrap();
+            let cli = UMsgerClient::new(
+                &ctx,
+                saddr.to_string(),
+                "127.0.0.1:0".into(),
+                UdpRecv(Arc::new(Mutex::new(Vec::new()))),
+            );
+            cli.connect().await.unwrap();
+            for i in 0..100 {
+                let data = msg::Messageus {
+                    control: i,
+                    cmds: None,
+                    heads: None,
+                    bodys: msg::MsgBody::Bytes(vec![1u8; 100].into()),
+                };
+                cli.send1msg(data).await.unwrap();
+            }
+            // 分片经 sendmmsg/逐个发送
+            let data = msg::Messageus {
+                control: 100,
+                cmds: None,
+                heads: None,
+                bodys: msg::MsgBody::Bytes(vec![2u8; 20000].into()),
+            };
+            serv.send_msg(data, &None, Some(&saddr)).await.unwrap();
+            for _ in 0..40 {
+                if sls.lock().unwrap().len() >= 101 {
+                    break;
+                }
+                ruisutil::asyncs::sleep(Duration::from_millis(50)).await;
+            }
+            ctx.stop();
+            // 同一来源由同一任务按顺序处理
+            let ls = sls.lock().unwrap().clone();
+            let cls: Vec<i32> = ls.iter().cloned().filter(|v| *v < 100).collect();
+            assert_eq!(cls, (0..100).collect::<Vec<i32>>());
+            assert!(ls.contains(&100));
+            assert_eq!(serv.recv_dropped(), 0);
+        });
+    }
+    #[test]
+    fn udp_flood_ban() {
+        use crate::socks::{msg, UMsgerClient, UMsgerServ, UdpConfig};
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let sls = Arc::new(Mutex::new(Vec::new()));
+            let mut cfg = UdpConfig::default();
+            cfg.lmt_pkts = 10;
+            cfg.ban_strikes = 1;
+            cfg.ban_time = Duration::from_secs(10);
+            let serv = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(sls.clone()));
+            serv.set_config(cfg);
+            let c = serv.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let saddr = serv.local_addr().unwrap();
+            let cli = UMsgerClient::new(
+                &ctx,
+                saddr.to_string(),
+                "127.0.0.1:0".into(),
+                UdpRecv(Arc::new(Mutex::new(Vec::new()))),
+            );
+            cli.connect().await.unwrap();
+            for i in 0..50 {
+                let data = msg::Messageus {
+                    control: i,
+                    cmds: None,
+                    heads: None,
+                    bodys: msg::MsgBody::None,
+                };
+                cli.send1msg(data).await.unwrap();
+            }
+            ruisutil::asyncs::sleep(Duration::from_millis(300)).await;
+            ctx.stop();
+            //发送可能跨过 1 秒窗口, 只断言下限和总数
+            let n = sls.lock().unwrap().len() as u64;
+            let st = serv.drop_stats();
+            assert!(n >= 10 && st.pkt_rate >= 1 && st.banned >= 1, "{} {:?}", n, st);
+            assert!(n + st.pkt_rate + st.banned + st.queue <= 50);
+            let caddr = cli.local_addr().unwrap();
+            let bans = serv.bans();
+            assert_eq!(bans.len(), 1);
+            assert_eq!(bans[0].0, caddr);
+            assert!(serv.unban(&caddr));
+            assert!(serv.bans().is_empty());
+        });
+
+        //来源数已满时新来源直接丢弃
+        use crate::socks::udp::limit::Limiter;
+        use crate::socks::DropReason;
+        let mut cfg = UdpConfig::default();
+        cfg.lmt_pkts = 10;
+        cfg.lmt_max_srcs = 1;
+        let lmt = Limiter::new();
+        let a: std::net::SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: std::net::SocketAddr = "127.0.0.1:2".parse().unwrap();
+        assert_eq!(lmt.check(&cfg, &a, 10), None);
+        assert_eq!(lmt.check(&cfg, &b, 10), Some(DropReason::TooManySources));
+        assert_eq!(lmt.count(DropReason::TooManySources), 1);
+        assert!(lmt.notify(DropReason::TooManySources));
+        assert!(!lmt.notify(DropReason::TooManySources));
+    }
+    #[test]
+    fn udp_call() {
+        use crate::socks::{msg, LossSim, UMsgerServ, UdpConfig};
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let ls = Arc::new(Mutex::new(Vec::new()));
+            let mut cfg = UdpConfig::default();
+            cfg.call_retries = 10;
+            let a = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(Arc::new(Mutex::new(Vec::new()))));
+            let b = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(ls.clone()));
+            a.set_config(cfg.clone());
+            b.set_config(cfg);
+            a.set_loss_sim(Some(LossSim::new(0.2, 3)));
+            b.set_loss_sim(Some(LossSim::new(0.2, 5)));
+            for it in [a.clone(), b.clone()] {
+                ruisutil::asyncs::task::spawn(async move {
+                    let _ = it.run().await;
+                });
+            }
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let addr = b.local_addr().unwrap();
+            let newmsg = |ctrl: i32| msg::Messageus {
+                control: ctrl,
+                cmds: Some("call".into()),
+                heads: None,
+                bodys: msg::MsgBody::Bytes(vec![1u8; 100].into()),
+            };
+            for i in 1..=20 {
+                let rt = a.call(&addr, newmsg(i), &None, Duration::from_secs(3)).await.unwrap();
+                assert_eq!(rt.control, i + 1000);
+            }
+            assert!(a.call(&addr, newmsg(-1), &None, Duration::from_secs(3)).await.is_err());
+            ctx.stop();
+            // 重传的请求不会重复调用 on_call
+            let mut ls = ls.lock().unwrap().clone();
+            ls.sort();
+            let mut expect = vec![-1];
+            expect.extend(1..=20);
+            assert_eq!(ls, expect);
+        });
+    }
+    #[test]
+    fn udp_nat() {
+        use crate::socks::{msg, UMsgerServ};
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let ls = Arc::new(Mutex::new(Vec::new()));
+            let rdv = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(Arc::new(Mutex::new(Vec::new()))));
+            let a = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(Arc::new(Mutex::new(Vec::new()))));
+            let b = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(ls.clone()));
+            rdv.set_rendezvous(true).await;
+            for it in [rdv.clone(), a.clone(), b.clone()] {
+                ruisutil::asyncs::task::spawn(async move {
+                    let _ = it.run().await;
+                });
+            }
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let raddr = rdv.local_addr().unwrap();
+            let tmout = Duration::from_secs(3);
+            let apub = a.nat_register(&raddr, "a", tmout).await.unwrap();
+            let bpub = b.nat_register(&raddr, "b", tmout).await.unwrap();
+            assert_eq!(apub, a.local_addr().unwrap());
+            assert_eq!(bpub, b.local_addr().unwrap());
+            assert_eq!(rdv.nat_regs().await.len(), 2);
+            //已登记的名称不能被其他地址抢占
+            let m = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(Arc::new(Mutex::new(Vec::new()))));
+            let c = m.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                let _ = c.run().await;
+            });
+            assert!(m.nat_register(&raddr, "b", Duration::from_millis(300)).await.is_err());
+            let regs = rdv.nat_regs().await;
+            assert!(regs.contains(&(String::from("b"), bpub)));
+
+            assert_eq!(a.nat_connect("b", tmout).await.unwrap(), bpub);
+            for _ in 0..50 {
+                if b.nat_peer("a").await.is_some() {
+                    break;
+                }
+                ruisutil::asyncs::sleep(Duration::from_millis(20)).await;
+            }
+            assert_eq!(b.nat_peer("a").await, Some(apub));
+            assert!(a.nat_connect("c", Duration::from_millis(300)).await.is_err());
+
+            let newmsg = |ctrl: i32| msg::Messageus {
+                control: ctrl,
+                cmds: None,
+                heads: None,
+                bodys: msg::MsgBody::Bytes(vec![1u8; 100].into()),
+            };
+            a.nat_send("b", newmsg(1)).await.unwrap();
+            a.nat_relay("b", newmsg(2)).await.unwrap();
+            ruisutil::asyncs::sleep(Duration::from_millis(200)).await;
+            ctx.stop();
+            let mut ls = ls.lock().unwrap().clone();
+            ls.sort();
+            assert_eq!(ls, vec![1, 2]);
+        });
+    }
+    #[test]
+    fn header_golden() {
+        let mut info = crate::res::MsgInfo::new();
+        info.version = 2;
+        info.control = 0x01020304;
+        info.len_cmd = 5;
+        info.len_arg = 6;
+        info.len_head = 0x0a0b0c0d;
+        info.len_body = 7;
+        let bts = info.encode();
+        assert_eq!(
+            bts,
+            [2, 0, 4, 3, 2, 1, 5, 0, 6, 0, 0x0d, 0x0c, 0x0b, 0x0a, 7, 0, 0, 0]
+        );
+        let v = crate::res::MsgInfo::decode(&bts[..]).unwrap();
+        assert_eq!((v.version, v.control, v.len_cmd, v.len_arg), (2, 0x01020304, 5, 6));
+        assert_eq!((v.len_head, v.len_body), (0x0a0b0c0d, 7));
+        assert!(crate::res::MsgInfo::decode(&bts[..17]).is_err());
+
+        let mut res = crate::res::ResInfoV1::new();
+        res.code = -2;
+        res.len_head = 0x100;
+        res.len_body = 0x01020304;
+        let bts = res.encode();
+        assert_eq!(bts, [0xfe, 0xff, 0xff, 0xff, 0, 1, 0, 0, 4, 3, 2, 1]);
+        let v = crate::res::ResInfoV1::decode(&bts[..]).unwrap();
+        assert_eq!((v.code, v.len_head, v.len_body), (-2, 0x100, 0x01020304));
+        assert!(crate::res::ResInfoV1::decode(&bts[..11]).is_err());
+
+        let mut info = crate::socks::msg::entity::MsgInfo::new();
+        info.version = crate::socks::msg::entity::ver_join(1, 0x10);
+        info.control = 3;
+        info.len_cmd = 0x0102;
+        info.len_head = 9;
+        info.len_body = 0x00ff0000;
+        let bts = info.encode();
+        assert_eq!(bts, [1, 0x10, 3, 0, 0, 0, 2, 1, 9, 0, 0, 0, 0, 0, 0xff, 0]);
+        let v = crate::socks::msg::entity::MsgInfo::decode(&bts[..]).unwrap();
+        assert_eq!((v.version, v.control, v.len_cmd), (0x1001, 3, 0x0102));
+        assert_eq!((v.len_head, v.len_body), (9, 0x00ff0000));
+        assert!(crate::socks::msg::entity::MsgInfo::decode(&bts[..15]).is_err());
+    }
+    #[test]
+    fn ext_golden() {
+        use crate::ext::*;
+        use crate::socks::msg::compress::Compress;
+        let mut ext = crate::MsgExt::default();
+        assert!(ext.is_empty());
+        assert_eq!(ext.encode().unwrap(), vec![0, 0, 0, 0]);
+        ext.flags = 0x0102;
+        ext.req_id = Some(7);
+        ext.compress = Some(Compress::Zstd);
+        ext.encoding = Some("json".to_string());
+        let bts = ext.encode().unwrap();
+        assert_eq!(
+            bts,
+            vec![
+                2, 1, 22, 0, //
+                1, 8, 0, 7, 0, 0, 0, 0, 0, 0, 0, //
+                0x82, 1, 0, 2, //
+                3, 4, 0, b'j', b's', b'o', b'n',
+            ]
+        );
+        assert_eq!(crate::MsgExt::decode(&bts[..]).unwrap(), ext);
+        assert!(crate::MsgExt::decode(&bts[..bts.len() - 1]).is_err());
+
+        //不认识的普通扩展跳过, 必须识别的扩展拒绝
+        let mut bts = vec![0, 0, 0, 0, 0x20, 2, 0, 9, 9, EXT_DEADLINE, 8, 0];
+        bts.extend_from_slice(&1000u64.to_le_bytes());
+        bts[2] = (bts.len() - EXT_HEAD_LEN) as u8;
+        let v = crate::MsgExt::decode(&bts[..]).unwrap();
+        assert_eq!(v.deadline, Some(1000));
+        let bts = vec![0, 0, 3, 0, 0xa0, 0, 0];
+        assert!(crate::MsgExt::decode(&bts[..]).is_err());
+        let bts = vec![0, 0, 3, 0, EXT_REQ_ID, 1, 0];
+        assert!(crate::MsgExt::decode(&bts[..]).is_err());
+    }
+
+    //记录读取的字节数和单次读取的最大缓冲, 确认没有按对端声明的长度分配
+    struct CountRd {
+        data: Vec<u8>,
+        pos: usize,
+        max_buf: usize,
+    }
+    impl CountRd {
+        fn new(data: Vec<u8>) -> Self {
+            Self {
+                data: data,
+                pos: 0,
+                max_buf: 0,
+            }
+        }
+        fn read_to(&mut self, buf: &mut [u8]) -> usize {
+            self.max_buf = self.max_buf.max(buf.len());
+            let n = buf.len().min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            n
+        }
+    }
+    #[cfg(not(feature = "tokios"))]
+    impl crate::AsyncRead for CountRd {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Ok(self.read_to(buf)))
+        }
+    }
+    #[cfg(feature = "tokios")]
+    impl crate::AsyncRead for CountRd {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let n = self.read_to(buf.initialize_unfilled());
+            buf.advance(n);
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+    #[test]
+    fn parse_oversize() {
+        use crate::socks::msg::{entity, tcps, MAX_BODYS};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::asyncs::Context::new();
+            //声明 1G body 但只有几个字节: 报错且不按声明的长度分配
+            let mut info = entity::MsgInfo::new();
+            info.version = entity::MSG_VER;
+            info.len_body = MAX_BODYS as u32;
+            let mut bts = vec![0x8d, 0x8f];
+            bts.extend_from_slice(&info.encode());
+            bts.extend_from_slice(b"abc");
+            let ln = bts.len();
+            let mut rd = CountRd::new(bts);
+            assert!(tcps::parse_msg(&ctx, &mut rd).await.is_err());
+            assert_eq!(rd.pos, ln);
+            assert!(rd.max_buf <= crate::iovec::READ_CHUNK, "{}", rd.max_buf);
+
+            //请求头同样按块读取
+            let mut info = crate::res::MsgInfo::new();
+            info.version = 2;
+            info.len_head = crate::LmtMaxConfig::default().max_heads as u32;
+            let mut bts = info.encode().to_vec();
+            bts.extend_from_slice(b"HBTPabc");
+            let ln = bts.len();
+            let egn = crate::Engine::new("");
+            let mut rd = CountRd::new(bts);
+            let bts = crate::iovec::read_full(&ctx, &mut rd, crate::res::MsgInfo::SIZE)
+                .await
+                .unwrap();
+            let info = crate::res::MsgInfo::decode(&bts[..]).unwrap();
+            assert!(crate::Context::parse_info(&ctx, &egn, &info, &mut rd)
+                .await
+                .is_err());
+            assert_eq!(rd.pos, ln);
+            assert!(rd.max_buf <= crate::iovec::READ_CHUNK, "{}", rd.max_buf);
+        });
+        #[cfg(feature = "cmp-lz4")]
+        {
+            use crate::socks::msg::compress::{decompress, Compress};
+            let bts = [0xff, 0xff, 0xff, 0x0f, 0x10, 0];
+            assert!(decompress(Compress::Lz4, &bts, MAX_BODYS).is_err());
+        }
+    }
+
+    #[test]
+    fn udp_reliable_epoch() {
+        use crate::socks::udp::reliable::{seq_before, DataHead, RelReceiver, RelSender};
+        use crate::socks::UdpConfig;
+        let cfg = UdpConfig::default();
+        let dt = |epoch: u32, seq: u32| DataHead {
+            epoch: epoch,
+            seq: seq,
+            ind: 0,
+            count: 1,
+        };
+        let bt = |v: u8| ruisutil::bytes::Bytes::from(vec![v]);
+        assert!(seq_before(u32::MAX, 0));
+        assert!(!seq_before(0, u32::MAX));
+        let mut rcv = RelReceiver::new();
+        for i in 0..3u32 {
+            let rt = rcv.push(&cfg, dt(7, i), bt(i as u8)).unwrap();
+            assert_eq!(rt.msgs.len(), 1);
+        }
+        //发送端重建: 新 epoch 从 0 开始, 接收端重置而不是当作重复包
+        let rt = rcv.push(&cfg, dt(9, 0), bt(0)).unwrap();
+        assert_eq!(rt.msgs.len(), 1);
+        assert_eq!((rt.epoch, rt.cum), (9, 1));
+        //迟到的旧 epoch 包不影响当前状态
+        let rt = rcv.push(&cfg, dt(7, 1000), bt(0)).unwrap();
+        assert_eq!((rt.epoch, rt.cum, rt.msgs.len()), (9, 1, 0));
+
+        //seq 回绕: cum 跨过 u32::MAX 时仍能确认回绕前的包
+        let mut snd = RelSender::new(&cfg);
+        let base = u32::MAX - 1;
+        let now = std::time::Instant::now();
+        for i in 0..4u32 {
+            snd.queue(base.wrapping_add(i), bt(0));
+        }
+        assert_eq!(snd.pull_sendable(&cfg, now).len(), 4);
+        snd.on_ack(&cfg, base.wrapping_add(3), &[], now);
+        assert_eq!(snd.waits(), 1);
+    }
+    #[test]
+    fn udp_reliable_loss() {
+        use crate::socks::{msg, LossSim, UMsgerServ};
+        use std::sync::{Arc, Mutex};
+        ruisutil::asyncs::current_block_on(async {
+            let ctx = ruisutil::Context::background(None);
+            let ls = Arc::new(Mutex::new(Vec::new()));
+            let a = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(Arc::new(Mutex::new(Vec::new()))));
+            let b = UMsgerServ::new(&ctx, "127.0.0.1:0".into(), UdpRecv(ls.clone()));
+            a.set_loss_sim(Some(LossSim::new(0.2, 7)));
+            b.set_loss_sim(Some(LossSim::new(0.2, 11)));
+            for it in [a.clone(), b.clone()] {
+                ruisutil::asyncs::task::spawn(async move {
+                    let _ = it.run().await;
+                });
+            }
+            ruisutil::asyncs::sleep(Duration::from_millis(100)).await;
+            let addr = b.local_addr().unwrap();
+            for i in 1..=50 {
+                let data = msg::Messageus {
+                    control: i,
+                    cmds: None,
+                    heads: None,
+                    bodys: msg::MsgBody::Bytes(vec![i as u8; 3000].into()),
+                };
+                a.send_reliable(data, &None, &addr).await.unwrap();
+            }
+            for _ in 0..200 {
+                if ls.lock().unwrap().len() >= 50 {
+                    break;
+                }
+                ruisutil::asyncs::sleep(Duration::from_millis(50)).await;
+            }
+            ctx.stop();
+            let ls = ls.lock().unwrap().clone();
+            assert_eq!(ls, (1..=50).collect::<Vec<i32>>());
+        });
+    }
+    #[test]
     fn arrmps() {
         let ls = match crate::ArraJMaps::from_bts(b"[{\"a\":1}]") {
             Ok(ls) => ls,
@@ -182,6 +2244,8 @@ pub const ResCodeOk: i32 = 1;
 pub const ResCodeErr: i32 = 2;
 pub const ResCodeAuth: i32 = 3;
 pub const ResCodeNotFound: i32 = 4;
+//请求版本不被支持, body 为说明
+pub const ResCodeVersion: i32 = 5;
 
 // #[macro_export]
 /* #[proc_macro_attribute]
@@ -203,6 +2267,7 @@ struct Inner {
     fns: RwLock<HashMap<i32, Vec<AsyncFnPtr>>>,
     lmts: RwLock<HashMap<i32, LmtMaxConfig>>,
     addr: String,
+    unix_mode: Option<u32>,
 }
 // unsafe impl Send for Engine {}
 // unsafe impl Sync for Engine {}
@@ -225,6 +2290,7 @@ impl Engine {
                 fns: RwLock::new(HashMap::new()),
                 lmts: RwLock::new(HashMap::new()),
                 addr: String::from(addr),
+                unix_mode: None,
                 lmt_tm: LmtTmConfig::default(),
                 lmt_max: LmtMaxConfig::default(),
             }),
@@ -236,6 +2302,10 @@ impl Engine {
     pub fn set_lmt_max(&self, limit: LmtMaxConfig) {
         unsafe { self.inner.muts().lmt_max = limit };
     }
+    //run_unix 创建的 socket 文件权限, 如 0o660; None 时沿用 umask
+    pub fn set_unix_mode(&self, mode: Option<u32>) {
+        unsafe { self.inner.muts().unix_mode = mode };
+    }
 
     pub async fn get_lmt_tm(&self) -> &LmtTmConfig {
         &self.inner.lmt_tm
@@ -248,11 +2318,42 @@ impl Engine {
         }
     }
 
+    //停止监听并取消所有请求: handler 运行在请求 ctx 下, 处理中的 handler 会被中止且不再回写
     pub fn stop(&self) {
         self.inner.ctx.cancel();
     }
     pub async fn run(&self) -> std::io::Result<()> {
         let lsr = TcpListener::bind(self.inner.addr.as_str()).await?;
+        self.run_listener(lsr).await
+    }
+    //内存管道连接: 不经过 socket, 直接进入处理链, 用于测试, 不需要 run
+    pub fn pipe(&self) -> BoxStream {
+        let (cli, serv) = pipe::duplex(0);
+        let c = self.clone();
+        task::spawn(async move {
+            c.run_cli(Box::new(serv)).await;
+        });
+        Box::new(cli)
+    }
+    pub async fn dispatch(
+        &self,
+        mut req: Request,
+        hds: Option<&[u8]>,
+        bds: Option<&[u8]>,
+    ) -> io::Result<Response> {
+        req.set_conn(self.pipe())?;
+        req.dors(hds, bds).await
+    }
+    //监听 unix socket, 启动时清理残留的 socket 文件, 结束(stop)后删除
+    #[cfg(unix)]
+    pub async fn run_unix<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let lsr = transport::unix_bind(path, self.inner.unix_mode).await?;
+        let _guard = transport::UnixGuard(path.to_path_buf());
+        self.run_listener(lsr).await
+    }
+    //使用已绑定的监听(TCP、unix 或自定义 Listener), 如端口 0 时先取 local_addr
+    pub async fn run_listener<L: Listener>(&self, lsr: L) -> std::io::Result<()> {
         // let mut incom = lsr.accept();
         self.inner
             .ctx
@@ -263,7 +2364,7 @@ impl Engine {
                             println!("stream conn err:{}!!!!", e);
                             break;
                         }
-                        Ok((conn, _)) => {
+                        Ok(conn) => {
                             let c = self.clone();
                             task::spawn(async move {
                                 c.run_cli(conn).await;
@@ -275,7 +2376,7 @@ impl Engine {
             })
             .await
     }
-    async fn run_cli(self, conn: TcpStream) {
+    async fn run_cli(self, conn: BoxStream) {
         match Context::parse_conn(&self.inner.ctx, &self, conn).await {
             Err(e) => println!("ParseContext err:{}", e),
             Ok(res) => {
@@ -293,12 +2394,31 @@ impl Engine {
                         fncs = Some(vs);
                     }
                 }
+                #[cfg(feature = "otel")]
+                let otel = trace::otel_start(&res);
+                #[cfg(feature = "otel")]
+                let mut errs: Option<String> = None;
                 if let Some(ls) = fncs {
                     for ft in ls {
                         if res.is_sended() {
                             break;
                         }
-                        if let Err(e) = ft.await {
+                        #[cfg(feature = "otel")]
+                        let ft = trace::otel_run(&otel, ft);
+                        //超过客户端 deadline 或对端已关闭时中止处理, 且不再回写
+                        if let Err(e) = res.ctx().wait_futs(ft).await {
+                            #[cfg(feature = "otel")]
+                            {
+                                errs = Some(e.to_string());
+                            }
+                            if res.expired() {
+                                println!("method deadline exceeded:{}", res.control());
+                                break;
+                            }
+                            if res.peer_closed() {
+                                println!("method peer closed:{}", res.control());
+                                break;
+                            }
                             if let Err(e) = res
                                 .res_string(ResCodeErr, format!("method return err:{}", e).as_str())
                                 .await
@@ -310,11 +2430,14 @@ impl Engine {
                 } else {
                     println!("not found function:{}", res.control())
                 }
-                if !res.is_sended() {
+                if !res.is_sended() && !res.expired() && !res.peer_closed() {
                     if let Err(e) = res.res_string(ResCodeErr, "Unknown").await {
                         println!("res_string Unknown err:{}", e.to_string().as_str());
                     }
                 }
+                res.unwatch().await;
+                #[cfg(feature = "otel")]
+                trace::otel_end(&otel, errs);
             }
         }
     }
@@ -0,0 +1,190 @@
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use crate::transport::{AsyncRead, AsyncWrite, Stream};
+
+/*
+  内存双工管道, 两端各自实现 Stream, 不经过 socket:
+  Engine::pipe/dispatch 用它把请求直接送进处理链
+*/
+pub fn duplex(cap: usize) -> (Pipe, Pipe) {
+    let a = Arc::new(Mutex::new(Buf::new()));
+    let b = Arc::new(Mutex::new(Buf::new()));
+    let cap = if cap > 0 { cap } else { 1024 * 64 };
+    (
+        Pipe {
+            rd: a.clone(),
+            wr: b.clone(),
+            cap: cap,
+        },
+        Pipe {
+            rd: b,
+            wr: a,
+            cap: cap,
+        },
+    )
+}
+
+struct Buf {
+    data: VecDeque<u8>,
+    //写端已关闭, 读完后 EOF
+    wr_closed: bool,
+    //读端已释放, 再写返回 BrokenPipe
+    rd_closed: bool,
+    rd_waker: Option<Waker>,
+    wr_waker: Option<Waker>,
+}
+impl Buf {
+    fn new() -> Self {
+        Self {
+            data: VecDeque::new(),
+            wr_closed: false,
+            rd_closed: false,
+            rd_waker: None,
+            wr_waker: None,
+        }
+    }
+}
+
+pub struct Pipe {
+    rd: Arc<Mutex<Buf>>,
+    wr: Arc<Mutex<Buf>>,
+    cap: usize,
+}
+impl Pipe {
+    fn read_to(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut b = match self.rd.lock() {
+            Ok(v) => v,
+            Err(_) => return Poll::Ready(Err(ruisutil::ioerr("pipe lock err", None))),
+        };
+        if b.data.is_empty() {
+            if b.wr_closed || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            b.rd_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(b.data.len());
+        for (i, v) in b.data.drain(..n).enumerate() {
+            buf[i] = v;
+        }
+        if let Some(w) = b.wr_waker.take() {
+            w.wake();
+        }
+        Poll::Ready(Ok(n))
+    }
+    fn write_from(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut b = match self.wr.lock() {
+            Ok(v) => v,
+            Err(_) => return Poll::Ready(Err(ruisutil::ioerr("pipe lock err", None))),
+        };
+        if b.rd_closed || b.wr_closed {
+            return Poll::Ready(Err(ruisutil::ioerr(
+                "pipe closed",
+                Some(io::ErrorKind::BrokenPipe),
+            )));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(self.cap.saturating_sub(b.data.len()));
+        if n == 0 {
+            b.wr_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        b.data.extend(&buf[..n]);
+        if let Some(w) = b.rd_waker.take() {
+            w.wake();
+        }
+        Poll::Ready(Ok(n))
+    }
+    //关闭写端, 对端读完已写入的数据后 EOF
+    pub(crate) fn close_wr(&self) {
+        if let Ok(mut b) = self.wr.lock() {
+            b.wr_closed = true;
+            if let Some(w) = b.rd_waker.take() {
+                w.wake();
+            }
+        }
+    }
+}
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        self.close_wr();
+        if let Ok(mut b) = self.rd.lock() {
+            b.rd_closed = true;
+            if let Some(w) = b.wr_waker.take() {
+                w.wake();
+            }
+        }
+    }
+}
+impl Stream for Pipe {}
+
+#[cfg(not(feature = "tokios"))]
+impl AsyncRead for Pipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.read_to(cx, buf)
+    }
+}
+#[cfg(not(feature = "tokios"))]
+impl AsyncWrite for Pipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.write_from(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close_wr();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokios")]
+impl AsyncRead for Pipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.read_to(cx, buf.initialize_unfilled()) {
+            Poll::Ready(Ok(n)) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+#[cfg(feature = "tokios")]
+impl AsyncWrite for Pipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.write_from(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close_wr();
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,21 +1,27 @@
 use std::{
-    io, mem,
+    io,
     net::ToSocketAddrs,
     sync::atomic::{AtomicBool, Ordering},
     time::Duration,
 };
 
 use qstring::QString;
-use ruisutil::asyncs::{net::TcpStream, sync::Mutex};
+use ruisutil::asyncs::{net::TcpStream, sync::Mutex, AsyncReadExt};
 use serde::{Deserialize, Serialize};
 
-use crate::res::*;
+use crate::{
+    ext::MsgExt,
+    res::*,
+    socks::msg::compress::{self, Compress},
+    trace::TraceContext,
+    transport::{self, BoxStream, Stream},
+};
 
 pub struct Request {
     ctx: Option<ruisutil::asyncs::Context>,
     sended: bool,
     addr: String,
-    conn: Option<TcpStream>,
+    conn: Option<BoxStream>,
     ctrl: i32,
     cmds: String,
     args: Option<QString>,
@@ -25,6 +31,11 @@ pub struct Request {
     lmt_max: LmtMaxConfig,
 
     use_version: u16,
+    sent_version: u16,
+    ext: MsgExt,
+    cmp: Compress,
+    cmp_min: usize,
+    send_dl: bool,
 }
 impl Request {
     const MINS: Duration = Duration::from_millis(100);
@@ -43,23 +54,81 @@ impl Request {
             lmt_max: LmtMaxConfig::default(),
 
             use_version: 0,
+            sent_version: 0,
+            ext: MsgExt::default(),
+            cmp: Compress::None,
+            cmp_min: 0,
+            send_dl: false,
         }
     }
-    pub fn new_conn(conn: TcpStream, control: i32) -> Self {
+    //连接 unix socket, 等同 Request::new("unix:<path>", control)
+    pub fn new_unix(path: &str, control: i32) -> Self {
+        Self::new(&format!("unix:{}", path), control)
+    }
+    pub fn new_conn<S: Stream + 'static>(conn: S, control: i32) -> Self {
         let mut rt = Self::new("", control);
         let _ = rt.set_conn(conn);
         rt
     }
-    pub fn set_conn(&mut self, conn: TcpStream) -> std::io::Result<()> {
+    pub fn set_conn<S: Stream + 'static>(&mut self, conn: S) -> std::io::Result<()> {
         if self.sended {
             return Err(ruisutil::ioerr("req is sended", None));
         }
-        self.conn = Some(conn);
+        self.conn = Some(transport::boxed(conn));
         Ok(())
     }
+    //0: 默认 v2. 扩展(req_id/压缩/追踪/deadline)需要显式设为 3:
+    //老版本服务端会把扩展区当作 cmd/args 读取, 无法识别也无法降级重试, 须确认服务端已支持 v3
     pub fn set_use_version(&mut self, v: u16) {
         self.use_version = v;
     }
+    pub fn ext(&self) -> &MsgExt {
+        &self.ext
+    }
+    pub fn ext_mut(&mut self) -> &mut MsgExt {
+        &mut self.ext
+    }
+    pub fn set_req_id(&mut self, id: u64) {
+        self.ext.req_id = Some(id);
+    }
+    pub fn set_encoding(&mut self, s: &str) {
+        self.ext.encoding = Some(String::from(s));
+    }
+    //body 不小于 min 时压缩, 需要 v3
+    pub fn set_compress(&mut self, cmp: Compress, min: usize) {
+        self.cmp = cmp;
+        self.cmp_min = min;
+    }
+    //W3C 追踪上下文, 需要 v3; 在处理函数中发起下游请求时传 Context::span
+    pub fn set_trace(&mut self, tc: &TraceContext) {
+        tc.to_ext(&mut self.ext);
+    }
+    //取 OpenTelemetry 当前 span 作为父 span
+    #[cfg(feature = "otel")]
+    pub fn set_trace_otel(&mut self, cx: &opentelemetry::Context) {
+        if let Some(v) = TraceContext::from_otel(cx) {
+            self.set_trace(&v);
+        }
+    }
+    //按 timeout 告知服务端剩余时长(deadline), 需要 v3
+    pub fn set_send_deadline(&mut self, on: bool) {
+        self.send_dl = on;
+    }
+    fn version(&self) -> io::Result<u16> {
+        let exts = !self.ext.is_empty() || self.cmp != Compress::None || self.send_dl;
+        match self.use_version {
+            0 if !exts => Ok(HBTP_VER),
+            v if v > HBTP_VER_MAX => Err(ruisutil::ioerr(
+                format!("not support version:{}", v),
+                Some(io::ErrorKind::InvalidInput),
+            )),
+            v if v < 3 && exts => Err(ruisutil::ioerr(
+                format!("ext need set_use_version(3), use:{}", v),
+                Some(io::ErrorKind::InvalidInput),
+            )),
+            v => Ok(v),
+        }
+    }
     pub fn set_lmt_tm(&mut self, limit: LmtTmConfig) {
         self.lmt_tm = limit;
     }
@@ -113,10 +182,19 @@ impl Request {
             self.args = Some(QString::new(vec![(name, value)]));
         }
     }
-    async fn send(&mut self, hds: Option<&[u8]>, bds: Option<&[u8]>) -> io::Result<TcpStream> {
-        let mut conn = if self.conn.is_none() {
-            ruisutil::asyncs::timeouts(self.tmout.clone(), TcpStream::connect(self.addr.as_str()))
-                .await??
+    async fn send(&mut self, hds: Option<&[u8]>, bds: Option<&[u8]>) -> io::Result<BoxStream> {
+        let version = self.version()?;
+        let mut conn: BoxStream = if let (None, Some(path)) =
+            (&self.conn, self.addr.strip_prefix("unix:"))
+        {
+            ruisutil::asyncs::timeouts(self.tmout.clone(), transport::unix_connect(path)).await??
+        } else if self.conn.is_none() {
+            let conn = ruisutil::asyncs::timeouts(
+                self.tmout.clone(),
+                TcpStream::connect(self.addr.as_str()),
+            )
+            .await??;
+            Box::new(conn)
         } else {
             let rst = std::mem::replace(&mut self.conn, None);
             rst.ok_or(ruisutil::ioerr("panic?", None))?
@@ -125,12 +203,35 @@ impl Request {
             return Err(ruisutil::ioerr("already request!", None));
         }
         self.sended = true;
+        self.sent_version = version;
+        let mut ext = self.ext.clone();
+        if self.send_dl && ext.deadline.is_none() {
+            ext.set_deadline_in(self.tmout);
+        }
+        let mut cmps = None;
+        if let Some(v) = bds {
+            if self.cmp != Compress::None {
+                cmps = compress::compress_min(self.cmp, self.cmp_min, v)?;
+                if cmps.is_some() {
+                    ext.compress = Some(self.cmp);
+                }
+            }
+        }
+        let bds = match &cmps {
+            Some(v) => Some(&v[..]),
+            None => bds,
+        };
+        let exts = if version >= 3 {
+            Some(ext.encode()?)
+        } else {
+            None
+        };
         let mut args = String::new();
         if let Some(v) = &self.args {
             args = v.to_string();
         }
         let mut reqs = MsgInfo::new();
-        reqs.version = 2;
+        reqs.version = version;
         reqs.control = self.ctrl;
         reqs.len_cmd = self.cmds.len() as u16;
         reqs.len_arg = args.len() as u16;
@@ -140,49 +241,71 @@ impl Request {
         if let Some(v) = bds {
             reqs.len_body = v.len() as u32;
         }
-        if self.use_version > 0 {
-            reqs.version = self.use_version;
-        }
-        let bts = ruisutil::struct2byte(&reqs);
-        let ctxp: ruisutil::asyncs::Context = (&self.ctx).into();
-        let ctx = ctxp.child_timeout(self.lmt_tm.tm_ohther);
-        ruisutil::write_all_async(&ctx, &mut conn, bts).await?;
+        let bts = reqs.encode();
+        let mut bufs: Vec<&[u8]> = Vec::with_capacity(5);
+        bufs.push(&bts[..]);
         if reqs.version >= 2 {
-            ruisutil::write_all_async(&ctx, &mut conn, &[0x48, 0x42, 0x54, 0x50]).await?;
+            bufs.push(&HBTP_MAGIC);
+        }
+        if let Some(v) = &exts {
+            bufs.push(&v[..]);
         }
         if reqs.len_cmd > 0 {
-            let bts = self.cmds.as_bytes();
-            ruisutil::write_all_async(&ctx, &mut conn, bts).await?;
+            bufs.push(self.cmds.as_bytes());
         }
         if reqs.len_arg > 0 {
-            let bts = args.as_bytes();
-            ruisutil::write_all_async(&ctx, &mut conn, bts).await?;
+            bufs.push(args.as_bytes());
         }
+        //头/heads/bodys 各自的超时, 合并为一次 vectored 写出
+        let mut phases = vec![(self.lmt_tm.tm_ohther, bufs)];
         if let Some(v) = hds {
-            let ctxs = ctxp.child_timeout(self.lmt_tm.tm_heads);
-            ruisutil::write_all_async(&ctxs, &mut conn, v).await?;
+            phases.push((self.lmt_tm.tm_heads, vec![v]));
         }
         if let Some(v) = bds {
-            let ctxs = ctxp.child_timeout(self.lmt_tm.tm_bodys);
-            ruisutil::write_all_async(&ctxs, &mut conn, v).await?;
+            phases.push((self.lmt_tm.tm_bodys, vec![v]));
         }
+        let ctxp: ruisutil::asyncs::Context = (&self.ctx).into();
+        crate::iovec::write_all_phases(&ctxp, &mut conn, &phases[..]).await?;
         Ok(conn)
     }
-    async fn response(&self, mut conn: TcpStream) -> io::Result<Response> {
-        let mut info = ResInfoV1::new();
-        let infoln = mem::size_of::<ResInfoV1>();
+    async fn response(&self, mut conn: BoxStream) -> io::Result<Response> {
+        let (info, heads, ext) = self.parse_head(self.sent_version, &mut conn).await?;
+        Ok(Response::new(
+            conn,
+            info.code,
+            heads,
+            info.len_body as usize,
+            self.lmt_max.max_bodys,
+            ext,
+        ))
+    }
+    //读取响应头、扩展区和 heads, body 由 Response 按需读取
+    pub(crate) async fn parse_head<R: AsyncReadExt + Unpin>(
+        &self,
+        ver: u16,
+        conn: &mut R,
+    ) -> io::Result<(ResInfoV1, Option<ruisutil::bytes::Bytes>, MsgExt)> {
         let ctxp: ruisutil::asyncs::Context = (&self.ctx).into();
         let ctx = ctxp.child_timeout(self.tmout);
-        let bts = ruisutil::read_all_async(&ctx, &mut conn, infoln).await?;
-        ruisutil::byte2struct(&mut info, &bts[..])?;
+        let bts = crate::iovec::read_full(&ctx, conn, ResInfoV1::SIZE).await?;
+        let info = ResInfoV1::decode(&bts[..])?;
         if info.len_head as u64 > self.lmt_max.max_heads {
             return Err(ruisutil::ioerr("bytes2 out limit!!", None));
         }
+        if info.len_body as u64 > self.lmt_max.max_bodys {
+            return Err(ruisutil::ioerr("bytes3 out limit!!", None));
+        }
+        //服务端不支持该版本时直接回错误且不带扩展区
+        let ext = if ver >= 3 && info.code != crate::ResCodeVersion {
+            MsgExt::read(&ctx, conn).await?
+        } else {
+            MsgExt::default()
+        };
         let heads;
         let ctxs = ctxp.child_timeout(self.lmt_tm.tm_heads);
         let lnsz = info.len_head as usize;
         if lnsz > 0 {
-            let bts = ruisutil::read_all_async(&ctxs, &mut conn, lnsz as usize).await?;
+            let bts = crate::iovec::read_full(&ctxs, conn, lnsz as usize).await?;
             heads = Some(ruisutil::bytes::Bytes::from(bts));
         } else {
             heads = None;
@@ -193,12 +316,7 @@ impl Request {
             let bts = ruisutil::read_all_async(&ctx, &mut conn, lnsz as usize).await?;
             rt.bodys = Some(bts);
         } */
-        Ok(Response::new(
-            conn,
-            info.code,
-            heads,
-            info.len_body as usize,
-        ))
+        Ok((info, heads, ext))
     }
     pub async fn dors(&mut self, hds: Option<&[u8]>, bds: Option<&[u8]>) -> io::Result<Response> {
         let conn = self.send(hds, bds).await?;
@@ -239,16 +357,25 @@ pub struct Response {
 }
 
 pub struct Inner {
-    conn: Option<TcpStream>,
+    conn: Option<BoxStream>,
 
     code: i32,
     heads: Option<ruisutil::bytes::Bytes>,
     bodys: Option<ruisutil::bytes::Bytes>,
     bodyok: AtomicBool,
     bodylen: usize,
+    bodymax: u64,
+    ext: MsgExt,
 }
 impl<'a> Response {
-    fn new(conn: TcpStream, code: i32, heads: Option<ruisutil::bytes::Bytes>, byln: usize) -> Self {
+    fn new(
+        conn: BoxStream,
+        code: i32,
+        heads: Option<ruisutil::bytes::Bytes>,
+        byln: usize,
+        bymax: u64,
+        ext: MsgExt,
+    ) -> Self {
         Self {
             inner: ruisutil::ArcMut::new(Inner {
                 conn: Some(conn),
@@ -257,16 +384,18 @@ impl<'a> Response {
                 bodys: None,
                 bodyok: AtomicBool::new(false),
                 bodylen: byln,
+                bodymax: bymax,
+                ext: ext,
             }),
         }
     }
-    pub fn get_conn(&self) -> &TcpStream {
+    pub fn get_conn(&self) -> &BoxStream {
         if let Some(v) = &self.inner.conn {
             return v;
         }
         panic!("conn?");
     }
-    pub async fn own_conn(&self) -> TcpStream {
+    pub async fn own_conn(&self) -> BoxStream {
         self.get_bodys(&None).await;
         let ins = unsafe { self.inner.muts() };
         if let Some(v) = std::mem::replace(&mut ins.conn, None) {
@@ -277,6 +406,13 @@ impl<'a> Response {
     pub fn get_code(&self) -> i32 {
         self.inner.code
     }
+    pub fn ext(&self) -> &MsgExt {
+        &self.inner.ext
+    }
+    //服务端处理本次请求的 span, 请求带了追踪上下文时才有
+    pub fn trace(&self) -> Option<TraceContext> {
+        TraceContext::from_ext(&self.inner.ext).unwrap_or_default()
+    }
     pub fn get_heads(&self) -> &Option<ruisutil::bytes::Bytes> {
         &self.inner.heads
     }
@@ -289,8 +425,15 @@ impl<'a> Response {
                 let ins = unsafe { self.inner.muts() };
                 if let Some(conn) = &mut ins.conn {
                     let ctxs = ctx.into();
-                    match ruisutil::read_all_async(&ctxs, conn, self.inner.bodylen).await {
-                        Ok(bts) => ins.bodys = Some(ruisutil::bytes::Bytes::from(bts)),
+                    match crate::iovec::read_full(&ctxs, conn, self.inner.bodylen).await {
+                        Ok(bts) => match body_decompress(
+                            &self.inner.ext,
+                            bts,
+                            self.inner.bodymax,
+                        ) {
+                            Ok(v) => ins.bodys = Some(ruisutil::bytes::Bytes::from(v)),
+                            Err(e) => println!("get_bodys decompress err:{}", e),
+                        },
                         Err(e) => println!("get_bodys tcp read err:{}", e),
                     }
                 }
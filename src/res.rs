@@ -1,9 +1,25 @@
-use std::{collections::HashMap, io, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    time::{Duration, Instant},
+};
 
 use qstring::QString;
-use ruisutil::asyncs::{net::TcpStream, sync::Mutex};
+use ruisutil::asyncs::{sync::Mutex, AsyncReadExt};
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    ext::MsgExt,
+    trace::TraceContext,
+    transport::{BoxStream, Stream},
+};
+
+// 'H', 'B', 'T', 'P'
+pub const HBTP_MAGIC: [u8; 4] = [0x48, 0x42, 0x54, 0x50];
+//默认请求版本, 扩展区需要显式使用 v3
+pub const HBTP_VER: u16 = 2;
+pub const HBTP_VER_MAX: u16 = 3;
+
 /* fn callfun(fun: &ConnFun, ctx: &mut Context) {
   std::panic::catch_unwind(|| println!("callfun catch panic"));
   fun(ctx);
@@ -14,8 +30,10 @@ pub struct Context {
     inner: ruisutil::ArcMut<Inner>,
 }
 struct Inner {
+    //请求级 ctx: 引擎停止、超过客户端 deadline 或对端关闭时取消
+    ctx: ruisutil::asyncs::Context,
     sended: bool,
-    conn: Option<TcpStream>,
+    conn: Option<BoxStream>,
     ctrl: i32,
     cmds: String,
     args: Option<QString>,
@@ -23,13 +41,28 @@ struct Inner {
     bodys: Option<ruisutil::bytes::Bytes>,
     bodyok: Mutex<bool>,
     bodylen: usize,
+    bodymax: u64,
+    ver: u16,
+    ext: MsgExt,
+    //收到请求时按扩展区的剩余时长换算的本地时间点
+    deadline: Option<Instant>,
+    //请求带来的追踪上下文, 以及本次处理的 span(首次使用时生成)
+    trace: Option<TraceContext>,
+    span: Option<TraceContext>,
+    //对端关闭监听, 见 watch_peer; 监听期间连接移入 watch_conn, 地址等取监听前的快照
+    watch: Option<ruisutil::asyncs::Context>,
+    watch_conn: Mutex<Option<BoxStream>>,
+    watch_addr: (Option<std::net::SocketAddr>, Option<std::net::SocketAddr>),
+    watch_cred: Option<crate::PeerCred>,
+    closed: bool,
 
     data: HashMap<String, Vec<u8>>,
 }
 impl<'a> Context {
-    fn new(control: i32, byln: usize) -> Self {
+    fn new(ctx: ruisutil::asyncs::Context, control: i32, byln: usize) -> Self {
         Self {
             inner: ruisutil::ArcMut::new(Inner {
+                ctx: ctx,
                 sended: false,
                 conn: None,
                 ctrl: control,
@@ -39,6 +72,17 @@ impl<'a> Context {
                 bodys: None,
                 bodyok: Mutex::new(false),
                 bodylen: byln,
+                bodymax: LmtMaxConfig::default().max_bodys,
+                ver: HBTP_VER,
+                ext: MsgExt::default(),
+                deadline: None,
+                trace: None,
+                span: None,
+                watch: None,
+                watch_conn: Mutex::new(None),
+                watch_addr: (None, None),
+                watch_cred: None,
+                closed: false,
                 data: HashMap::new(),
             }),
         }
@@ -47,17 +91,48 @@ impl<'a> Context {
     pub(crate) async fn parse_conn(
         ctx: &ruisutil::asyncs::Context,
         egn: &crate::Engine,
-        mut conn: TcpStream,
+        mut conn: BoxStream,
     ) -> io::Result<Self> {
-        let mut info = MsgInfo::new();
-        let infoln = std::mem::size_of::<MsgInfo>();
         let lmt_tm = egn.get_lmt_tm().await;
         let ctxs=ctx.child_timeout(lmt_tm.tm_ohther);
-        let bts = ruisutil::read_all_async(&ctxs, &mut conn, infoln).await?;
-        ruisutil::byte2struct(&mut info, &bts[..])?;
-        if info.version < 1 && info.version > 2 {
-            return Err(ruisutil::ioerr("not found version!", None));
+        let bts = crate::iovec::read_full(&ctxs, &mut conn, MsgInfo::SIZE).await?;
+        let info = MsgInfo::decode(&bts[..])?;
+        if !version_ok(info.version) {
+            //告知对端支持的最高版本, 之后断开
+            let mut res = ResInfoV1::new();
+            let bds = format!(
+                "hbtp version {} not supported, max:{}",
+                info.version, HBTP_VER_MAX
+            );
+            res.code = crate::ResCodeVersion;
+            res.len_body = bds.len() as u32;
+            let bts = res.encode();
+            let bufs: Vec<&[u8]> = vec![&bts[..], bds.as_bytes()];
+            let _ = crate::iovec::write_all_vectored(&ctxs, &mut conn, &bufs[..]).await;
+            return Err(ruisutil::ioerr(
+                format!("not support version:{}", info.version),
+                Some(io::ErrorKind::InvalidData),
+            ));
+        }
+        let rt = Self::parse_info(ctx, egn, &info, &mut conn).await?;
+        unsafe { rt.inner.muts() }.conn = Some(conn);
+        Ok(rt)
+    }
+    //读取请求头之后的部分(不含 body), 不依赖具体连接类型
+    pub(crate) async fn parse_info<R: AsyncReadExt + Unpin>(
+        ctx: &ruisutil::asyncs::Context,
+        egn: &crate::Engine,
+        info: &MsgInfo,
+        conn: &mut R,
+    ) -> io::Result<Self> {
+        if !version_ok(info.version) {
+            return Err(ruisutil::ioerr(
+                format!("not support version:{}", info.version),
+                Some(io::ErrorKind::InvalidData),
+            ));
         }
+        let lmt_tm = egn.get_lmt_tm().await;
+        let ctxs = ctx.child_timeout(lmt_tm.tm_ohther);
         let cfg = egn.get_lmt_max(info.control).await;
         if (info.len_cmd as u64 + info.len_arg as u64) > cfg.max_ohther {
             return Err(ruisutil::ioerr("bytes1 out limit!!", None));
@@ -65,20 +140,40 @@ impl<'a> Context {
         if info.len_head as u64 > cfg.max_heads {
             return Err(ruisutil::ioerr("bytes2 out limit!!", None));
         }
+        if info.len_body as u64 > cfg.max_bodys {
+            return Err(ruisutil::ioerr("bytes3 out limit!!", None));
+        }
         if info.version >= 2 {
-            let bts = ruisutil::read_all_async(&ctxs, &mut conn, 4).await?;
-            // 'H', 'B', 'T', 'P'
-            // if bts[0] == 0x48 && bts[0] == 0x42 && bts[0] == 0x54 && bts[0] == 0x50 {
-            if !bts[..].eq(&[0x48, 0x42, 0x54, 0x50]) {
+            let bts = crate::iovec::read_full(&ctxs, conn, 4).await?;
+            if !bts[..].eq(&HBTP_MAGIC) {
                 return Err(ruisutil::ioerr("HBTP fmt err!!", None));
             }
         }
+        let ext = if info.version >= 3 {
+            MsgExt::read(&ctxs, conn).await?
+        } else {
+            MsgExt::default()
+        };
 
-        let rt = Self::new(info.control, info.len_body as usize);
+        let dlin = ext.deadline_in();
+        let ctxr = match dlin {
+            Some(v) => ctx.child_timeout(v),
+            None => ctx.child(),
+        };
+        let rt = Self::new(ctxr, info.control, info.len_body as usize);
         let ins = unsafe { rt.inner.muts() };
+        ins.ver = info.version;
+        ins.deadline = dlin.map(|v| Instant::now() + v);
+        ins.bodymax = cfg.max_bodys;
+        //traceparent 不合法时按 W3C 约定忽略, 另起新 trace
+        ins.trace = TraceContext::from_ext(&ext).unwrap_or_default();
+        if let Some(v) = &ins.trace {
+            ins.span = Some(v.child());
+        }
+        ins.ext = ext;
         let lnsz = info.len_cmd as usize;
         if lnsz > 0 {
-            let bts = ruisutil::read_all_async(&ctxs, &mut conn, lnsz).await?;
+            let bts = crate::iovec::read_full(&ctxs, conn, lnsz).await?;
             ins.cmds = match std::str::from_utf8(&bts[..]) {
                 Err(_) => return Err(ruisutil::ioerr("cmd err", None)),
                 Ok(v) => String::from(v),
@@ -86,7 +181,7 @@ impl<'a> Context {
         }
         let lnsz = info.len_arg as usize;
         if lnsz > 0 {
-            let bts = ruisutil::read_all_async(&ctxs, &mut conn, lnsz as usize).await?;
+            let bts = crate::iovec::read_full(&ctxs, conn, lnsz as usize).await?;
             let args = match std::str::from_utf8(&bts[..]) {
                 Err(_) => return Err(ruisutil::ioerr("args err", None)),
                 Ok(v) => String::from(v),
@@ -96,7 +191,7 @@ impl<'a> Context {
         let ctxs=ctx.child_timeout(lmt_tm.tm_heads);
         let lnsz = info.len_head as usize;
         if lnsz > 0 {
-            let bts = ruisutil::read_all_async(&ctxs, &mut conn, lnsz as usize).await?;
+            let bts = crate::iovec::read_full(&ctxs, conn, lnsz as usize).await?;
             ins.heads = Some(ruisutil::bytes::Bytes::from(bts));
         }
         /* let ctxs = ruisutil::Context::with_timeout(Some(ctx.clone()), lmt_tm.tm_bodys);
@@ -105,7 +200,6 @@ impl<'a> Context {
             let bts = ruisutil::read_all_async(&ctxs, &mut conn, lnsz as usize).await?;
             ins.bodys = Some(bts);
         } */
-        ins.conn = Some(conn);
         Ok(rt)
     }
 
@@ -127,8 +221,9 @@ impl<'a> Context {
         }
         panic!("conn?");
     } */
-    pub async fn own_conn(&self) -> TcpStream {
+    pub async fn own_conn(&self) -> BoxStream {
         self.get_bodys(&None).await;
+        self.unwatch().await;
         let ins = unsafe { self.inner.muts() };
         if let Some(v) = std::mem::replace(&mut ins.conn, None) {
             return v;
@@ -136,6 +231,9 @@ impl<'a> Context {
         panic!("conn?");
     }
     pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        if self.inner.watch.is_some() {
+            return self.inner.watch_addr.0.ok_or(ruisutil::ioerr("can't get addr", None));
+        }
         if let Some(conn) = &self.inner.conn {
             let addr = conn.local_addr()?;
             Ok(addr)
@@ -143,7 +241,24 @@ impl<'a> Context {
             Err(ruisutil::ioerr("can't get addr", None))
         }
     }
+    //unix socket 连接的对端 uid/gid/pid, 用于鉴权
+    pub fn peer_cred(&self) -> io::Result<crate::PeerCred> {
+        if self.inner.watch.is_some() {
+            return self.inner.watch_cred.clone().ok_or(ruisutil::ioerr(
+                "can't get peer cred",
+                Some(io::ErrorKind::Unsupported),
+            ));
+        }
+        if let Some(conn) = &self.inner.conn {
+            conn.peer_cred()
+        } else {
+            Err(ruisutil::ioerr("can't get peer cred", None))
+        }
+    }
     pub fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        if self.inner.watch.is_some() {
+            return self.inner.watch_addr.1.ok_or(ruisutil::ioerr("can't get addr", None));
+        }
         if let Some(conn) = &self.inner.conn {
             let addr = conn.peer_addr()?;
             Ok(addr)
@@ -157,6 +272,112 @@ impl<'a> Context {
     pub fn command(&self) -> &str {
         self.inner.cmds.as_str()
     }
+    //请求使用的协议版本, 响应头格式随之
+    pub fn version(&self) -> u16 {
+        self.inner.ver
+    }
+    pub fn ext(&self) -> &MsgExt {
+        &self.inner.ext
+    }
+    //请求携带的 W3C 追踪上下文
+    pub fn trace(&self) -> Option<&TraceContext> {
+        self.inner.trace.as_ref()
+    }
+    //本次处理的 span, 向下游请求传递时用它作为父 span
+    pub fn span(&self) -> &TraceContext {
+        let ins = unsafe { self.inner.muts() };
+        ins.span.get_or_insert_with(TraceContext::new_root)
+    }
+    #[cfg(feature = "otel")]
+    pub(crate) fn set_span(&self, span: TraceContext) {
+        unsafe { self.inner.muts() }.span = Some(span);
+    }
+    //客户端愿意等待到的时间点, 来自请求扩展区(v3)
+    pub fn deadline(&self) -> Option<Instant> {
+        self.inner.deadline
+    }
+    //剩余时长, 已过期为 0
+    pub fn remaining(&self) -> Option<Duration> {
+        self.inner
+            .deadline
+            .map(|v| v.saturating_duration_since(Instant::now()))
+    }
+    pub fn expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+    //请求级 ctx, 耗时操作可用 wait_futs 包裹或轮询 cancelled
+    //(对端关闭需先调用 watch_peer)
+    pub fn ctx(&self) -> &ruisutil::asyncs::Context {
+        &self.inner.ctx
+    }
+    /*
+      消费完 body 后监听连接读端, 对端关闭时取消请求级 ctx, 处理函数随之中止.
+      读到 EOF 即视为关闭: 一个连接只承载一个请求, 客户端在等待响应期间
+      不应半关闭写方向(shutdown write), 否则处理会被中止.
+      监听期间连接归监听任务所有, response/own_conn 会先停止监听取回连接
+    */
+    pub async fn watch_peer(&self) {
+        self.get_bodys(&None).await;
+        let ins = unsafe { self.inner.muts() };
+        if ins.watch.is_some() {
+            return;
+        }
+        let conn = match ins.conn.take() {
+            None => return,
+            Some(v) => v,
+        };
+        ins.watch_addr = (conn.local_addr().ok(), conn.peer_addr().ok());
+        ins.watch_cred = conn.peer_cred().ok();
+        let wctx = self.inner.ctx.child();
+        ins.watch = Some(wctx.clone());
+        *self.inner.watch_conn.lock().await = Some(conn);
+        let c = self.clone();
+        ruisutil::asyncs::task::spawn(async move {
+            c.run_watch(wctx).await;
+        });
+    }
+    async fn run_watch(&self, wctx: ruisutil::asyncs::Context) {
+        let mut lk = self.inner.watch_conn.lock().await;
+        let mut buf = [0u8; 256];
+        while let Some(conn) = lk.as_mut() {
+            //body 之后的数据直接丢弃
+            match wctx.wait_futs(async { conn.read(&mut buf).await }).await {
+                Ok(n) if n > 0 => continue,
+                _ => {}
+            }
+            if !wctx.cancelled() {
+                unsafe { self.inner.muts() }.closed = true;
+                self.inner.ctx.cancel();
+            }
+            break;
+        }
+    }
+    //停止监听并取回连接
+    pub(crate) async fn unwatch(&self) {
+        let wctx = match &self.inner.watch {
+            None => return,
+            Some(v) => v.clone(),
+        };
+        wctx.cancel();
+        let conn = self.inner.watch_conn.lock().await.take();
+        let ins = unsafe { self.inner.muts() };
+        if conn.is_some() {
+            ins.conn = conn;
+        }
+        ins.watch = None;
+    }
+    //watch_peer 检测到对端已关闭
+    pub fn peer_closed(&self) -> bool {
+        self.inner.closed
+    }
+    //ctx 取消(超过 deadline/对端关闭/引擎停止)时返回
+    pub async fn done(&self) {
+        let _ = self
+            .inner
+            .ctx
+            .wait_futs(std::future::pending::<io::Result<()>>())
+            .await;
+    }
     pub fn get_args(&'a self) -> Option<&'a QString> {
         if let Some(v) = &self.inner.args {
             Some(v)
@@ -207,8 +428,15 @@ impl<'a> Context {
                         Some(v) => v,
                     }; */
                     let ctxs=ctx.into();
-                    match ruisutil::read_all_async(&ctxs, conn, self.inner.bodylen).await {
-                        Ok(bts) => ins.bodys = Some(ruisutil::bytes::Bytes::from(bts)),
+                    match crate::iovec::read_full(&ctxs, conn, self.inner.bodylen).await {
+                        Ok(bts) => match body_decompress(
+                            &self.inner.ext,
+                            bts,
+                            self.inner.bodymax,
+                        ) {
+                            Ok(v) => ins.bodys = Some(ruisutil::bytes::Bytes::from(v)),
+                            Err(e) => println!("get_bodys decompress err:{}", e),
+                        },
                         Err(e) => println!("get_bodys tcp read err:{}", e),
                     }
                 }
@@ -261,12 +489,20 @@ impl<'a> Context {
             Some(v) => v,
             None => return Err(ruisutil::ioerr("not found conn", None)),
         }; */
+        self.unwatch().await;
         if let None = self.inner.conn {
             return Err(ruisutil::ioerr("not found conn", None));
         }
         if self.inner.sended {
             return Err(ruisutil::ioerr("already responsed!", None));
         }
+        //客户端已放弃等待, 不再回写
+        if self.expired() {
+            return Err(ruisutil::ioerr(
+                "deadline exceeded",
+                Some(io::ErrorKind::TimedOut),
+            ));
+        }
         let ins = unsafe { self.inner.muts() };
         ins.sended = true;
         let mut res = ResInfoV1::new();
@@ -277,18 +513,34 @@ impl<'a> Context {
         if let Some(v) = bds {
             res.len_body = v.len() as u32;
         }
+        //v3 请求的响应头后带扩展区, 回显 req_id 和本次处理的 span
+        let exts = if ins.ver >= 3 {
+            let mut ext = MsgExt::default();
+            ext.req_id = ins.ext.req_id;
+            if let (Some(_), Some(v)) = (&ins.trace, &ins.span) {
+                v.to_ext(&mut ext);
+            }
+            Some(ext.encode()?)
+        } else {
+            None
+        };
         if let Some(conn) = &mut ins.conn {
-            let bts = ruisutil::struct2byte(&res);
-            let ctx=ruisutil::asyncs::Context::new_timeout(Duration::from_secs(10));
-            ruisutil::write_all_async(&ctx, conn, bts).await?;
+            let bts = res.encode();
+            let mut bufs: Vec<&[u8]> = Vec::with_capacity(2);
+            bufs.push(&bts[..]);
+            if let Some(v) = &exts {
+                bufs.push(&v[..]);
+            }
+            //整体不超过 10s, heads/bodys 分段超时
+            let mut phases = vec![(Duration::from_secs(10), bufs)];
             if let Some(v) = hds {
-                let ctxs=ctx.child_timeout(Duration::from_secs(20));
-                ruisutil::write_all_async(&ctxs, conn, v).await?;
+                phases.push((Duration::from_secs(20), vec![v]));
             }
             if let Some(v) = bds {
-                let ctxs=ctx.child_timeout(Duration::from_secs(30));
-                ruisutil::write_all_async(&ctxs, conn, v).await?;
+                phases.push((Duration::from_secs(30), vec![v]));
             }
+            let ctx = ruisutil::asyncs::Context::new_timeout(Duration::from_secs(10));
+            crate::iovec::write_all_phases(&ctx, conn, &phases[..]).await?;
         } else {
             return Err(ruisutil::ioerr("not found conn", None));
         }
@@ -309,8 +561,28 @@ impl<'a> Context {
     }
 }
 
+pub(crate) fn version_ok(v: u16) -> bool {
+    v >= 1 && v <= HBTP_VER_MAX
+}
+//max 为 LmtMaxConfig.max_bodys, 防止小 body 解压膨胀
+pub(crate) fn body_decompress(ext: &MsgExt, bts: Vec<u8>, max: u64) -> io::Result<Vec<u8>> {
+    use crate::socks::msg::compress;
+    match ext.compress {
+        Some(cmp) if cmp != compress::Compress::None => compress::decompress(cmp, &bts[..], max),
+        _ => Ok(bts),
+    }
+}
+
 //----------------------------------bean
-#[repr(C, packed)]
+/*
+  请求头, 18字节, 小端序:
+  0  version  u16
+  2  control  i32
+  6  len_cmd  u16
+  8  len_arg  u16
+  10 len_head u32
+  14 len_body u32
+*/
 pub struct MsgInfo {
     pub version: u16,
     pub control: i32,
@@ -320,6 +592,7 @@ pub struct MsgInfo {
     pub len_body: u32,
 }
 impl MsgInfo {
+    pub const SIZE: usize = 18;
     pub fn new() -> Self {
         Self {
             version: 0,
@@ -330,14 +603,46 @@ impl MsgInfo {
             len_body: 0,
         }
     }
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut rt = [0u8; Self::SIZE];
+        rt[0..2].copy_from_slice(&self.version.to_le_bytes());
+        rt[2..6].copy_from_slice(&self.control.to_le_bytes());
+        rt[6..8].copy_from_slice(&self.len_cmd.to_le_bytes());
+        rt[8..10].copy_from_slice(&self.len_arg.to_le_bytes());
+        rt[10..14].copy_from_slice(&self.len_head.to_le_bytes());
+        rt[14..18].copy_from_slice(&self.len_body.to_le_bytes());
+        rt
+    }
+    pub fn decode(bts: &[u8]) -> io::Result<Self> {
+        if bts.len() < Self::SIZE {
+            return Err(ruisutil::ioerr(
+                "MsgInfo len err",
+                Some(io::ErrorKind::UnexpectedEof),
+            ));
+        }
+        Ok(Self {
+            version: u16::from_le_bytes([bts[0], bts[1]]),
+            control: i32::from_le_bytes([bts[2], bts[3], bts[4], bts[5]]),
+            len_cmd: u16::from_le_bytes([bts[6], bts[7]]),
+            len_arg: u16::from_le_bytes([bts[8], bts[9]]),
+            len_head: u32::from_le_bytes([bts[10], bts[11], bts[12], bts[13]]),
+            len_body: u32::from_le_bytes([bts[14], bts[15], bts[16], bts[17]]),
+        })
+    }
 }
-#[repr(C, packed)]
+/*
+  响应头, 12字节, 小端序:
+  0 code     i32
+  4 len_head u32
+  8 len_body u32
+*/
 pub struct ResInfoV1 {
     pub code: i32,
     pub len_head: u32,
     pub len_body: u32,
 }
 impl ResInfoV1 {
+    pub const SIZE: usize = 12;
     pub fn new() -> Self {
         Self {
             code: 0,
@@ -345,12 +650,34 @@ impl ResInfoV1 {
             len_body: 0,
         }
     }
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut rt = [0u8; Self::SIZE];
+        rt[0..4].copy_from_slice(&self.code.to_le_bytes());
+        rt[4..8].copy_from_slice(&self.len_head.to_le_bytes());
+        rt[8..12].copy_from_slice(&self.len_body.to_le_bytes());
+        rt
+    }
+    pub fn decode(bts: &[u8]) -> io::Result<Self> {
+        if bts.len() < Self::SIZE {
+            return Err(ruisutil::ioerr(
+                "ResInfoV1 len err",
+                Some(io::ErrorKind::UnexpectedEof),
+            ));
+        }
+        Ok(Self {
+            code: i32::from_le_bytes([bts[0], bts[1], bts[2], bts[3]]),
+            len_head: u32::from_le_bytes([bts[4], bts[5], bts[6], bts[7]]),
+            len_body: u32::from_le_bytes([bts[8], bts[9], bts[10], bts[11]]),
+        })
+    }
 }
 
 #[derive(Clone)]
 pub struct LmtMaxConfig {
     pub max_ohther: u64,
     pub max_heads: u64,
+    //body 上限, 压缩的 body 按解压后的大小计
+    pub max_bodys: u64,
 }
 
 impl Default for LmtMaxConfig {
@@ -358,6 +685,7 @@ impl Default for LmtMaxConfig {
         Self {
             max_ohther: 1024 * 1024 * 2, //2M
             max_heads: 1024 * 1024 * 10, //10M
+            max_bodys: 1024 * 1024 * 100, //100M
         }
     }
 }
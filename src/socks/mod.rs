@@ -1,6 +1,6 @@
-mod tcp;
-mod udp;
+pub(crate) mod tcp;
+pub(crate) mod udp;
 pub mod msg;
 
-pub use tcp::{Messager,MessageRecv,Senders};
-pub use udp::{UMsgerServ,IUMsgerServ};
\ No newline at end of file
+pub use tcp::{Messager,MessageRecv,Senders,MsgPriority,QueuePolicy,SendConfig};
+pub use udp::{UMsgerServ,IUMsgerServ,UMsgerClient,IUMsgerCli,UdpConfig,LossSim,UdpSession,SessionClose,DropReason,DropStats};
\ No newline at end of file
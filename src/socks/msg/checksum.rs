@@ -0,0 +1,63 @@
+use std::{error, fmt, io};
+
+const POLY: u32 = 0x82f63b78; //CRC32C (Castagnoli), reversed
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+static TABLE: [u32; 256] = make_table();
+
+pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in data {
+        crc = TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_append(0, data)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumError {
+    pub expect: u32,
+    pub actual: u32,
+}
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checksum err:expect={:08x},actual={:08x}",
+            self.expect, self.actual
+        )
+    }
+}
+impl error::Error for ChecksumError {}
+impl ChecksumError {
+    pub fn ioerr(expect: u32, actual: u32) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, Self { expect, actual })
+    }
+    //从 io::Error 中取出校验错误
+    pub fn from_ioerr(e: &io::Error) -> Option<&Self> {
+        e.get_ref().and_then(|v| v.downcast_ref::<Self>())
+    }
+}
+
+pub fn check(expect: u32, actual: u32) -> io::Result<()> {
+    if expect != actual {
+        return Err(ChecksumError::ioerr(expect, actual));
+    }
+    Ok(())
+}
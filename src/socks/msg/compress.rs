@@ -0,0 +1,167 @@
+use std::io;
+#[cfg(any(feature = "cmp-deflate", feature = "cmp-zstd"))]
+use std::io::Read;
+
+// version 高8位为flags, 低4位为压缩算法
+pub const FLAG_CMP_MASK: u8 = 0x0f;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compress {
+    None = 0,
+    Deflate = 1,
+    Zstd = 2,
+    Lz4 = 3,
+}
+
+impl Compress {
+    pub fn from_flags(flags: u8) -> io::Result<Self> {
+        match flags & FLAG_CMP_MASK {
+            0 => Ok(Compress::None),
+            1 => Ok(Compress::Deflate),
+            2 => Ok(Compress::Zstd),
+            3 => Ok(Compress::Lz4),
+            v => Err(ruisutil::ioerr(format!("unknown compress:{}", v), None)),
+        }
+    }
+    pub fn flags(&self) -> u8 {
+        *self as u8
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compress::None => "none",
+            Compress::Deflate => "deflate",
+            Compress::Zstd => "zstd",
+            Compress::Lz4 => "lz4",
+        }
+    }
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s.trim() {
+            "none" => Some(Compress::None),
+            "deflate" => Some(Compress::Deflate),
+            "zstd" => Some(Compress::Zstd),
+            "lz4" => Some(Compress::Lz4),
+            _ => None,
+        }
+    }
+    //本地编译支持的算法(按优先级)
+    pub fn supported() -> Vec<Self> {
+        let mut rt = Vec::new();
+        #[cfg(feature = "cmp-zstd")]
+        rt.push(Compress::Zstd);
+        #[cfg(feature = "cmp-lz4")]
+        rt.push(Compress::Lz4);
+        #[cfg(feature = "cmp-deflate")]
+        rt.push(Compress::Deflate);
+        rt
+    }
+    pub fn is_supported(&self) -> bool {
+        *self == Compress::None || Self::supported().contains(self)
+    }
+
+    //格式: "zstd,lz4"
+    pub fn fmt_list(ls: &[Self]) -> String {
+        let mut rt = String::new();
+        for it in ls {
+            if !rt.is_empty() {
+                rt.push(',');
+            }
+            rt.push_str(it.name());
+        }
+        rt
+    }
+    pub fn parse_list(s: &str) -> Vec<Self> {
+        s.split(',').filter_map(|it| Self::from_name(it)).collect()
+    }
+    //按本地优先级选择双方都支持的算法
+    pub fn negotiate(locals: &[Self], peers: &[Self]) -> Self {
+        for it in locals {
+            if *it != Compress::None && it.is_supported() && peers.contains(it) {
+                return *it;
+            }
+        }
+        Compress::None
+    }
+}
+
+pub fn compress(alg: Compress, data: &[u8]) -> io::Result<Vec<u8>> {
+    match alg {
+        Compress::None => Ok(data.to_vec()),
+        #[cfg(feature = "cmp-deflate")]
+        Compress::Deflate => {
+            use std::io::Write;
+            let mut enc =
+                flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            enc.write_all(data)?;
+            enc.finish()
+        }
+        #[cfg(feature = "cmp-zstd")]
+        Compress::Zstd => zstd::stream::encode_all(data, 3),
+        #[cfg(feature = "cmp-lz4")]
+        Compress::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        #[allow(unreachable_patterns)]
+        _ => Err(ruisutil::ioerr(
+            format!("compress {} not enabled", alg.name()),
+            None,
+        )),
+    }
+}
+
+pub fn decompress(alg: Compress, data: &[u8], max: u64) -> io::Result<Vec<u8>> {
+    let rt = match alg {
+        Compress::None => data.to_vec(),
+        #[cfg(feature = "cmp-deflate")]
+        Compress::Deflate => {
+            let mut rt = Vec::new();
+            flate2::read::DeflateDecoder::new(data)
+                .take(max + 1)
+                .read_to_end(&mut rt)?;
+            rt
+        }
+        #[cfg(feature = "cmp-zstd")]
+        Compress::Zstd => {
+            let mut rt = Vec::new();
+            zstd::stream::read::Decoder::new(data)?
+                .take(max + 1)
+                .read_to_end(&mut rt)?;
+            rt
+        }
+        #[cfg(feature = "cmp-lz4")]
+        Compress::Lz4 => {
+            if data.len() < 4 {
+                return Err(ruisutil::ioerr("lz4 data err", None));
+            }
+            let ln = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            //lz4 压缩比不超过 255, 防止按声明的长度超大分配
+            if ln as u64 > max || ln as u64 > data.len() as u64 * 255 {
+                return Err(ruisutil::ioerr("decompress out limit!!", None));
+            }
+            match lz4_flex::decompress_size_prepended(data) {
+                Ok(v) => v,
+                Err(e) => return Err(ruisutil::ioerr(format!("lz4 err:{}", e), None)),
+            }
+        }
+        #[allow(unreachable_patterns)]
+        _ => {
+            return Err(ruisutil::ioerr(
+                format!("compress {} not enabled", alg.name()),
+                None,
+            ))
+        }
+    };
+    if rt.len() as u64 > max {
+        return Err(ruisutil::ioerr("decompress out limit!!", None));
+    }
+    Ok(rt)
+}
+
+//小于 min 或压缩后变大时返回 None
+pub fn compress_min(alg: Compress, min: usize, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    if alg == Compress::None || data.len() < min || data.len() <= 0 {
+        return Ok(None);
+    }
+    let rt = compress(alg, data)?;
+    if rt.len() >= data.len() {
+        return Ok(None);
+    }
+    Ok(Some(rt))
+}
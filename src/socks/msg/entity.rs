@@ -1,7 +1,61 @@
+use std::io;
+
 use ruisutil::bytes;
 
+use super::compress::Compress;
+
+pub const MSG_VER: u16 = 1;
+//大body分片: FRAG 后续还有分片, FRAG_END 最后一片
+pub const MSG_VER_FRAG: u16 = 2;
+pub const MSG_VER_FRAG_END: u16 = 3;
+//分片 body 前缀: 消息id(4) + 分片序号(4)
+pub const FRAG_HEAD_LEN: usize = 8;
+
+//version 字段: 低8位为版本, 高8位为flags
+pub fn ver_split(v: u16) -> (u16, u8) {
+    (v & 0xff, (v >> 8) as u8)
+}
+pub fn ver_join(ver: u16, flags: u8) -> u16 {
+    (ver & 0xff) | ((flags as u16) << 8)
+}
+//flags: 帧尾(结束符前)带4字节 CRC32C
+pub const FLAG_CRC: u8 = 0x10;
+//flags: heads 前部为追踪扩展区(见 Messages::set_trace)
+pub const FLAG_TRACE: u8 = 0x20;
+
+//UDP packet 版本: 2 为包尾带4字节 CRC32C, 3 为 AEAD 加密包(见 udp::seal)
+pub const PACKET_VER: u8 = 1;
+pub const PACKET_VER_CRC: u8 = 2;
+pub const PACKET_VER_SEAL: u8 = 3;
+//版本高4位为flags: token 后带会话 id(8)
+pub const PACKET_FLAG_SESS: u8 = 0x10;
+pub const PACKET_VER_MASK: u8 = 0x0f;
+
+#[derive(Clone)]
+pub struct MsgOpts {
+    pub cmp: Compress,
+    pub cmp_min: usize,
+    pub crc: bool,
+}
+impl Default for MsgOpts {
+    fn default() -> Self {
+        Self {
+            cmp: Compress::None,
+            cmp_min: 1024,
+            crc: false,
+        }
+    }
+}
+
 //----------------------------------bean
-#[repr(C, packed)]
+/*
+  消息头, 16字节, 小端序:
+  0  version  u16 (低8位版本, 高8位flags)
+  2  control  i32
+  6  len_cmd  u16
+  8  len_head u32
+  12 len_body u32
+*/
 pub struct MsgInfo {
     pub version: u16,
     pub control: i32,
@@ -10,6 +64,7 @@ pub struct MsgInfo {
     pub len_body: u32,
 }
 impl MsgInfo {
+    pub const SIZE: usize = 16;
     pub fn new() -> Self {
         Self {
             version: 0,
@@ -19,6 +74,30 @@ impl MsgInfo {
             len_body: 0,
         }
     }
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut rt = [0u8; Self::SIZE];
+        rt[0..2].copy_from_slice(&self.version.to_le_bytes());
+        rt[2..6].copy_from_slice(&self.control.to_le_bytes());
+        rt[6..8].copy_from_slice(&self.len_cmd.to_le_bytes());
+        rt[8..12].copy_from_slice(&self.len_head.to_le_bytes());
+        rt[12..16].copy_from_slice(&self.len_body.to_le_bytes());
+        rt
+    }
+    pub fn decode(bts: &[u8]) -> io::Result<Self> {
+        if bts.len() < Self::SIZE {
+            return Err(ruisutil::ioerr(
+                "MsgInfo len err",
+                Some(io::ErrorKind::UnexpectedEof),
+            ));
+        }
+        Ok(Self {
+            version: u16::from_le_bytes([bts[0], bts[1]]),
+            control: i32::from_le_bytes([bts[2], bts[3], bts[4], bts[5]]),
+            len_cmd: u16::from_le_bytes([bts[6], bts[7]]),
+            len_head: u32::from_le_bytes([bts[8], bts[9], bts[10], bts[11]]),
+            len_body: u32::from_le_bytes([bts[12], bts[13], bts[14], bts[15]]),
+        })
+    }
 }
 
 
@@ -27,5 +106,7 @@ impl MsgInfo {
 pub struct UdpPackage {
     pub ctrl: u16,
     pub token: Option<String>,
+    //发送方已建立会话时的会话 id
+    pub sess: Option<u64>,
     pub data: bytes::Bytes,
 }
\ No newline at end of file
@@ -1,10 +1,15 @@
 
 mod msg;
 pub mod entity;
+pub mod compress;
+pub mod checksum;
 pub mod tcps;
 pub mod udps;
 
-pub use msg::{Message,Messages,Messageu,Messageus};
+pub use msg::{Message,Messages,Messageu,Messageus,MsgBody};
+pub use compress::Compress;
+pub use checksum::ChecksumError;
+pub use entity::MsgOpts;
 
 
 // pub const MaxOther: u64 = 1024 * 1024 * 20; //20M
@@ -1,14 +1,35 @@
-use std::sync::Arc;
+use std::{io, sync::Arc};
 
 use ruisutil::bytes;
 
-#[derive(Clone)]
+use crate::trace::TraceContext;
+
+//构造时其余字段用 ..Default::default() 补齐
+#[derive(Clone, Default)]
 pub struct Messages {
     pub control: i32,
     pub cmds: Option<String>,
     pub heads: Option<bytes::Bytes>,
     pub bodys: Option<bytes::Bytes>,
     pub bodybuf: Option<Arc<bytes::ByteBoxBuf>>,
+    //heads 前部带追踪上下文, 发送时置 FLAG_TRACE, 由 set_trace 设置
+    pub(crate) trace: bool,
+}
+impl Messages {
+    pub fn is_trace(&self) -> bool {
+        self.trace
+    }
+    //追踪上下文写在 heads 前部, 对端用 Message::take_trace 取出.
+    //Messager 不会自动注入或取出, 需要追踪的消息手动调用
+    pub fn set_trace(&mut self, tc: &TraceContext) -> io::Result<()> {
+        if self.trace {
+            return Err(ruisutil::ioerr("msg trace is set", None));
+        }
+        let hds = tc.heads_inject(self.heads.as_ref().map(|v| &v[..]))?;
+        self.heads = Some(bytes::Bytes::from(hds));
+        self.trace = true;
+        Ok(())
+    }
 }
 #[derive(Clone)]
 pub struct Message {
@@ -17,6 +38,8 @@ pub struct Message {
     pub cmds: String,
     pub heads: Option<bytes::Bytes>,
     pub bodys: MsgBody,
+    //帧头带 FLAG_TRACE, heads 前部为追踪上下文
+    pub(crate) trace: bool,
 }
 
 #[derive(Clone)]
@@ -42,11 +65,31 @@ impl Message {
             cmds: String::new(),
             heads: None,
             bodys: MsgBody::None,
+            trace: false,
         }
     }
+    pub fn is_trace(&self) -> bool {
+        self.trace
+    }
     pub fn own_bodys(&mut self) -> MsgBody {
         std::mem::replace(&mut self.bodys, MsgBody::None)
     }
+    //取出 set_trace 写入的追踪上下文, heads 还原为原内容; 未置 FLAG_TRACE 时返回 None
+    pub fn take_trace(&mut self) -> io::Result<Option<TraceContext>> {
+        if !self.trace {
+            return Ok(None);
+        }
+        let (tc, hds) = match &self.heads {
+            None => return Err(ruisutil::ioerr("heads trace err", None)),
+            Some(v) => match TraceContext::heads_extract(&v[..])? {
+                (tc, pos) if pos < v.len() => (tc, Some(bytes::Bytes::from(v[pos..].to_vec()))),
+                (tc, _) => (tc, None),
+            },
+        };
+        self.heads = hds;
+        self.trace = false;
+        Ok(Some(tc))
+    }
     pub fn body_box(&self) -> Option<bytes::Bytes> {
         match &self.bodys {
             MsgBody::None => None,
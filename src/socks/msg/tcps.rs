@@ -1,21 +1,40 @@
-use std::{io, mem};
+use std::io;
 
-use ruisutil::asyncs::net::TcpStream;
+use ruisutil::asyncs::{AsyncReadExt, AsyncWriteExt};
 use ruisutil::bytes::{self, ByteSteamBuf};
 
-use crate::socks::msg::entity::MsgInfo;
+use crate::socks::msg::entity::{self, MsgInfo};
 
-use super::{Message, Messages};
+use super::{
+    checksum,
+    compress::{self, Compress},
+    entity::MsgOpts,
+    Message, Messages,
+};
 
-pub async fn parse_msg(ctxs: &ruisutil::asyncs::Context, conn: &mut TcpStream) -> io::Result<Message> {
-    let bts = ruisutil::read_all_async(ctxs, conn, 1).await?;
+//resync 时候选帧 cmd+heads+body 的上限
+pub const RESYNC_MAX_FRAME: u64 = 1024 * 1024 * 16; //16M
+
+pub async fn parse_msg<R: AsyncReadExt + Unpin>(
+    ctxs: &ruisutil::asyncs::Context,
+    conn: &mut R,
+) -> io::Result<Message> {
+    parse_msg_max(ctxs, conn, super::MAX_BODYS).await
+}
+//max: body(解压后)的上限
+pub async fn parse_msg_max<R: AsyncReadExt + Unpin>(
+    ctxs: &ruisutil::asyncs::Context,
+    conn: &mut R,
+    max: u64,
+) -> io::Result<Message> {
+    let bts = crate::iovec::read_full(ctxs, conn, 1).await?;
     if bts.len() < 1 || bts[0] != 0x8du8 {
         return Err(ruisutil::ioerr(
             format!("first byte err:{:?}", &bts[..]),
             None,
         ));
     }
-    let bts = ruisutil::read_all_async(ctxs, conn, 1).await?;
+    let bts = crate::iovec::read_full(ctxs, conn, 1).await?;
     if bts.len() < 1 || bts[0] != 0x8fu8 {
         return Err(ruisutil::ioerr(
             format!("second byte err:{:?}", &bts[..]),
@@ -23,23 +42,27 @@ pub async fn parse_msg(ctxs: &ruisutil::asyncs::Context, conn: &mut TcpStream) -
         ));
     }
 
-    let mut info = MsgInfo::new();
-    let infoln = mem::size_of::<MsgInfo>();
-    let bts = ruisutil::read_all_async(ctxs, conn, infoln).await?;
-    ruisutil::byte2struct(&mut info, &bts[..])?;
+    let bts = crate::iovec::read_full(ctxs, conn, MsgInfo::SIZE).await?;
+    let info = MsgInfo::decode(&bts[..])?;
+    let mut crc = checksum::crc32c(&bts[..]);
     if info.len_head as u64 > super::MAX_HEADS {
         return Err(ruisutil::ioerr("bytes2 out limit!!", None));
     }
-    if info.len_body as u64 > super::MAX_BODYS {
+    if info.len_body as u64 > max {
         return Err(ruisutil::ioerr("bytes3 out limit!!", None));
     }
 
+    let (ver, flags) = entity::ver_split(info.version);
+    let cmp = Compress::from_flags(flags)?;
+    let crcs = flags & entity::FLAG_CRC != 0;
     let mut rt = Message::new();
-    rt.version = info.version;
+    rt.version = ver;
     rt.control = info.control;
+    rt.trace = flags & entity::FLAG_TRACE != 0;
     let lnsz = info.len_cmd as usize;
     if lnsz > 0 {
-        let bts = ruisutil::read_all_async(&ctxs, conn, lnsz).await?;
+        let bts = crate::iovec::read_full(&ctxs, conn, lnsz).await?;
+        crc = checksum::crc32c_append(crc, &bts[..]);
         rt.cmds = match std::str::from_utf8(&bts[..]) {
             Err(e) => return Err(ruisutil::ioerr("cmd err", None)),
             Ok(v) => String::from(v),
@@ -47,95 +70,294 @@ pub async fn parse_msg(ctxs: &ruisutil::asyncs::Context, conn: &mut TcpStream) -
     }
     let lnsz = info.len_head as usize;
     if lnsz > 0 {
-        let bts = ruisutil::read_all_async(&ctxs, conn, lnsz as usize).await?;
+        let bts = crate::iovec::read_full(&ctxs, conn, lnsz as usize).await?;
+        crc = checksum::crc32c_append(crc, &bts[..]);
         rt.heads = Some(bytes::Bytes::from(bts));
     }
     let lnsz = info.len_body as usize;
+    let mut bodys = None;
     if lnsz > 0 {
-        let bts = ruisutil::read_all_async(&ctxs, conn, lnsz as usize).await?;
-        rt.bodys = super::msg::MsgBody::Bytes(bytes::Bytes::from(bts));
+        let bts = crate::iovec::read_full(&ctxs, conn, lnsz as usize).await?;
+        crc = checksum::crc32c_append(crc, &bts[..]);
+        bodys = Some(bts);
+    }
+    if crcs {
+        let bts = crate::iovec::read_full(ctxs, conn, 4).await?;
+        checksum::check(crc_get(&bts[..]), crc)?;
     }
-    let bts = ruisutil::read_all_async(ctxs, conn, 2).await?;
+    let bts = crate::iovec::read_full(ctxs, conn, 2).await?;
     if bts.len() < 2 || bts[0] != 0x8eu8 || bts[1] != 0x8fu8 {
         return Err(ruisutil::ioerr(
             format!("end byte err:{:?}", &bts[..]),
             None,
         ));
     }
+    // 校验通过后再解压
+    if let Some(bts) = bodys {
+        rt.bodys = if cmp != Compress::None {
+            let bts = compress::decompress(cmp, &bts[..], max)?;
+            super::msg::MsgBody::Bytes(bytes::Bytes::from(bts))
+        } else {
+            super::msg::MsgBody::Bytes(bytes::Bytes::from(bts))
+        };
+    }
 
     Ok(rt)
 }
 pub async fn parse_steam_msg(ctxs: &ruisutil::asyncs::Context, buf: &ByteSteamBuf) -> io::Result<Message> {
+    parse_steam_msg_max(ctxs, buf, super::MAX_BODYS, false).await
+}
+//resync: 读取帧内容前先按 resync 的规则检查帧头, 长度异常的帧立即返回 InvalidData
+pub async fn parse_steam_msg_max(
+    ctxs: &ruisutil::asyncs::Context,
+    buf: &ByteSteamBuf,
+    max: u64,
+    resync: bool,
+) -> io::Result<Message> {
     let bts = buf.pull_size(Some(ctxs), 1).await?.to_bytes();
     if bts.len() < 1 || bts[0] != 0x8du8 {
         return Err(ruisutil::ioerr(
             format!("first byte err:{:?}", &bts[..]),
-            None,
+            Some(io::ErrorKind::InvalidData),
         ));
     }
     let bts = buf.pull_size(Some(ctxs), 1).await?.to_bytes();
     if bts.len() < 1 || bts[0] != 0x8fu8 {
         return Err(ruisutil::ioerr(
             format!("second byte err:{:?}", &bts[..]),
-            None,
+            Some(io::ErrorKind::InvalidData),
         ));
     }
+    parse_steam_frame(ctxs, buf, max, resync).await
+}
 
-    let mut info = MsgInfo::new();
-    let infoln = mem::size_of::<MsgInfo>();
-    let bts = buf.pull_size(Some(ctxs), infoln).await?.to_bytes();
-    ruisutil::byte2struct(&mut info, &bts[..])?;
+//帧错误后向前扫描下一个 0x8d 0x8f 帧头并重新解析, dropped 累计丢弃的字节数
+//win 为已取出未消费的预读数据, 由调用方保存: 不为空时应继续调用本函数而不是 parse_steam_msg
+//候选帧头先在 win 中预读校验, 失败时从标记后一字节继续扫描, 不会吞掉真正的帧头
+pub async fn resync_steam_msg(
+    ctxs: &ruisutil::asyncs::Context,
+    buf: &ByteSteamBuf,
+    win: &mut Vec<u8>,
+    dropped: &mut u64,
+) -> io::Result<Message> {
+    resync_steam_msg_max(ctxs, buf, win, dropped, super::MAX_BODYS).await
+}
+pub async fn resync_steam_msg_max(
+    ctxs: &ruisutil::asyncs::Context,
+    buf: &ByteSteamBuf,
+    win: &mut Vec<u8>,
+    dropped: &mut u64,
+    max: u64,
+) -> io::Result<Message> {
+    let hdln = 2 + MsgInfo::SIZE;
+    loop {
+        let pos = match win.windows(2).position(|v| v == [0x8du8, 0x8fu8]) {
+            Some(v) => v,
+            None => {
+                //末尾的 0x8d 可能是下一个帧头的首字节
+                let keep = if win.last() == Some(&0x8du8) { 1 } else { 0 };
+                let n = win.len() - keep;
+                *dropped += n as u64;
+                win.drain(..n);
+                let bts = buf.pull_size(Some(ctxs), 1).await?.to_bytes();
+                win.extend_from_slice(&bts[..]);
+                continue;
+            }
+        };
+        *dropped += pos as u64;
+        win.drain(..pos);
+        if win_fill(ctxs, buf, win, hdln).await? {
+            let info = MsgInfo::decode(&win[2..hdln])?;
+            if check_info(&info).is_ok() {
+                let ln = frame_len(&info);
+                if win_fill(ctxs, buf, win, ln).await? {
+                    if let Ok(v) = parse_msg_max(ctxs, &mut &win[..ln], max).await {
+                        win.drain(..ln);
+                        return Ok(v);
+                    }
+                }
+            }
+        }
+        //假帧头: 丢弃 0x8d, 从下一字节继续
+        *dropped += 1;
+        win.drain(..1);
+    }
+}
+//预读到 win 至少 n 字节, 流已结束返回 false
+async fn win_fill(
+    ctxs: &ruisutil::asyncs::Context,
+    buf: &ByteSteamBuf,
+    win: &mut Vec<u8>,
+    n: usize,
+) -> io::Result<bool> {
+    if win.len() >= n {
+        return Ok(true);
+    }
+    match buf.pull_size(Some(ctxs), n - win.len()).await {
+        Ok(v) => {
+            win.extend_from_slice(&v.to_bytes()[..]);
+            Ok(true)
+        }
+        Err(e) => {
+            if ctxs.done() {
+                return Err(e);
+            }
+            Ok(false)
+        }
+    }
+}
+fn frame_len(info: &MsgInfo) -> usize {
+    let (_, flags) = entity::ver_split(info.version);
+    let crcln = if flags & entity::FLAG_CRC != 0 { 4 } else { 0 };
+    2 + MsgInfo::SIZE
+        + info.len_cmd as usize
+        + info.len_head as usize
+        + info.len_body as usize
+        + crcln
+        + 2
+}
+
+fn check_info(info: &MsgInfo) -> io::Result<()> {
+    let (ver, flags) = entity::ver_split(info.version);
+    if ver < entity::MSG_VER || ver > entity::MSG_VER_FRAG_END {
+        return Err(ruisutil::ioerr(
+            format!("version err:{}", ver),
+            Some(io::ErrorKind::InvalidData),
+        ));
+    }
+    if flags & !(compress::FLAG_CMP_MASK | entity::FLAG_CRC | entity::FLAG_TRACE) != 0 {
+        return Err(ruisutil::ioerr(
+            format!("flags err:{}", flags),
+            Some(io::ErrorKind::InvalidData),
+        ));
+    }
+    if let Err(e) = Compress::from_flags(flags) {
+        return Err(ruisutil::ioerr(e, Some(io::ErrorKind::InvalidData)));
+    }
+    //预读整帧, 长度按更严格的上限判断, 避免假帧头让扫描长时间等待
+    let ln = info.len_cmd as u64 + info.len_head as u64 + info.len_body as u64;
+    if info.len_head as u64 > super::MAX_HEADS || ln > RESYNC_MAX_FRAME {
+        return Err(ruisutil::ioerr(
+            format!("frame len err:{}", ln),
+            Some(io::ErrorKind::InvalidData),
+        ));
+    }
+    Ok(())
+}
+
+async fn parse_steam_frame(
+    ctxs: &ruisutil::asyncs::Context,
+    buf: &ByteSteamBuf,
+    max: u64,
+    resync: bool,
+) -> io::Result<Message> {
+    let bts = buf.pull_size(Some(ctxs), MsgInfo::SIZE).await?.to_bytes();
+    let info = MsgInfo::decode(&bts[..])?;
+    let mut crc = checksum::crc32c(&bts[..]);
+    if resync {
+        check_info(&info)?;
+    }
     if info.len_head as u64 > super::MAX_HEADS {
-        return Err(ruisutil::ioerr("bytes2 out limit!!", None));
+        return Err(ruisutil::ioerr(
+            "bytes2 out limit!!",
+            Some(io::ErrorKind::InvalidData),
+        ));
     }
-    if info.len_body as u64 > super::MAX_BODYS {
-        return Err(ruisutil::ioerr("bytes3 out limit!!", None));
+    if info.len_body as u64 > max {
+        return Err(ruisutil::ioerr(
+            "bytes3 out limit!!",
+            Some(io::ErrorKind::InvalidData),
+        ));
     }
 
+    let (ver, flags) = entity::ver_split(info.version);
+    let cmp = match Compress::from_flags(flags) {
+        Ok(v) => v,
+        Err(e) => return Err(ruisutil::ioerr(e, Some(io::ErrorKind::InvalidData))),
+    };
+    let crcs = flags & entity::FLAG_CRC != 0;
     let mut rt = Message::new();
-    rt.version = info.version;
+    rt.version = ver;
     rt.control = info.control;
+    rt.trace = flags & entity::FLAG_TRACE != 0;
     let lnsz = info.len_cmd as usize;
     if lnsz > 0 {
         let bts = buf.pull_size(Some(ctxs), lnsz).await?.to_bytes();
+        crc = checksum::crc32c_append(crc, &bts[..]);
         rt.cmds = match std::str::from_utf8(&bts[..]) {
-            Err(e) => return Err(ruisutil::ioerr("cmd err", None)),
+            Err(_) => {
+                return Err(ruisutil::ioerr(
+                    "cmd err",
+                    Some(io::ErrorKind::InvalidData),
+                ))
+            }
             Ok(v) => String::from(v),
         };
     }
     let lnsz = info.len_head as usize;
     if lnsz > 0 {
         let bts = buf.pull_size(Some(ctxs), lnsz).await?.to_bytes();
+        crc = checksum::crc32c_append(crc, &bts[..]);
         rt.heads = Some(bytes::Bytes::from(bts));
     }
     let lnsz = info.len_body as usize;
+    let mut bodys = None;
     if lnsz > 0 {
         let bts = buf.pull_size(Some(ctxs), lnsz).await?;
-        // rt.bodys = Some(bts.to_bytes());
-        rt.bodys = super::msg::MsgBody::BoxBuf(bts);
+        if crcs {
+            crc = checksum::crc32c_append(crc, &bts.to_bytes()[..]);
+        }
+        bodys = Some(bts);
+    }
+    if crcs {
+        let bts = buf.pull_size(Some(ctxs), 4).await?.to_bytes();
+        checksum::check(crc_get(&bts[..]), crc)?;
     }
     let bts = buf.pull_size(Some(ctxs), 2).await?.to_bytes();
     if bts.len() < 2 || bts[0] != 0x8eu8 || bts[1] != 0x8fu8 {
         return Err(ruisutil::ioerr(
             format!("end byte err:{:?}", &bts[..]),
-            None,
+            Some(io::ErrorKind::InvalidData),
         ));
     }
+    // 校验通过后再解压
+    if let Some(bts) = bodys {
+        // rt.bodys = Some(bts.to_bytes());
+        rt.bodys = if cmp != Compress::None {
+            let bts = match compress::decompress(cmp, &bts.to_bytes()[..], max) {
+                Ok(v) => v,
+                Err(e) => return Err(ruisutil::ioerr(e, Some(io::ErrorKind::InvalidData))),
+            };
+            super::msg::MsgBody::Bytes(bytes::Bytes::from(bts))
+        } else {
+            super::msg::MsgBody::BoxBuf(bts)
+        };
+    }
 
     Ok(rt)
 }
 
-pub async fn send_msg(
+pub async fn send_msg<W: AsyncWriteExt + Unpin>(
+    ctxs: &ruisutil::asyncs::Context,
+    conn: &mut W,
+    ctrl: i32,
+    cmds: Option<String>,
+    hds: &Option<bytes::Bytes>,
+    bds: Option<&[u8]>,
+) -> io::Result<()> {
+    send_msg_ver(ctxs, conn, entity::MSG_VER, ctrl, cmds, hds, bds).await
+}
+pub async fn send_msg_ver<W: AsyncWriteExt + Unpin>(
     ctxs: &ruisutil::asyncs::Context,
-    conn: &mut TcpStream,
+    conn: &mut W,
+    ver: u16,
     ctrl: i32,
     cmds: Option<String>,
     hds: &Option<bytes::Bytes>,
     bds: Option<&[u8]>,
 ) -> io::Result<()> {
     let mut info = MsgInfo::new();
-    // let infoln = mem::size_of::<MsgInfo>();
-    info.version = 1;
+    info.version = ver;
     info.control = ctrl;
     if let Some(v) = &cmds {
         info.len_cmd = v.len() as u16;
@@ -146,33 +368,75 @@ pub async fn send_msg(
     if let Some(v) = &bds {
         info.len_body = v.len() as u32;
     }
-    ruisutil::write_all_async(ctxs, conn, &[0x8du8, 0x8fu8]).await?;
-    let bts = ruisutil::struct2byte(&info);
-    ruisutil::write_all_async(ctxs, conn, bts).await?;
+    let bts = info.encode();
+    let mut crc = checksum::crc32c(&bts[..]);
+    let mut bufs: Vec<&[u8]> = Vec::with_capacity(7);
+    bufs.push(&[0x8du8, 0x8fu8]);
+    bufs.push(&bts[..]);
     if let Some(v) = &cmds {
-        ruisutil::write_all_async(ctxs, conn, v.as_bytes()).await?;
+        crc = checksum::crc32c_append(crc, v.as_bytes());
+        bufs.push(v.as_bytes());
     }
     if let Some(v) = hds {
-        ruisutil::write_all_async(ctxs, conn, &v[..]).await?;
+        crc = checksum::crc32c_append(crc, &v[..]);
+        bufs.push(&v[..]);
     }
     if let Some(v) = bds {
-        ruisutil::write_all_async(ctxs, conn, &v[..]).await?;
+        crc = checksum::crc32c_append(crc, &v[..]);
+        bufs.push(&v[..]);
     }
-    ruisutil::write_all_async(ctxs, conn, &[0x8eu8, 0x8fu8]).await?;
-    Ok(())
+    let crcbts = crc.to_le_bytes();
+    let (_, flags) = entity::ver_split(ver);
+    if flags & entity::FLAG_CRC != 0 {
+        bufs.push(&crcbts[..]);
+    }
+    bufs.push(&[0x8eu8, 0x8fu8]);
+    crate::iovec::write_all_vectored(ctxs, conn, &bufs[..]).await
+}
+pub async fn send_msg_opt<W: AsyncWriteExt + Unpin>(
+    ctxs: &ruisutil::asyncs::Context,
+    conn: &mut W,
+    ver: u16,
+    opts: &MsgOpts,
+    ctrl: i32,
+    cmds: Option<String>,
+    hds: &Option<bytes::Bytes>,
+    bds: Option<&[u8]>,
+) -> io::Result<()> {
+    let (ver, mut flags) = entity::ver_split(ver);
+    if opts.crc {
+        flags |= entity::FLAG_CRC;
+    }
+    if let Some(v) = bds {
+        if let Some(cbts) = compress::compress_min(opts.cmp, opts.cmp_min, v)? {
+            let ver = entity::ver_join(ver, flags | opts.cmp.flags());
+            return send_msg_ver(ctxs, conn, ver, ctrl, cmds, hds, Some(&cbts[..])).await;
+        }
+    }
+    let ver = entity::ver_join(ver, flags);
+    send_msg_ver(ctxs, conn, ver, ctrl, cmds, hds, bds).await
 }
 
-pub async fn send_msgs(
+pub async fn send_msgs<W: AsyncWriteExt + Unpin>(
     ctxs: &ruisutil::asyncs::Context,
-    conn: &mut TcpStream,
+    conn: &mut W,
+    msg: Messages,
+) -> io::Result<()> {
+    send_msgs_ver(ctxs, conn, msgs_ver(&msg), msg).await
+}
+async fn send_msgs_ver<W: AsyncWriteExt + Unpin>(
+    ctxs: &ruisutil::asyncs::Context,
+    conn: &mut W,
+    ver: u16,
     msg: Messages,
 ) -> io::Result<()> {
     if let Some(buf) = &msg.bodybuf {
-        send_msg_buf(ctxs, conn, msg.control, msg.cmds, &msg.heads, Some(buf)).await
+        send_msg_buf_ver(ctxs, conn, ver, msg.control, msg.cmds, &msg.heads, Some(buf)).await
     } else if let Some(bds) = &msg.bodys {
-        send_msg(
+        send_msg_ver(
             ctxs,
             conn,
+            ver,
             msg.control,
             msg.cmds,
             &msg.heads,
@@ -180,20 +444,72 @@ pub async fn send_msgs(
         )
         .await
     } else {
-        send_msg(ctxs, conn, msg.control, msg.cmds, &msg.heads, None).await
+        send_msg_ver(ctxs, conn, ver, msg.control, msg.cmds, &msg.heads, None).await
+    }
+}
+fn msgs_ver(msg: &Messages) -> u16 {
+    if msg.trace {
+        entity::ver_join(entity::MSG_VER, entity::FLAG_TRACE)
+    } else {
+        entity::MSG_VER
+    }
+}
+pub async fn send_msgs_opt<W: AsyncWriteExt + Unpin>(
+    ctxs: &ruisutil::asyncs::Context,
+    conn: &mut W,
+    msg: Messages,
+    opts: &MsgOpts,
+) -> io::Result<()> {
+    //不压缩时 bodybuf 按段直接写出, 不合并
+    if opts.cmp == Compress::None {
+        let (ver, mut flags) = entity::ver_split(msgs_ver(&msg));
+        if opts.crc {
+            flags |= entity::FLAG_CRC;
+        }
+        return send_msgs_ver(ctxs, conn, entity::ver_join(ver, flags), msg).await;
     }
+    let bds = if let Some(buf) = &msg.bodybuf {
+        Some(buf.to_bytes())
+    } else {
+        msg.bodys.clone()
+    };
+    send_msg_opt(
+        ctxs,
+        conn,
+        msgs_ver(&msg),
+        opts,
+        msg.control,
+        msg.cmds,
+        &msg.heads,
+        match &bds {
+            Some(v) => Some(&v[..]),
+            None => None,
+        },
+    )
+    .await
+}
+pub async fn send_msg_buf<W: AsyncWriteExt + Unpin>(
+    ctxs: &ruisutil::asyncs::Context,
+    conn: &mut W,
+    ctrl: i32,
+    cmds: Option<String>,
+    hds: &Option<bytes::Bytes>,
+    bds: Option<&bytes::ByteBoxBuf>,
+) -> io::Result<()> {
+    send_msg_buf_ver(ctxs, conn, entity::MSG_VER, ctrl, cmds, hds, bds).await
 }
-pub async fn send_msg_buf(
+//bodybuf 的每一段作为单独的 slice 与帧头一起写出; ver 带 FLAG_CRC 时追加校验
+async fn send_msg_buf_ver<W: AsyncWriteExt + Unpin>(
     ctxs: &ruisutil::asyncs::Context,
-    conn: &mut TcpStream,
+    conn: &mut W,
+    ver: u16,
     ctrl: i32,
     cmds: Option<String>,
     hds: &Option<bytes::Bytes>,
     bds: Option<&bytes::ByteBoxBuf>,
 ) -> io::Result<()> {
     let mut info = MsgInfo::new();
-    // let infoln = mem::size_of::<MsgInfo>();
-    info.version = 1;
+    info.version = ver;
     info.control = ctrl;
     if let Some(v) = &cmds {
         info.len_cmd = v.len() as u16;
@@ -204,18 +520,34 @@ pub async fn send_msg_buf(
     if let Some(v) = bds {
         info.len_body = v.len() as u32;
     }
-    ruisutil::write_all_async(ctxs, conn, &[0x8du8, 0x8fu8]).await?;
-    let bts = ruisutil::struct2byte(&info);
-    ruisutil::write_all_async(ctxs, conn, bts).await?;
+    let bts = info.encode();
+    let mut crc = checksum::crc32c(&bts[..]);
+    let mut bufs: Vec<&[u8]> = Vec::with_capacity(7);
+    bufs.push(&[0x8du8, 0x8fu8]);
+    bufs.push(&bts[..]);
     if let Some(v) = &cmds {
-        ruisutil::write_all_async(ctxs, conn, v.as_bytes()).await?;
+        crc = checksum::crc32c_append(crc, v.as_bytes());
+        bufs.push(v.as_bytes());
     }
     if let Some(v) = hds {
-        ruisutil::write_all_async(ctxs, conn, &v[..]).await?;
+        crc = checksum::crc32c_append(crc, &v[..]);
+        bufs.push(&v[..]);
     }
     if let Some(v) = bds {
-        ruisutil::write_allbuf_async(ctxs, conn, &v).await?;
+        for it in v.iter() {
+            crc = checksum::crc32c_append(crc, &it[..]);
+            bufs.push(&it[..]);
+        }
     }
-    ruisutil::write_all_async(ctxs, conn, &[0x8eu8, 0x8fu8]).await?;
-    Ok(())
+    let crcbts = crc.to_le_bytes();
+    let (_, flags) = entity::ver_split(ver);
+    if flags & entity::FLAG_CRC != 0 {
+        bufs.push(&crcbts[..]);
+    }
+    bufs.push(&[0x8eu8, 0x8fu8]);
+    crate::iovec::write_all_vectored(ctxs, conn, &bufs[..]).await
+}
+
+fn crc_get(bts: &[u8]) -> u32 {
+    u32::from_le_bytes([bts[0], bts[1], bts[2], bts[3]])
 }
@@ -1,26 +1,51 @@
-use std::{io, mem};
+use std::io;
 
 use ruisutil::bytes::{self, BytesCut};
 
 use super::{
+    checksum,
+    compress::{self, Compress},
     entity::{self, MsgInfo},
     Messageu, Messageus,
 };
 
 pub fn packet_parse(mut buf: bytes::Bytes) -> io::Result<entity::UdpPackage> {
-    let bts = buf.cuts(3)?;
-    if bts[0] != 0x48 || bts[1] != 0x42 {
+    if buf.len() < 3 {
+        return Err(ruisutil::ioerr("packet len err", None));
+    }
+    if buf[0] != 0x48 || buf[1] != 0x42 {
         return Err(ruisutil::ioerr(
-            format!("packet start err:[{},{}]", bts[0], bts[1]),
+            format!("packet start err:[{},{}]", buf[0], buf[1]),
             None,
         ));
     }
-    if bts[2] != 1 {
+    let flags = buf[2] & !entity::PACKET_VER_MASK;
+    if flags & !entity::PACKET_FLAG_SESS != 0 {
         return Err(ruisutil::ioerr(
-            format!("packet version err:[{}]", bts[2]),
+            format!("packet flags err:[{}]", flags),
             None,
         ));
     }
+    match buf[2] & entity::PACKET_VER_MASK {
+        entity::PACKET_VER => {}
+        entity::PACKET_VER_CRC => {
+            if buf.len() < 7 {
+                return Err(ruisutil::ioerr("packet len err", None));
+            }
+            let ln = buf.len() - 4;
+            let crc = checksum::crc32c(&buf[..ln]);
+            let expect = u32::from_le_bytes([buf[ln], buf[ln + 1], buf[ln + 2], buf[ln + 3]]);
+            checksum::check(expect, crc)?;
+            buf = buf.cuts(ln)?;
+        }
+        v => {
+            return Err(ruisutil::ioerr(
+                format!("packet version err:[{}]", v),
+                None,
+            ))
+        }
+    }
+    buf.cuts(3)?;
     let bts = buf.cuts(2)?;
     let ctrl = ruisutil::byte_2i(&bts[..]) as u16;
     let bts = buf.cuts(2)?;
@@ -34,16 +59,39 @@ pub fn packet_parse(mut buf: bytes::Bytes) -> io::Result<entity::UdpPackage> {
         };
         tk = Some(keys.to_string());
     }
+    let mut sess = None;
+    if flags & entity::PACKET_FLAG_SESS != 0 {
+        let bts = buf.cuts(8)?;
+        sess = Some(ruisutil::byte_2i(&bts[..]) as u64);
+    }
 
     Ok(entity::UdpPackage {
         ctrl: ctrl,
         token: tk,
+        sess: sess,
         data: buf,
     })
 }
 pub fn packet_fmts(ctrl: u16, tks: &Option<String>) -> io::Result<bytes::ByteBoxBuf> {
+    packet_fmts_ver(entity::PACKET_VER, ctrl, tks)
+}
+pub fn packet_fmts_ver(ver: u8, ctrl: u16, tks: &Option<String>) -> io::Result<bytes::ByteBoxBuf> {
+    packet_fmts_sess(ver, ctrl, tks, None)
+}
+//sess 不为空时置 PACKET_FLAG_SESS 并在 token 后写入会话 id
+pub fn packet_fmts_sess(
+    ver: u8,
+    ctrl: u16,
+    tks: &Option<String>,
+    sess: Option<u64>,
+) -> io::Result<bytes::ByteBoxBuf> {
     let mut buf = bytes::ByteBoxBuf::new();
-    buf.push(vec![0x48, 0x42, 1]);
+    let flags = if sess.is_some() {
+        entity::PACKET_FLAG_SESS
+    } else {
+        0
+    };
+    buf.push(vec![0x48, 0x42, ver | flags]);
     buf.push(ruisutil::i2_byte(ctrl as i64, 2));
     if let Some(v) = tks {
         let bts = v.as_bytes().to_vec();
@@ -52,10 +100,26 @@ pub fn packet_fmts(ctrl: u16, tks: &Option<String>) -> io::Result<bytes::ByteBox
     } else {
         buf.push(vec![0, 0]);
     }
+    if let Some(v) = sess {
+        buf.push(ruisutil::i2_byte(v as i64, 8));
+    }
     Ok(buf)
 }
 
-pub fn msg_parse(mut buf: bytes::Bytes) -> io::Result<Messageu> {
+//PACKET_VER_CRC: 数据填充完后追加校验
+pub fn packet_crc(buf: &mut bytes::ByteBoxBuf) {
+    let crc = checksum::crc32c(&buf.to_bytes()[..]);
+    buf.push(crc.to_le_bytes().to_vec());
+}
+
+//udp 消息 body(解压后)的默认上限, 与 UdpConfig::frag_max_bytes 默认值一致
+pub const MAX_BODYS_UDP: u64 = 1024 * 1024 * 4; //4M
+
+pub fn msg_parse(buf: bytes::Bytes) -> io::Result<Messageu> {
+    msg_parse_max(buf, MAX_BODYS_UDP)
+}
+//max: body(解压后)的上限
+pub fn msg_parse_max(mut buf: bytes::Bytes, max: u64) -> io::Result<Messageu> {
     let bts = buf.cuts(2)?;
     if bts[0] != 0x8e || bts[1] != 0x8f {
         return Err(ruisutil::ioerr(
@@ -63,19 +127,19 @@ pub fn msg_parse(mut buf: bytes::Bytes) -> io::Result<Messageu> {
             None,
         ));
     }
-    let mut info = MsgInfo::new();
-    let infoln = mem::size_of::<MsgInfo>();
-    let bts = buf.cuts(infoln)?;
-    ruisutil::byte2struct(&mut info, &bts[..])?;
+    let bts = buf.cuts(MsgInfo::SIZE)?;
+    let info = MsgInfo::decode(&bts[..])?;
     if info.len_head as u64 > super::MAX_HEADS {
         return Err(ruisutil::ioerr("bytes2 out limit!!", None));
     }
-    if info.len_body as u64 > super::MAX_BODYS {
+    if info.len_body as u64 > max {
         return Err(ruisutil::ioerr("bytes3 out limit!!", None));
     }
 
+    let (ver, flags) = entity::ver_split(info.version);
+    let cmp = Compress::from_flags(flags)?;
     let mut rt = Messageu::new();
-    rt.version = info.version;
+    rt.version = ver;
     rt.control = info.control;
     let lnsz = info.len_cmd as usize;
     if lnsz > 0 {
@@ -93,15 +157,27 @@ pub fn msg_parse(mut buf: bytes::Bytes) -> io::Result<Messageu> {
     let lnsz = info.len_body as usize;
     if lnsz > 0 {
         let bts = buf.cuts(lnsz)?;
-        rt.bodys = Some(bts);
+        rt.bodys = if cmp != Compress::None {
+            let bts = compress::decompress(cmp, &bts[..], max)?;
+            Some(bytes::Bytes::from(bts))
+        } else {
+            Some(bts)
+        };
     }
     Ok(rt)
 }
 
 pub fn msg_fmts(data: Messageus) -> io::Result<bytes::ByteBoxBuf> {
+    msg_fmts_cmp(data, Compress::None, 0)
+}
+pub fn msg_fmts_cmp(
+    mut data: Messageus,
+    cmp: Compress,
+    cmp_min: usize,
+) -> io::Result<bytes::ByteBoxBuf> {
     let mut buf = bytes::ByteBoxBuf::new();
     let mut info = MsgInfo::new();
-    info.version = 1;
+    info.version = entity::MSG_VER;
     info.control = data.control;
     if let Some(v) = &data.cmds {
         info.len_cmd = v.len() as u16;
@@ -109,9 +185,22 @@ pub fn msg_fmts(data: Messageus) -> io::Result<bytes::ByteBoxBuf> {
     if let Some(v) = &data.heads {
         info.len_head = v.len() as u32;
     }
+    if cmp != Compress::None {
+        let bds = match &data.bodys {
+            super::msg::MsgBody::None => None,
+            super::msg::MsgBody::Bytes(v) => compress::compress_min(cmp, cmp_min, &v[..])?,
+            super::msg::MsgBody::BoxBuf(v) => {
+                compress::compress_min(cmp, cmp_min, &v.to_bytes()[..])?
+            }
+        };
+        if let Some(v) = bds {
+            info.version = entity::ver_join(info.version, cmp.flags());
+            data.bodys = super::msg::MsgBody::Bytes(bytes::Bytes::from(v));
+        }
+    }
     info.len_body = data.bodys.len() as u32;
     buf.push(vec![0x8e, 0x8f]);
-    let bts = ruisutil::struct2byte(&info).to_vec();
+    let bts = info.encode().to_vec();
     buf.push(bts);
     if let Some(v) = &data.cmds {
         buf.push(v.as_bytes().to_vec());
@@ -1,8 +1,10 @@
 mod msger;
+pub(crate) mod sendq;
 // mod msgbuf;
 
 use ruisutil::asyncs::Sender;
 pub use msger::Messager;
+pub use sendq::{MsgPriority, QueuePolicy, SendConfig};
 // pub use msgbuf::MessagBuffer;
 
 use super::msg::{Message, Messages};
@@ -1,11 +1,24 @@
-use std::{io, sync::Arc, time::Duration};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use ruisutil::asyncs::{make_channel, net::TcpStream, task, AsyncReadExt, Receiver, Sender};
-use ruisutil::bytes::ByteSteamBuf;
+use ruisutil::asyncs::{make_channel, task, AsyncReadExt, Receiver, Sender};
+use ruisutil::bytes::{self, ByteSteamBuf, BytesCut};
 
-use crate::socks::msg::{self, tcps};
+use crate::{
+    socks::msg::{self, entity, tcps, Compress},
+    transport::{self, BoxStream, Stream},
+};
 
-use super::{Senders, TMessageRecv};
+use super::{
+    sendq::{MsgPriority, QueuePolicy, SendConfig, SendQueue},
+    Senders, TMessageRecv,
+};
 
 #[derive(Clone)]
 pub struct Messager {
@@ -14,7 +27,7 @@ pub struct Messager {
 
 struct Inner {
     ctx: ruisutil::asyncs::Context,
-    conn: TcpStream,
+    conn: BoxStream,
     shuted: bool,
     is_serv: bool,
     //check
@@ -22,16 +35,36 @@ struct Inner {
     ctmout: ruisutil::Timer,
     msgs_sx: Sender<msg::Messages>,
     msgs_rx: Receiver<msg::Messages>,
+    sendq: SendQueue,
+    wake_rx: Receiver<()>,
 
     buf: ByteSteamBuf,
+    //(消息id, 下一分片序号, 首片消息, 已收body)
+    frags: Option<(u32, u32, msg::Message, bytes::ByteBoxBuf)>,
+    //对端心跳声明了 frag=1 才分片发送, 兼容不认识分片帧的旧版本
+    peer_frag: bool,
+    frag_ids: AtomicU32,
+
+    //压缩: 本地可用算法, 通过心跳与对端协商
+    cmp_prefs: Vec<Compress>,
+    opts: entity::MsgOpts,
+
+    //接收 body(解压或分片合并后)的上限
+    max_bodys: u64,
+
+    //帧错误后扫描下一帧继续解析(仅 stream buf 模式)
+    resync: bool,
+    resync_win: Vec<u8>,
+    resyncs: AtomicU64,
+    resync_dropped: AtomicU64,
 
     recver: Box<TMessageRecv>,
 }
 
 impl Messager {
-    pub fn new(
+    pub fn new<S: Stream + 'static>(
         ctx: &ruisutil::asyncs::Context,
-        conn: TcpStream,
+        conn: S,
         recver: Box<TMessageRecv>,
         sndbufln: usize,
     ) -> (Self, Senders) {
@@ -40,10 +73,11 @@ impl Messager {
         } else {
             make_channel(100)
         };
+        let (wsx, wrx) = make_channel(1);
         let c = Self {
             inner: ruisutil::ArcMut::new(Inner {
                 ctx: ctx.child(),
-                conn: conn,
+                conn: transport::boxed(conn),
                 shuted: false,
                 is_serv: false,
 
@@ -51,8 +85,23 @@ impl Messager {
                 ctmout: ruisutil::Timer::new(Duration::from_secs(30)),
                 msgs_sx: sx.clone(),
                 msgs_rx: rx,
+                sendq: SendQueue::new(SendConfig::default(), wsx),
+                wake_rx: wrx,
 
                 buf: ByteSteamBuf::new(&ctx, 1024, Duration::from_millis(100)),
+                frags: None,
+                peer_frag: false,
+                frag_ids: AtomicU32::new(1),
+
+                cmp_prefs: Compress::supported(),
+                opts: entity::MsgOpts::default(),
+
+                max_bodys: msg::MAX_BODYS,
+
+                resync: false,
+                resync_win: Vec::new(),
+                resyncs: AtomicU64::new(0),
+                resync_dropped: AtomicU64::new(0),
 
                 recver: recver,
             }),
@@ -60,6 +109,43 @@ impl Messager {
         (c, sx)
     }
 
+    pub fn set_send_config(&self, cfg: SendConfig) {
+        unsafe { self.inner.muts().sendq.set_config(cfg) };
+    }
+    //设置可用压缩算法(按优先级), 为空则不压缩; body 小于 min 时不压缩
+    pub fn set_compress(&self, prefs: Vec<Compress>, min: usize) {
+        let ins = unsafe { self.inner.muts() };
+        ins.cmp_prefs = prefs;
+        ins.opts.cmp_min = min;
+        ins.opts.cmp = Compress::None;
+    }
+    pub fn compress(&self) -> Compress {
+        self.inner.opts.cmp
+    }
+    //发送帧附带 CRC32C 校验, 接收端无论此设置都会校验带 CRC 的帧
+    pub fn set_checksum(&self, crc: bool) {
+        unsafe { self.inner.muts().opts.crc = crc };
+    }
+    pub fn set_max_bodys(&self, max: u64) {
+        unsafe { self.inner.muts().max_bodys = max };
+    }
+    pub fn set_resync(&self, resync: bool) {
+        unsafe { self.inner.muts().resync = resync };
+    }
+    //(重新同步次数, 丢弃字节数)
+    pub fn resync_stats(&self) -> (u64, u64) {
+        (
+            self.inner.resyncs.load(Ordering::SeqCst),
+            self.inner.resync_dropped.load(Ordering::SeqCst),
+        )
+    }
+    pub fn queue_depth(&self, pri: MsgPriority) -> usize {
+        self.inner.sendq.depth(pri)
+    }
+    pub fn queue_dropped(&self) -> usize {
+        self.inner.sendq.dropped()
+    }
+
     pub async fn stop(&self) -> io::Result<()> {
         if self.inner.shuted {
             return Ok(());
@@ -70,13 +156,20 @@ impl Messager {
         self.inner.ctx.cancel();
         self.inner.buf.close();
         ruisutil::asyncs::close_channel_snd(&self.inner.msgs_sx);
-        ruisutil::asyncs::tcp_shutdownw_ac(&mut ins.conn).await
+        transport::shutdownw(&mut ins.conn).await
     }
 
     pub async fn run(&self, servs: bool, is_stream_buf: bool) {
         self.inner.ctmout.reset();
         unsafe { self.inner.muts().is_serv = servs };
         let c = self.clone();
+        task::spawn(async move {
+            if let Err(e) = c.run_pump().await {
+                println!("run_pump err:{}", e);
+            }
+            println!("Messager run_pump end!!");
+        });
+        let c = self.clone();
         task::spawn(async move {
             if let Err(e) = c.run_send().await {
                 println!("run_send err:{}", e);
@@ -112,6 +205,19 @@ impl Messager {
                 println!("Messager run_recv end!!");
             });
         }
+        if !servs {
+            // 立即发送心跳以尽早完成压缩协商
+            let _ = self
+                .inner
+                .sendq
+                .push(
+                    &self.inner.ctx,
+                    MsgPriority::Control,
+                    self.heart_msg(),
+                    QueuePolicy::DropOldest,
+                )
+                .await;
+        }
         println!("Messager start run check");
         while !self.inner.ctx.cancelled() {
             self.run_check().await;
@@ -144,27 +250,159 @@ impl Messager {
     }
     async fn run_parse(&self) -> io::Result<()> {
         loop {
-            let v = tcps::parse_steam_msg(&self.inner.ctx, &self.inner.buf).await?;
+            //resync 预读剩余的数据先解析完
+            if !self.inner.resync_win.is_empty() {
+                let v = self.resync_next().await?;
+                if let Err(e) = self.on_msg(v).await {
+                    println!("run_parse on_msg err:{}", e);
+                }
+                continue;
+            }
+            let ins = &self.inner;
+            let v = match tcps::parse_steam_msg_max(&ins.ctx, &ins.buf, ins.max_bodys, ins.resync)
+                .await
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    if !self.inner.resync || e.kind() != io::ErrorKind::InvalidData {
+                        return Err(e);
+                    }
+                    println!("run_parse frame err:{}, start resync", e);
+                    self.resync().await?
+                }
+            };
             if let Err(e) = self.on_msg(v).await {
                 println!("run_parse on_msg err:{}", e);
             }
         }
     }
+    async fn resync(&self) -> io::Result<msg::Message> {
+        unsafe { self.inner.muts().frags = None };
+        self.inner.resyncs.fetch_add(1, Ordering::SeqCst);
+        self.resync_next().await
+    }
+    async fn resync_next(&self) -> io::Result<msg::Message> {
+        let ins = unsafe { self.inner.muts() };
+        let mut dropped = 0u64;
+        let rt = tcps::resync_steam_msg_max(
+            &ins.ctx,
+            &ins.buf,
+            &mut ins.resync_win,
+            &mut dropped,
+            ins.max_bodys,
+        )
+        .await;
+        self.inner
+            .resync_dropped
+            .fetch_add(dropped, Ordering::SeqCst);
+        rt
+    }
+    //心跳 heads: "cmp=zstd,lz4&frag=1", 各项以 & 分隔
+    fn heart_msg(&self) -> msg::Messages {
+        let mut hds = Vec::new();
+        if !self.inner.cmp_prefs.is_empty() {
+            hds.push(format!("cmp={}", Compress::fmt_list(&self.inner.cmp_prefs[..])));
+        }
+        hds.push("frag=1".to_string());
+        msg::Messages {
+            control: 0,
+            cmds: Some("heart".into()),
+            heads: Some(bytes::Bytes::from(hds.join("&").into_bytes())),
+            bodys: None,
+            bodybuf: None,
+            ..Default::default()
+        }
+    }
+    fn on_heart(&self, msg: &msg::Message) {
+        let mut peers = Vec::new();
+        let mut frag = false;
+        if let Some(v) = &msg.heads {
+            if let Ok(s) = std::str::from_utf8(&v[..]) {
+                for it in s.split('&') {
+                    if let Some(ls) = it.strip_prefix("cmp=") {
+                        peers = Compress::parse_list(ls);
+                    } else if it == "frag=1" {
+                        frag = true;
+                    }
+                }
+            }
+        }
+        let cmp = Compress::negotiate(&self.inner.cmp_prefs[..], &peers[..]);
+        if cmp != self.inner.opts.cmp {
+            unsafe { self.inner.muts().opts.cmp = cmp };
+        }
+        if frag != self.inner.peer_frag {
+            unsafe { self.inner.muts().peer_frag = frag };
+        }
+    }
+    fn merge_frag(&self, mut msg: msg::Message) -> io::Result<Option<msg::Message>> {
+        let ins = unsafe { self.inner.muts() };
+        let mut bds = match msg.own_bodys() {
+            msg::MsgBody::Bytes(v) if v.len() >= entity::FRAG_HEAD_LEN => v,
+            msg::MsgBody::BoxBuf(v) if v.len() >= entity::FRAG_HEAD_LEN => v.to_bytes(),
+            _ => {
+                ins.frags = None;
+                return Err(ruisutil::ioerr("frag head err!!", None));
+            }
+        };
+        let hd = bds.cuts(entity::FRAG_HEAD_LEN)?;
+        let id = ruisutil::byte_2i(&hd[0..4]) as u32;
+        let ind = ruisutil::byte_2i(&hd[4..8]) as u32;
+        //新消息的首片: 丢弃之前未完成的分片(如发送端中途出错)
+        if ind == 0 {
+            ins.frags = Some((id, 0, msg.clone(), bytes::ByteBoxBuf::new()));
+        }
+        let ok = match &mut ins.frags {
+            Some((fid, next, _, buf)) if *fid == id && *next == ind => {
+                buf.push(bds);
+                *next += 1;
+                buf.len() as u64 <= self.inner.max_bodys
+            }
+            _ => false,
+        };
+        if !ok {
+            ins.frags = None;
+            return Err(ruisutil::ioerr("frag out of order or out limit!!", None));
+        }
+        if msg.version != entity::MSG_VER_FRAG_END {
+            return Ok(None);
+        }
+        match std::mem::replace(&mut ins.frags, None) {
+            None => Ok(None),
+            Some((_, _, mut rt, buf)) => {
+                rt.version = entity::MSG_VER;
+                rt.bodys = msg::MsgBody::BoxBuf(buf);
+                Ok(Some(rt))
+            }
+        }
+    }
     async fn on_msg(&self, msg: msg::Message) -> io::Result<()> {
+        let msg = match msg.version {
+            entity::MSG_VER_FRAG | entity::MSG_VER_FRAG_END => match self.merge_frag(msg)? {
+                None => return Ok(()),
+                Some(v) => v,
+            },
+            _ => msg,
+        };
         let ctrl = msg.control;
         match ctrl {
             0 => {
                 self.inner.ctmout.reset();
+                self.on_heart(&msg);
                 if self.inner.is_serv {
-                    let msg = msg::Messages {
-                        control: 0,
-                        cmds: Some("heart".into()),
-                        heads: None,
-                        bodys: None,
-                        bodybuf: None,
-                    };
-                    if let Err(e) = self.inner.msgs_sx.try_send(msg) {
-                        println!("chan send err:{}", e);
+                    let msg = self.heart_msg();
+                    if let Err(e) = self
+                        .inner
+                        .sendq
+                        .push(
+                            &self.inner.ctx,
+                            MsgPriority::Control,
+                            msg,
+                            QueuePolicy::DropOldest,
+                        )
+                        .await
+                    {
+                        println!("heart send err:{}", e);
                     }
                 }
             }
@@ -188,28 +426,92 @@ impl Messager {
     async fn run_recv(&self) -> std::io::Result<()> {
         let ins = unsafe { self.inner.muts() };
         loop {
-            let v = msg::tcps::parse_msg(&self.inner.ctx, &mut ins.conn).await?;
+            let v = msg::tcps::parse_msg_max(&self.inner.ctx, &mut ins.conn, self.inner.max_bodys)
+                .await?;
             if let Err(e) = self.on_msg(v).await {
                 println!("run_recv on_msg err:{}", e);
             }
         }
     }
-    async fn run_send(&self) -> std::io::Result<()> {
+    async fn run_pump(&self) -> std::io::Result<()> {
         let ins = unsafe { self.inner.muts() };
         self.inner
             .ctx
             .wait_futs(async {
                 loop {
                     let v = ruisutil::asyncs::channel_recv(&mut ins.msgs_rx).await?;
-                    // println!("-------test-run_send: send_msgs start:ctrl={}", v.control);
-                    if let Err(e) = msg::tcps::send_msgs(&self.inner.ctx, &mut ins.conn, v).await {
-                        println!("run_send send_msgs err:{}", e);
-                        ruisutil::asyncs::sleep(Duration::from_millis(10)).await;
+                    let pri = self.inner.sendq.classify(&v);
+                    let policy = self.inner.sendq.config().policy;
+                    if let Err(e) = self
+                        .inner
+                        .sendq
+                        .push(&self.inner.ctx, pri, v, policy)
+                        .await
+                    {
+                        println!("run_pump push err:{}", e);
                     }
                 }
             })
             .await
     }
+    async fn run_send(&self) -> std::io::Result<()> {
+        let ins = unsafe { self.inner.muts() };
+        let mut bulk: Option<FragSend> = None;
+        while !self.inner.ctx.cancelled() {
+            if let Some(v) = self.inner.sendq.pop_urgent() {
+                // println!("-------test-run_send: send_msgs start:ctrl={}", v.control);
+                if let Err(e) = self.send_msgs(v).await {
+                    println!("run_send send_msgs err:{}", e);
+                    ruisutil::asyncs::sleep(Duration::from_millis(10)).await;
+                }
+                continue;
+            }
+            if let Some(fs) = &mut bulk {
+                match fs
+                    .send_next(&self.inner.ctx, &mut ins.conn, &self.inner.opts)
+                    .await
+                {
+                    Ok(true) => bulk = None,
+                    Ok(false) => {}
+                    Err(e) => {
+                        // 对端收到下一条分片消息的首片时丢弃这条未完成的消息
+                        println!("run_send send frag err:{}", e);
+                        bulk = None;
+                        ruisutil::asyncs::sleep(Duration::from_millis(10)).await;
+                    }
+                }
+                continue;
+            }
+            if let Some(v) = self.inner.sendq.pop(MsgPriority::Bulk) {
+                let fragsz = if self.inner.peer_frag {
+                    self.inner.sendq.config().frag_size
+                } else {
+                    0
+                };
+                let id = self.inner.frag_ids.fetch_add(1, Ordering::SeqCst);
+                match FragSend::new(v, id, fragsz) {
+                    Ok(fs) => bulk = Some(fs),
+                    Err(v) => {
+                        if let Err(e) = self.send_msgs(v).await {
+                            println!("run_send send_msgs err:{}", e);
+                            ruisutil::asyncs::sleep(Duration::from_millis(10)).await;
+                        }
+                    }
+                }
+                continue;
+            }
+            let _ = ruisutil::asyncs::timeouts(
+                Duration::from_millis(100),
+                ruisutil::asyncs::channel_recv(&mut ins.wake_rx),
+            )
+            .await;
+        }
+        Ok(())
+    }
+    async fn send_msgs(&self, v: msg::Messages) -> io::Result<()> {
+        let ins = unsafe { self.inner.muts() };
+        tcps::send_msgs_opt(&self.inner.ctx, &mut ins.conn, v, &self.inner.opts).await
+    }
     async fn run_check(&self) {
         /* println!(
             "m run_check:ctmout={}ms!!!--------------",
@@ -223,23 +525,18 @@ impl Messager {
         }
 
         if !self.inner.is_serv && self.inner.ctms.tick() {
-            let msg = msg::Messages {
-                control: 0,
-                cmds: Some("heart".into()),
-                heads: None,
-                bodys: None,
-                bodybuf: None,
-            };
+            let msg = self.heart_msg();
             // self.inner.msgs_sx.try_send(msg);
-            let c = self.clone();
-            if let Err(e) = ruisutil::asyncs::timeouts(Duration::from_secs(3), async move {
-                c.inner
-                    .msgs_sx
-                    .send(msg)
-                    .await
-                    .map_err(|e| ruisutil::ioerr("send err", None))
-            })
-            .await
+            if let Err(e) = self
+                .inner
+                .sendq
+                .push(
+                    &self.inner.ctx,
+                    MsgPriority::Control,
+                    msg,
+                    QueuePolicy::DropOldest,
+                )
+                .await
             {
                 println!("msger run_check send heart err:{}", e);
             }
@@ -255,11 +552,99 @@ impl Messager {
     }
 
     pub async fn send(&self, mv: msg::Messages) -> io::Result<()> {
-        if let Err(e) = self.inner.msgs_sx.send(mv).await {
-            //println!("chan send err:{}", e);
-            Err(ruisutil::ioerr(format!("chan send err:{}", e), None))
+        let pri = self.inner.sendq.classify(&mv);
+        self.send_pri(pri, mv).await
+    }
+    pub async fn send_pri(&self, pri: MsgPriority, mv: msg::Messages) -> io::Result<()> {
+        if self.inner.shuted {
+            return Err(ruisutil::ioerr("msger is stoped", None));
+        }
+        let policy = self.inner.sendq.config().policy;
+        self.inner
+            .sendq
+            .push(&self.inner.ctx, pri, mv, policy)
+            .await
+    }
+}
+
+struct FragSend {
+    id: u32,
+    ind: u32,
+    first: bool,
+    trace: bool,
+    control: i32,
+    cmds: Option<String>,
+    heads: Option<bytes::Bytes>,
+    bodys: bytes::Bytes,
+    fragsz: usize,
+}
+impl FragSend {
+    fn new(msg: msg::Messages, id: u32, fragsz: usize) -> Result<Self, msg::Messages> {
+        let bodys = if let Some(v) = &msg.bodybuf {
+            v.to_bytes()
+        } else if let Some(v) = &msg.bodys {
+            v.clone()
         } else {
-            Ok(())
+            return Err(msg);
+        };
+        if fragsz <= 0 || bodys.len() <= fragsz {
+            return Err(msg);
         }
+        Ok(Self {
+            id: id,
+            ind: 0,
+            first: true,
+            trace: msg.trace,
+            control: msg.control,
+            cmds: msg.cmds,
+            heads: msg.heads,
+            bodys: bodys,
+            fragsz: fragsz,
+        })
+    }
+    //返回 true 表示已发送最后一片
+    async fn send_next(
+        &mut self,
+        ctx: &ruisutil::asyncs::Context,
+        conn: &mut BoxStream,
+        opts: &entity::MsgOpts,
+    ) -> io::Result<bool> {
+        let n = if self.bodys.len() > self.fragsz {
+            self.fragsz
+        } else {
+            self.bodys.len()
+        };
+        let bts = self.bodys.cuts(n)?;
+        let mut bds = Vec::with_capacity(entity::FRAG_HEAD_LEN + bts.len());
+        bds.extend(ruisutil::i2_byte(self.id as i64, 4));
+        bds.extend(ruisutil::i2_byte(self.ind as i64, 4));
+        bds.extend_from_slice(&bts[..]);
+        self.ind += 1;
+        let ends = self.bodys.len() <= 0;
+        let ver = if ends {
+            entity::MSG_VER_FRAG_END
+        } else {
+            entity::MSG_VER_FRAG
+        };
+        //heads(及追踪前缀)只在第一片
+        let (ver, cmds, heads) = if self.first {
+            self.first = false;
+            let flags = if self.trace { entity::FLAG_TRACE } else { 0 };
+            (entity::ver_join(ver, flags), self.cmds.take(), self.heads.take())
+        } else {
+            (ver, None, None)
+        };
+        tcps::send_msg_opt(
+            ctx,
+            conn,
+            ver,
+            opts,
+            self.control,
+            cmds,
+            &heads,
+            Some(&bds[..]),
+        )
+        .await?;
+        Ok(ends)
     }
 }
@@ -0,0 +1,205 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use ruisutil::asyncs::Sender;
+
+use crate::socks::msg::Messages;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MsgPriority {
+    Control = 0,
+    Normal = 1,
+    Bulk = 2,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QueuePolicy {
+    Block,
+    DropOldest,
+    Error,
+}
+
+#[derive(Clone)]
+pub struct SendConfig {
+    pub cap_control: usize,
+    pub cap_normal: usize,
+    pub cap_bulk: usize,
+    pub policy: QueuePolicy,
+    //bulk body 超过此大小将分片发送(仅当对端在心跳中声明支持分片)
+    pub frag_size: usize,
+}
+
+impl Default for SendConfig {
+    fn default() -> Self {
+        Self {
+            cap_control: 100,
+            cap_normal: 100,
+            cap_bulk: 20,
+            policy: QueuePolicy::Block,
+            frag_size: 1024 * 64, //64K
+        }
+    }
+}
+
+pub(crate) struct SendQueue {
+    cfg: SendConfig,
+    qs: [Mutex<VecDeque<Messages>>; 3],
+    lens: [AtomicUsize; 3],
+    //Block 策略下等待空位的发送方, pop 时唤醒; 先锁 qs 再锁 waits
+    waits: [Mutex<Vec<Waker>>; 3],
+    drops: AtomicUsize,
+    wake: Sender<()>,
+}
+
+impl SendQueue {
+    pub fn new(cfg: SendConfig, wake: Sender<()>) -> Self {
+        Self {
+            cfg: cfg,
+            qs: [
+                Mutex::new(VecDeque::new()),
+                Mutex::new(VecDeque::new()),
+                Mutex::new(VecDeque::new()),
+            ],
+            lens: [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+            waits: Default::default(),
+            drops: AtomicUsize::new(0),
+            wake: wake,
+        }
+    }
+
+    pub fn config(&self) -> &SendConfig {
+        &self.cfg
+    }
+    pub fn set_config(&mut self, cfg: SendConfig) {
+        self.cfg = cfg;
+    }
+    fn cap(&self, pri: MsgPriority) -> usize {
+        let n = match pri {
+            MsgPriority::Control => self.cfg.cap_control,
+            MsgPriority::Normal => self.cfg.cap_normal,
+            MsgPriority::Bulk => self.cfg.cap_bulk,
+        };
+        if n > 0 {
+            n
+        } else {
+            1
+        }
+    }
+
+    pub fn classify(&self, msg: &Messages) -> MsgPriority {
+        if msg.control == 0 {
+            return MsgPriority::Control;
+        }
+        let ln = if let Some(v) = &msg.bodybuf {
+            v.len()
+        } else if let Some(v) = &msg.bodys {
+            v.len()
+        } else {
+            0
+        };
+        if ln > self.cfg.frag_size {
+            MsgPriority::Bulk
+        } else {
+            MsgPriority::Normal
+        }
+    }
+
+    pub fn depth(&self, pri: MsgPriority) -> usize {
+        self.lens[pri as usize].load(Ordering::SeqCst)
+    }
+    pub fn dropped(&self) -> usize {
+        self.drops.load(Ordering::SeqCst)
+    }
+
+    fn try_push(&self, pri: MsgPriority, msg: Messages, policy: QueuePolicy) -> Result<(), Messages> {
+        let i = pri as usize;
+        let mut lkv = self.qs[i].lock().unwrap();
+        if lkv.len() >= self.cap(pri) {
+            if policy != QueuePolicy::DropOldest {
+                return Err(msg);
+            }
+            lkv.pop_front();
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+        lkv.push_back(msg);
+        self.lens[i].store(lkv.len(), Ordering::SeqCst);
+        let _ = self.wake.try_send(());
+        Ok(())
+    }
+
+    pub async fn push(
+        &self,
+        ctx: &ruisutil::asyncs::Context,
+        pri: MsgPriority,
+        msg: Messages,
+        policy: QueuePolicy,
+    ) -> io::Result<()> {
+        let mut msg = msg;
+        loop {
+            msg = match self.try_push(pri, msg, policy) {
+                Ok(()) => return Ok(()),
+                Err(v) => v,
+            };
+            if policy == QueuePolicy::Error {
+                return Err(ruisutil::ioerr(
+                    format!("send queue({:?}) full", pri),
+                    Some(io::ErrorKind::WouldBlock),
+                ));
+            }
+            if ctx.cancelled() {
+                return Err(ruisutil::ioerr("send queue closed", None));
+            }
+            ctx.wait_futs(SpaceWait { q: self, pri: pri }).await?;
+        }
+    }
+
+    pub fn pop(&self, pri: MsgPriority) -> Option<Messages> {
+        let i = pri as usize;
+        let mut lkv = self.qs[i].lock().unwrap();
+        let rt = lkv.pop_front();
+        self.lens[i].store(lkv.len(), Ordering::SeqCst);
+        if rt.is_some() {
+            for w in self.waits[i].lock().unwrap().drain(..) {
+                w.wake();
+            }
+        }
+        rt
+    }
+    //Control 优先于 Normal; Bulk 由发送方按分片穿插
+    pub fn pop_urgent(&self) -> Option<Messages> {
+        match self.pop(MsgPriority::Control) {
+            Some(v) => Some(v),
+            None => self.pop(MsgPriority::Normal),
+        }
+    }
+}
+
+//等待队列出现空位
+struct SpaceWait<'a> {
+    q: &'a SendQueue,
+    pri: MsgPriority,
+}
+impl Future for SpaceWait<'_> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let i = self.pri as usize;
+        let lkv = self.q.qs[i].lock().unwrap();
+        if lkv.len() < self.q.cap(self.pri) {
+            return Poll::Ready(());
+        }
+        let mut ws = self.q.waits[i].lock().unwrap();
+        if !ws.iter().any(|w| w.will_wake(cx.waker())) {
+            ws.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
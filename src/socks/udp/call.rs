@@ -0,0 +1,27 @@
+use std::io;
+
+use ruisutil::bytes::{self, BytesCut};
+
+/*
+  41: CALL   reqid(4) + msg      请求, 超时重传同一 reqid
+  42: REPLY  reqid(4) + msg      应答
+  43: ERROR  reqid(4) + 错误信息  on_call 返回错误
+*/
+pub const CTRL_CALL: u16 = 41;
+pub const CTRL_REPLY: u16 = 42;
+pub const CTRL_ERROR: u16 = 43;
+pub const CALL_HEAD_LEN: usize = 4;
+
+pub fn id_parse(buf: &mut bytes::Bytes) -> io::Result<u32> {
+    let bts = buf.cuts(CALL_HEAD_LEN)?;
+    Ok(ruisutil::byte_2i(&bts[..]) as u32)
+}
+pub fn id_fmts(id: u32) -> Vec<u8> {
+    ruisutil::i2_byte(id as i64, CALL_HEAD_LEN)
+}
+pub fn err_parse(buf: bytes::Bytes) -> io::Error {
+    match std::str::from_utf8(&buf[..]) {
+        Ok(v) => ruisutil::ioerr(format!("call err:{}", v), None),
+        Err(_) => ruisutil::ioerr("call err", None),
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use super::udps::UdpConfig;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DropReason {
+    //来源在封禁期内
+    Banned,
+    //超过每秒包数
+    PktRate,
+    //超过每秒字节数
+    ByteRate,
+    //处理中的包超过上限
+    Inflight,
+    //解析队列已满
+    Queue,
+    //check_token 未通过
+    Token,
+    //统计的来源数已满, 新来源不再接收
+    TooManySources,
+}
+const DROP_REASONS: usize = 7;
+
+#[derive(Clone, Default, Debug)]
+pub struct DropStats {
+    pub banned: u64,
+    pub pkt_rate: u64,
+    pub byte_rate: u64,
+    pub inflight: u64,
+    pub queue: u64,
+    pub token: u64,
+    pub too_many_srcs: u64,
+}
+
+struct SrcStat {
+    win: Instant,
+    pkts: u32,
+    bytes: u64,
+    //本窗口是否已记违规
+    over: bool,
+    strikes: u32,
+    uptm: Instant,
+}
+impl SrcStat {
+    fn new(now: Instant) -> Self {
+        Self {
+            win: now,
+            pkts: 0,
+            bytes: 0,
+            over: false,
+            strikes: 0,
+            uptm: now,
+        }
+    }
+}
+
+//按来源限速, 多次违规后临时封禁
+pub struct Limiter {
+    srcs: std::sync::Mutex<HashMap<SocketAddr, SrcStat>>,
+    bans: std::sync::Mutex<HashMap<SocketAddr, Instant>>,
+
+    banned: AtomicU64,
+    pkt_rate: AtomicU64,
+    byte_rate: AtomicU64,
+    inflight: AtomicU64,
+    queue: AtomicU64,
+    token: AtomicU64,
+    too_many_srcs: AtomicU64,
+    //各原因上次回调 on_drop 的时间(相对 start 的毫秒数 + 1, 0 为未回调过)
+    start: Instant,
+    notified: [AtomicU64; DROP_REASONS],
+}
+impl Limiter {
+    pub fn new() -> Self {
+        Self {
+            srcs: std::sync::Mutex::new(HashMap::new()),
+            bans: std::sync::Mutex::new(HashMap::new()),
+            banned: AtomicU64::new(0),
+            pkt_rate: AtomicU64::new(0),
+            byte_rate: AtomicU64::new(0),
+            inflight: AtomicU64::new(0),
+            queue: AtomicU64::new(0),
+            token: AtomicU64::new(0),
+            too_many_srcs: AtomicU64::new(0),
+            start: Instant::now(),
+            notified: Default::default(),
+        }
+    }
+
+    fn is_banned(&self, src: &SocketAddr, now: Instant) -> bool {
+        let mut lkv = match self.bans.lock() {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+        match lkv.get(src) {
+            None => false,
+            Some(tm) if *tm > now => true,
+            Some(_) => {
+                lkv.remove(src);
+                false
+            }
+        }
+    }
+
+    //返回需要丢弃的原因
+    pub fn check(&self, cfg: &UdpConfig, src: &SocketAddr, ln: usize) -> Option<DropReason> {
+        let now = Instant::now();
+        if self.is_banned(src, now) {
+            return Some(DropReason::Banned);
+        }
+        if cfg.lmt_pkts <= 0 && cfg.lmt_bytes <= 0 {
+            return None;
+        }
+        let mut lkv = match self.srcs.lock() {
+            Ok(v) => v,
+            Err(_) => return None,
+        };
+        if !lkv.contains_key(src) {
+            if lkv.len() >= cfg.lmt_max_srcs {
+                lkv.retain(|_, v| now.saturating_duration_since(v.uptm) < cfg.ban_time);
+                if lkv.len() >= cfg.lmt_max_srcs {
+                    // 不统计就不限速, 满了直接丢弃新来源
+                    return Some(DropReason::TooManySources);
+                }
+            }
+            lkv.insert(src.clone(), SrcStat::new(now));
+        }
+        let st = match lkv.get_mut(src) {
+            None => return None,
+            Some(v) => v,
+        };
+        st.uptm = now;
+        if now.saturating_duration_since(st.win) >= Duration::from_secs(1) {
+            st.win = now;
+            st.pkts = 0;
+            st.bytes = 0;
+            st.over = false;
+        }
+        st.pkts += 1;
+        st.bytes += ln as u64;
+        let rt = if cfg.lmt_pkts > 0 && st.pkts > cfg.lmt_pkts {
+            DropReason::PktRate
+        } else if cfg.lmt_bytes > 0 && st.bytes > cfg.lmt_bytes {
+            DropReason::ByteRate
+        } else {
+            return None;
+        };
+        if !st.over {
+            st.over = true;
+            st.strikes += 1;
+            if cfg.ban_strikes > 0 && st.strikes >= cfg.ban_strikes {
+                st.strikes = 0;
+                std::mem::drop(lkv);
+                self.ban(src, cfg.ban_time);
+            }
+        }
+        Some(rt)
+    }
+    //记一次违规(如 token 错误), 返回是否因此封禁
+    pub fn strike(&self, cfg: &UdpConfig, src: &SocketAddr) -> bool {
+        if cfg.ban_strikes <= 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let mut lkv = match self.srcs.lock() {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+        if !lkv.contains_key(src) {
+            if lkv.len() >= cfg.lmt_max_srcs {
+                return false;
+            }
+            lkv.insert(src.clone(), SrcStat::new(now));
+        }
+        if let Some(st) = lkv.get_mut(src) {
+            st.uptm = now;
+            st.strikes += 1;
+            if st.strikes >= cfg.ban_strikes {
+                st.strikes = 0;
+                std::mem::drop(lkv);
+                self.ban(src, cfg.ban_time);
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn ban(&self, src: &SocketAddr, tm: Duration) {
+        if let Ok(mut lkv) = self.bans.lock() {
+            lkv.insert(src.clone(), Instant::now() + tm);
+        }
+    }
+    pub fn unban(&self, src: &SocketAddr) -> bool {
+        match self.bans.lock() {
+            Ok(mut lkv) => lkv.remove(src).is_some(),
+            Err(_) => false,
+        }
+    }
+    //封禁列表及剩余时间
+    pub fn bans(&self) -> Vec<(SocketAddr, Duration)> {
+        let now = Instant::now();
+        match self.bans.lock() {
+            Ok(lkv) => lkv
+                .iter()
+                .filter(|(_, v)| **v > now)
+                .map(|(k, v)| (k.clone(), v.saturating_duration_since(now)))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+    pub fn clean(&self, cfg: &UdpConfig) {
+        let now = Instant::now();
+        if let Ok(mut lkv) = self.bans.lock() {
+            lkv.retain(|_, v| *v > now);
+        }
+        if let Ok(mut lkv) = self.srcs.lock() {
+            lkv.retain(|_, v| now.saturating_duration_since(v.uptm) < cfg.ban_time);
+        }
+    }
+
+    pub fn count(&self, reason: DropReason) -> u64 {
+        let v = match reason {
+            DropReason::Banned => &self.banned,
+            DropReason::PktRate => &self.pkt_rate,
+            DropReason::ByteRate => &self.byte_rate,
+            DropReason::Inflight => &self.inflight,
+            DropReason::Queue => &self.queue,
+            DropReason::Token => &self.token,
+            DropReason::TooManySources => &self.too_many_srcs,
+        };
+        v.fetch_add(1, Ordering::SeqCst) + 1
+    }
+    //on_drop 抽样: 每个原因每秒最多回调一次, 避免洪水时每个包都进回调
+    pub fn notify(&self, reason: DropReason) -> bool {
+        let v = &self.notified[reason as usize];
+        let now = self.start.elapsed().as_millis() as u64 + 1;
+        let last = v.load(Ordering::Relaxed);
+        if last != 0 && now < last + 1000 {
+            return false;
+        }
+        v.compare_exchange(last, now, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+    }
+    pub fn stats(&self) -> DropStats {
+        DropStats {
+            banned: self.banned.load(Ordering::SeqCst),
+            pkt_rate: self.pkt_rate.load(Ordering::SeqCst),
+            byte_rate: self.byte_rate.load(Ordering::SeqCst),
+            inflight: self.inflight.load(Ordering::SeqCst),
+            queue: self.queue.load(Ordering::SeqCst),
+            token: self.token.load(Ordering::SeqCst),
+            too_many_srcs: self.too_many_srcs.load(Ordering::SeqCst),
+        }
+    }
+}
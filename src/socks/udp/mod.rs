@@ -1,6 +1,18 @@
 
+mod call;
+pub(crate) mod limit;
+mod nat;
+pub(crate) mod reliable;
+pub(crate) mod seal;
+pub(crate) mod session;
+mod sock;
 mod udp;
-// mod udpc;
-mod udps;
+mod udpc;
+pub(crate) mod udps;
 
-pub use udp::{UMsgerServ,IUMsgerServ};
\ No newline at end of file
+pub use udp::{UMsgerServ,IUMsgerServ};
+pub use limit::{DropReason, DropStats};
+pub use reliable::LossSim;
+pub use session::{SessionClose, UdpSession};
+pub use udpc::{IUMsgerCli, UMsgerClient};
+pub use udps::UdpConfig;
\ No newline at end of file
@@ -0,0 +1,112 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use ruisutil::bytes::{self, BytesCut};
+
+/*
+  51: REG        peer -> 中继: name                 登记观察到的公网地址
+  52: REG_ACK    中继 -> peer: addr                 返回观察到的地址
+  53: PUNCH_REQ  peer -> 中继: name + name(对端)     请求对端地址
+  54: PEER_INFO  中继 -> 双方: name + addr           双方收到后同时打洞
+  55: PUNCH      peer -> peer: name                 打洞/保活
+  56: PUNCH_ACK  peer -> peer: name                 收到 PUNCH 的应答
+  57: RELAY      peer -> 中继: name + name(对端) + msg  打洞失败时由中继转发
+  58: RELAYED    中继 -> peer: name + addr + msg
+  name/addr: len(1) + utf8
+*/
+pub const CTRL_REG: u16 = 51;
+pub const CTRL_REG_ACK: u16 = 52;
+pub const CTRL_PUNCH_REQ: u16 = 53;
+pub const CTRL_PEER_INFO: u16 = 54;
+pub const CTRL_PUNCH: u16 = 55;
+pub const CTRL_PUNCH_ACK: u16 = 56;
+pub const CTRL_RELAY: u16 = 57;
+pub const CTRL_RELAYED: u16 = 58;
+
+pub fn str_fmts(buf: &mut Vec<u8>, s: &str) -> io::Result<()> {
+    let bts = s.as_bytes();
+    if bts.len() > 255 {
+        return Err(ruisutil::ioerr("nat name len out 255", None));
+    }
+    buf.push(bts.len() as u8);
+    buf.extend_from_slice(bts);
+    Ok(())
+}
+pub fn str_parse(buf: &mut bytes::Bytes) -> io::Result<String> {
+    let bts = buf.cuts(1)?;
+    let ln = bts[0] as usize;
+    let bts = buf.cuts(ln)?;
+    match std::str::from_utf8(&bts[..]) {
+        Ok(v) => Ok(v.to_string()),
+        Err(e) => Err(ruisutil::ioerr(format!("nat str err:{}", e), None)),
+    }
+}
+pub fn addr_parse(buf: &mut bytes::Bytes) -> io::Result<SocketAddr> {
+    let s = str_parse(buf)?;
+    match s.parse() {
+        Ok(v) => Ok(v),
+        Err(e) => Err(ruisutil::ioerr(format!("nat addr err:{}", e), None)),
+    }
+}
+pub fn names_fmts(ls: &[&str]) -> io::Result<Vec<u8>> {
+    let mut rt = Vec::new();
+    for it in ls {
+        str_fmts(&mut rt, it)?;
+    }
+    Ok(rt)
+}
+
+pub struct NatLink {
+    pub addr: SocketAddr,
+    //已收到对端的 PUNCH/PUNCH_ACK
+    pub ok: bool,
+    pub uptm: Instant,
+    pub ping: Instant,
+}
+
+#[derive(Default)]
+pub struct NatState {
+    //中继: 登记的 peer
+    pub rdv_on: bool,
+    pub regs: HashMap<String, (SocketAddr, Instant)>,
+
+    //peer: 中继地址, 本端名称, 中继观察到的本端地址
+    pub rdv: Option<SocketAddr>,
+    pub name: String,
+    pub public: Option<SocketAddr>,
+    pub reg_tm: Option<Instant>,
+    pub links: HashMap<String, NatLink>,
+}
+impl NatState {
+    pub fn clean(&mut self, ttl: Duration) {
+        self.regs.retain(|_, v| v.1.elapsed() < ttl);
+        self.links.retain(|_, v| v.uptm.elapsed() < ttl);
+    }
+    //中继: 名称归首个登记的地址所有, 过期(ttl)前其他地址的登记/请求被拒绝
+    pub fn reg(&mut self, name: &str, addr: &SocketAddr, ttl: Duration) -> bool {
+        if let Some(v) = self.regs.get_mut(name) {
+            if v.0 != *addr && v.1.elapsed() < ttl {
+                return false;
+            }
+            *v = (addr.clone(), Instant::now());
+            return true;
+        }
+        self.regs.insert(name.to_string(), (addr.clone(), Instant::now()));
+        true
+    }
+    //只接受来自 PEER_INFO 所给地址的 PUNCH/PUNCH_ACK, 防止冒名劫持
+    pub fn link_ok(&mut self, name: &str, addr: &SocketAddr) -> bool {
+        match self.links.get_mut(name) {
+            Some(v) if v.addr == *addr => {
+                v.ok = true;
+                v.uptm = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+}
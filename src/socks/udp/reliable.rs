@@ -0,0 +1,397 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use ruisutil::bytes::{self, BytesCut};
+
+use super::udps::UdpConfig;
+
+/*
+  31: DATA  epoch(4) + seq(4) + index(2) + count(2) + data
+  32: ACK   epoch(4) + cum(4) + n(2) + n*seq(4)   cum 为下一个期望的 seq, 后跟已收到的乱序 seq
+  33: NACK  epoch(4) + n(2) + n*seq(4)            缺失的 seq, 发送端立即重传
+  epoch 为发送端实例标识, 发送端重建后 seq 从 0 开始, 接收端见到新 epoch 即重置;
+  seq 回绕, 比较一律按序列号算术
+*/
+pub const CTRL_DATA: u16 = 31;
+pub const CTRL_ACK: u16 = 32;
+pub const CTRL_NACK: u16 = 33;
+pub const DATA_HEAD_LEN: usize = 12;
+const MAX_SACKS: usize = 32;
+
+pub struct DataHead {
+    pub epoch: u32,
+    pub seq: u32,
+    pub ind: u16,
+    pub count: u16,
+}
+pub fn data_parse(buf: &mut bytes::Bytes) -> io::Result<DataHead> {
+    let bts = buf.cuts(DATA_HEAD_LEN)?;
+    let rt = DataHead {
+        epoch: ruisutil::byte_2i(&bts[0..4]) as u32,
+        seq: ruisutil::byte_2i(&bts[4..8]) as u32,
+        ind: ruisutil::byte_2i(&bts[8..10]) as u16,
+        count: ruisutil::byte_2i(&bts[10..12]) as u16,
+    };
+    if rt.count <= 0 || rt.ind >= rt.count {
+        return Err(ruisutil::ioerr(
+            format!("data param err:seq={},ind={},count={}", rt.seq, rt.ind, rt.count),
+            None,
+        ));
+    }
+    Ok(rt)
+}
+pub fn data_fmts(hd: &DataHead) -> Vec<u8> {
+    let mut rt = Vec::with_capacity(DATA_HEAD_LEN);
+    rt.extend(ruisutil::i2_byte(hd.epoch as i64, 4));
+    rt.extend(ruisutil::i2_byte(hd.seq as i64, 4));
+    rt.extend(ruisutil::i2_byte(hd.ind as i64, 2));
+    rt.extend(ruisutil::i2_byte(hd.count as i64, 2));
+    rt
+}
+fn seqs_parse(buf: &mut bytes::Bytes) -> io::Result<Vec<u32>> {
+    let bts = buf.cuts(2)?;
+    let n = ruisutil::byte_2i(&bts[..]) as usize;
+    if n > MAX_SACKS {
+        return Err(ruisutil::ioerr(format!("seqs len err:{}", n), None));
+    }
+    let mut rt = Vec::with_capacity(n);
+    for _ in 0..n {
+        let bts = buf.cuts(4)?;
+        rt.push(ruisutil::byte_2i(&bts[..]) as u32);
+    }
+    Ok(rt)
+}
+fn seqs_fmts(rt: &mut Vec<u8>, seqs: &[u32]) {
+    rt.extend(ruisutil::i2_byte(seqs.len() as i64, 2));
+    for it in seqs {
+        rt.extend(ruisutil::i2_byte(*it as i64, 4));
+    }
+}
+fn u32_parse(buf: &mut bytes::Bytes) -> io::Result<u32> {
+    let bts = buf.cuts(4)?;
+    Ok(ruisutil::byte_2i(&bts[..]) as u32)
+}
+//返回 (epoch, cum, sacks)
+pub fn ack_parse(mut buf: bytes::Bytes) -> io::Result<(u32, u32, Vec<u32>)> {
+    let epoch = u32_parse(&mut buf)?;
+    let cum = u32_parse(&mut buf)?;
+    Ok((epoch, cum, seqs_parse(&mut buf)?))
+}
+pub fn ack_fmts(epoch: u32, cum: u32, sacks: &[u32]) -> Vec<u8> {
+    let mut rt = Vec::with_capacity(10 + sacks.len() * 4);
+    rt.extend(ruisutil::i2_byte(epoch as i64, 4));
+    rt.extend(ruisutil::i2_byte(cum as i64, 4));
+    seqs_fmts(&mut rt, sacks);
+    rt
+}
+//返回 (epoch, seqs)
+pub fn nack_parse(mut buf: bytes::Bytes) -> io::Result<(u32, Vec<u32>)> {
+    let epoch = u32_parse(&mut buf)?;
+    Ok((epoch, seqs_parse(&mut buf)?))
+}
+pub fn nack_fmts(epoch: u32, seqs: &[u32]) -> Vec<u8> {
+    let mut rt = Vec::with_capacity(6 + seqs.len() * 4);
+    rt.extend(ruisutil::i2_byte(epoch as i64, 4));
+    seqs_fmts(&mut rt, seqs);
+    rt
+}
+//序列号算术: a 在 b 之前(允许回绕)
+pub fn seq_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+//----------------------------------sender
+struct RelPending {
+    pckt: bytes::Bytes,
+    sent: Instant,
+    retries: u32,
+}
+
+pub struct RelSender {
+    epoch: u32,
+    next_seq: u32,
+    inflight: BTreeMap<u32, RelPending>,
+    waits: VecDeque<(u32, bytes::Bytes)>,
+
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    uptm: Instant,
+}
+impl RelSender {
+    pub fn new(cfg: &UdpConfig) -> Self {
+        Self {
+            epoch: crate::trace::rand_id() as u32 | 1,
+            next_seq: 0,
+            inflight: BTreeMap::new(),
+            waits: VecDeque::new(),
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            rto: cfg.rel_rto_init,
+            uptm: Instant::now(),
+        }
+    }
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+    pub fn idle(&self) -> Duration {
+        self.uptm.elapsed()
+    }
+
+    pub fn next_seq(&mut self) -> u32 {
+        let rt = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        rt
+    }
+    pub fn queue(&mut self, seq: u32, pckt: bytes::Bytes) {
+        self.uptm = Instant::now();
+        self.waits.push_back((seq, pckt));
+    }
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+    pub fn waits(&self) -> usize {
+        self.waits.len() + self.inflight.len()
+    }
+    pub fn is_idle(&self) -> bool {
+        self.waits.is_empty() && self.inflight.is_empty()
+    }
+
+    //窗口内可发送的包
+    pub fn pull_sendable(&mut self, cfg: &UdpConfig, now: Instant) -> Vec<bytes::Bytes> {
+        let mut rt = Vec::new();
+        while self.inflight.len() < cfg.rel_window {
+            let (seq, pckt) = match self.waits.pop_front() {
+                None => break,
+                Some(v) => v,
+            };
+            rt.push(pckt.clone());
+            self.inflight.insert(
+                seq,
+                RelPending {
+                    pckt: pckt,
+                    sent: now,
+                    retries: 0,
+                },
+            );
+        }
+        rt
+    }
+
+    pub fn on_ack(&mut self, cfg: &UdpConfig, cum: u32, sacks: &[u32], now: Instant) {
+        self.uptm = now;
+        let mut acks: Vec<u32> = self
+            .inflight
+            .keys()
+            .filter(|k| seq_before(**k, cum))
+            .copied()
+            .collect();
+        for it in sacks {
+            if self.inflight.contains_key(it) {
+                acks.push(*it);
+            }
+        }
+        for k in acks {
+            if let Some(v) = self.inflight.remove(&k) {
+                // Karn: 重传过的包不参与 RTT 计算
+                if v.retries <= 0 {
+                    self.sample(cfg, now.saturating_duration_since(v.sent));
+                }
+            }
+        }
+    }
+    fn sample(&mut self, cfg: &UdpConfig, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let diff = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + diff) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let rto = self.srtt.unwrap_or(rtt) + self.rttvar * 4;
+        self.rto = rto.max(cfg.rel_rto_min).min(cfg.rel_rto_max);
+    }
+
+    pub fn on_nack(&mut self, seqs: &[u32], now: Instant) -> Vec<bytes::Bytes> {
+        let mut rt = Vec::new();
+        for it in seqs {
+            if let Some(v) = self.inflight.get_mut(it) {
+                v.sent = now;
+                v.retries += 1;
+                rt.push(v.pckt.clone());
+            }
+        }
+        rt
+    }
+
+    //超时重传, 重传次数超限返回错误
+    pub fn tick(&mut self, cfg: &UdpConfig, now: Instant) -> io::Result<Vec<bytes::Bytes>> {
+        let mut rt = Vec::new();
+        let mut backoff = false;
+        for (k, v) in self.inflight.iter_mut() {
+            if now.saturating_duration_since(v.sent) < self.rto {
+                continue;
+            }
+            if v.retries >= cfg.rel_max_retries {
+                return Err(ruisutil::ioerr(
+                    format!("reliable seq {} retries out limit", k),
+                    Some(io::ErrorKind::TimedOut),
+                ));
+            }
+            v.sent = now;
+            v.retries += 1;
+            rt.push(v.pckt.clone());
+            backoff = true;
+        }
+        if backoff {
+            self.rto = (self.rto * 2).min(cfg.rel_rto_max);
+        }
+        Ok(rt)
+    }
+}
+
+//----------------------------------receiver
+pub struct RelRecv {
+    pub epoch: u32,
+    pub msgs: Vec<bytes::Bytes>,
+    pub cum: u32,
+    pub sacks: Vec<u32>,
+    pub nacks: Vec<u32>,
+}
+
+pub struct RelReceiver {
+    epoch: u32,
+    expect: u32,
+    bufs: BTreeMap<u32, (u16, u16, bytes::Bytes)>,
+    parts: bytes::ByteBoxBuf,
+    part_ind: u16,
+    uptm: Instant,
+}
+impl RelReceiver {
+    pub fn new() -> Self {
+        Self {
+            epoch: 0,
+            expect: 0,
+            bufs: BTreeMap::new(),
+            parts: bytes::ByteBoxBuf::new(),
+            part_ind: 0,
+            uptm: Instant::now(),
+        }
+    }
+    pub fn idle(&self) -> Duration {
+        self.uptm.elapsed()
+    }
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    pub fn push(
+        &mut self,
+        cfg: &UdpConfig,
+        hd: DataHead,
+        data: bytes::Bytes,
+    ) -> io::Result<RelRecv> {
+        self.uptm = Instant::now();
+        let win = (cfg.rel_window * 4) as u32;
+        if hd.epoch != self.epoch {
+            // 新 epoch 只从起始窗口内切换, 延迟到达的旧 epoch 包直接丢弃
+            if hd.seq >= win {
+                return Ok(RelRecv {
+                    epoch: self.epoch,
+                    msgs: Vec::new(),
+                    cum: self.expect,
+                    sacks: Vec::new(),
+                    nacks: Vec::new(),
+                });
+            }
+            // 发送端已重建, 旧状态作废
+            self.epoch = hd.epoch;
+            self.expect = 0;
+            self.bufs.clear();
+            self.parts = bytes::ByteBoxBuf::new();
+            self.part_ind = 0;
+        }
+        let mut rt = RelRecv {
+            epoch: self.epoch,
+            msgs: Vec::new(),
+            cum: self.expect,
+            sacks: Vec::new(),
+            nacks: Vec::new(),
+        };
+        let dist = hd.seq.wrapping_sub(self.expect);
+        if dist < win {
+            if dist > 0 && !self.bufs.contains_key(&hd.seq) {
+                // 新出现的缺口
+                let mut i = self.expect;
+                while i != hd.seq && rt.nacks.len() < MAX_SACKS {
+                    if !self.bufs.contains_key(&i) {
+                        rt.nacks.push(i);
+                    }
+                    i = i.wrapping_add(1);
+                }
+            }
+            self.bufs.insert(hd.seq, (hd.ind, hd.count, data));
+        }
+
+        while let Some((ind, count, data)) = self.bufs.remove(&self.expect) {
+            self.expect = self.expect.wrapping_add(1);
+            if ind == 0 {
+                self.parts = bytes::ByteBoxBuf::new();
+            } else if ind != self.part_ind {
+                // 分片不连续, 丢弃当前消息
+                self.parts = bytes::ByteBoxBuf::new();
+                self.part_ind = 0;
+                continue;
+            }
+            self.parts.push(data);
+            if self.parts.len() > cfg.frag_max_bytes {
+                self.parts = bytes::ByteBoxBuf::new();
+                self.part_ind = 0;
+                return Err(ruisutil::ioerr("reliable msg out limit!!", None));
+            }
+            self.part_ind = ind + 1;
+            if self.part_ind >= count {
+                let parts = std::mem::replace(&mut self.parts, bytes::ByteBoxBuf::new());
+                rt.msgs.push(parts.to_bytes());
+                self.part_ind = 0;
+            }
+        }
+        rt.cum = self.expect;
+        let mut sacks: Vec<u32> = self.bufs.keys().copied().collect();
+        sacks.sort_by_key(|k| k.wrapping_sub(self.expect));
+        sacks.truncate(MAX_SACKS);
+        rt.sacks = sacks;
+        Ok(rt)
+    }
+}
+
+//----------------------------------test
+//丢包模拟: 按 rate 概率丢弃发出的包
+pub struct LossSim {
+    rate: f64,
+    seed: AtomicU64,
+}
+impl LossSim {
+    pub fn new(rate: f64, seed: u64) -> Self {
+        Self {
+            rate: rate,
+            seed: AtomicU64::new(if seed == 0 { 0x9e3779b97f4a7c15 } else { seed }),
+        }
+    }
+    pub fn drops(&self) -> bool {
+        let mut x = self.seed.load(Ordering::SeqCst);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.seed.store(x, Ordering::SeqCst);
+        ((x >> 11) as f64 / (1u64 << 53) as f64) < self.rate
+    }
+}
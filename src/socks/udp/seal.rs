@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "udp-seal")]
+use chacha20poly1305::{aead::Aead, aead::Payload, ChaCha20Poly1305, KeyInit, Nonce};
+
+use crate::socks::msg::entity;
+
+/*
+  PACKET_VER_SEAL: 0x48 0x42 0x03 + salt(8) + counter(8) + 密文 + tag(16)
+  密文为完整的明文包(含包头/token/CRC), 前19字节作为 AAD.
+  每个发送端启动时随机 salt, 密钥 = HKDF-SHA256(psk, salt),
+  nonce = 0(4) + counter(8), counter 单调递增, 接收端按 (来源, salt) 做滑动窗口防重放.
+*/
+pub const SEAL_HEAD_LEN: usize = 19;
+pub const SEAL_TAG_LEN: usize = 16;
+const SEAL_INFO: &[u8] = b"hbtp udp seal v1";
+const REPLAY_WINDOW: u64 = 64;
+
+pub fn is_sealed(buf: &[u8]) -> bool {
+    buf.len() >= 3 && buf[0] == 0x48 && buf[1] == 0x42 && buf[2] == entity::PACKET_VER_SEAL
+}
+
+//滑动窗口: max 为已收到的最大 counter, bits 第 i 位表示 max-i 已收到
+pub struct ReplayWindow {
+    max: u64,
+    bits: u64,
+}
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self { max: 0, bits: 0 }
+    }
+    pub fn check(&self, n: u64) -> bool {
+        if n == 0 {
+            return false;
+        }
+        if n > self.max {
+            return true;
+        }
+        let diff = self.max - n;
+        diff < REPLAY_WINDOW && self.bits & (1u64 << diff) == 0
+    }
+    //需在认证通过后调用
+    pub fn update(&mut self, n: u64) {
+        if n > self.max {
+            let diff = n - self.max;
+            self.bits = if diff >= REPLAY_WINDOW {
+                0
+            } else {
+                self.bits << diff
+            };
+            self.bits |= 1;
+            self.max = n;
+        } else {
+            self.bits |= 1u64 << (self.max - n);
+        }
+    }
+}
+
+struct PeerKey {
+    key: [u8; 32],
+    window: ReplayWindow,
+    uptm: Instant,
+}
+
+pub struct UdpSeal {
+    psk: Vec<u8>,
+    salt: [u8; 8],
+    key: [u8; 32],
+    counter: AtomicU64,
+    peers: std::sync::Mutex<HashMap<(SocketAddr, [u8; 8]), PeerKey>>,
+}
+impl UdpSeal {
+    pub fn new(psk: &[u8]) -> io::Result<Self> {
+        if psk.len() < 16 {
+            return Err(ruisutil::ioerr(
+                "psk len must >= 16",
+                Some(io::ErrorKind::InvalidInput),
+            ));
+        }
+        let salt = new_salt()?;
+        Ok(Self {
+            psk: psk.to_vec(),
+            salt: salt,
+            key: derive(psk, &salt)?,
+            counter: AtomicU64::new(1),
+            peers: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn seal(&self, pckt: &[u8]) -> io::Result<Vec<u8>> {
+        let n = self.counter.fetch_add(1, Ordering::SeqCst);
+        let mut head = Vec::with_capacity(SEAL_HEAD_LEN + pckt.len() + SEAL_TAG_LEN);
+        head.extend_from_slice(&[0x48, 0x42, entity::PACKET_VER_SEAL]);
+        head.extend_from_slice(&self.salt);
+        head.extend_from_slice(&n.to_be_bytes());
+        let ct = encrypt(&self.key, n, &head[..], pckt)?;
+        head.extend(ct);
+        Ok(head)
+    }
+
+    //解密并校验重放, 返回明文包
+    pub fn open(&self, src: &SocketAddr, buf: &[u8], max_peers: usize) -> io::Result<Vec<u8>> {
+        if !is_sealed(buf) || buf.len() < SEAL_HEAD_LEN + SEAL_TAG_LEN {
+            return Err(ruisutil::ioerr(
+                "packet not sealed",
+                Some(io::ErrorKind::InvalidData),
+            ));
+        }
+        let mut salt = [0u8; 8];
+        salt.copy_from_slice(&buf[3..11]);
+        let mut nbs = [0u8; 8];
+        nbs.copy_from_slice(&buf[11..19]);
+        let n = u64::from_be_bytes(nbs);
+
+        let k = (src.clone(), salt);
+        {
+            let mut lkv = match self.peers.lock() {
+                Ok(v) => v,
+                Err(_) => return Err(ruisutil::ioerr("seal peers lock err", None)),
+            };
+            if let Some(peer) = lkv.get_mut(&k) {
+                if !peer.window.check(n) {
+                    return Err(ruisutil::ioerr(
+                        format!("packet replay:{}", n),
+                        Some(io::ErrorKind::InvalidData),
+                    ));
+                }
+                let rt = decrypt(&peer.key, n, &buf[..SEAL_HEAD_LEN], &buf[SEAL_HEAD_LEN..])?;
+                //认证通过后才更新窗口
+                peer.window.update(n);
+                peer.uptm = Instant::now();
+                return Ok(rt);
+            }
+        }
+        //新的 (来源, salt): 先在本地派生密钥并解密, 认证通过才记录, 伪造包不占用 peers
+        let key = derive(&self.psk[..], &salt)?;
+        let mut window = ReplayWindow::new();
+        if !window.check(n) {
+            return Err(ruisutil::ioerr(
+                format!("packet replay:{}", n),
+                Some(io::ErrorKind::InvalidData),
+            ));
+        }
+        let rt = decrypt(&key, n, &buf[..SEAL_HEAD_LEN], &buf[SEAL_HEAD_LEN..])?;
+        window.update(n);
+        let mut lkv = match self.peers.lock() {
+            Ok(v) => v,
+            Err(_) => return Err(ruisutil::ioerr("seal peers lock err", None)),
+        };
+        match lkv.get_mut(&k) {
+            //并发收到同一来源的包, 合并窗口
+            Some(peer) => {
+                if !peer.window.check(n) {
+                    return Err(ruisutil::ioerr(
+                        format!("packet replay:{}", n),
+                        Some(io::ErrorKind::InvalidData),
+                    ));
+                }
+                peer.window.update(n);
+                peer.uptm = Instant::now();
+            }
+            None => {
+                if lkv.len() >= max_peers {
+                    return Err(ruisutil::ioerr("seal peers out limit!!", None));
+                }
+                lkv.insert(
+                    k,
+                    PeerKey {
+                        key: key,
+                        window: window,
+                        uptm: Instant::now(),
+                    },
+                );
+            }
+        }
+        Ok(rt)
+    }
+
+    pub fn clean(&self, idle: Duration) {
+        if let Ok(mut lkv) = self.peers.lock() {
+            lkv.retain(|_, v| v.uptm.elapsed() < idle);
+        }
+    }
+}
+
+fn nonce(n: u64) -> [u8; 12] {
+    let mut rt = [0u8; 12];
+    rt[4..].copy_from_slice(&n.to_be_bytes());
+    rt
+}
+
+#[cfg(feature = "udp-seal")]
+fn new_salt() -> io::Result<[u8; 8]> {
+    let mut rt = [0u8; 8];
+    if let Err(e) = getrandom::getrandom(&mut rt) {
+        return Err(ruisutil::ioerr(format!("salt random err:{}", e), None));
+    }
+    Ok(rt)
+}
+#[cfg(feature = "udp-seal")]
+fn derive(psk: &[u8], salt: &[u8; 8]) -> io::Result<[u8; 32]> {
+    let mut rt = [0u8; 32];
+    let hk = hkdf::Hkdf::<sha2::Sha256>::new(Some(&salt[..]), psk);
+    if hk.expand(SEAL_INFO, &mut rt).is_err() {
+        return Err(ruisutil::ioerr("seal key derive err", None));
+    }
+    Ok(rt)
+}
+#[cfg(feature = "udp-seal")]
+fn encrypt(key: &[u8; 32], n: u64, aad: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let nc = nonce(n);
+    match cipher.encrypt(Nonce::from_slice(&nc), Payload { msg: data, aad: aad }) {
+        Ok(v) => Ok(v),
+        Err(_) => Err(ruisutil::ioerr("packet seal err", None)),
+    }
+}
+#[cfg(feature = "udp-seal")]
+fn decrypt(key: &[u8; 32], n: u64, aad: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let nc = nonce(n);
+    match cipher.decrypt(Nonce::from_slice(&nc), Payload { msg: data, aad: aad }) {
+        Ok(v) => Ok(v),
+        Err(_) => Err(ruisutil::ioerr(
+            "packet auth err",
+            Some(io::ErrorKind::InvalidData),
+        )),
+    }
+}
+
+#[cfg(not(feature = "udp-seal"))]
+fn unsupported() -> io::Error {
+    ruisutil::ioerr(
+        "udp seal not supported, enable feature udp-seal",
+        Some(io::ErrorKind::Unsupported),
+    )
+}
+#[cfg(not(feature = "udp-seal"))]
+fn new_salt() -> io::Result<[u8; 8]> {
+    Err(unsupported())
+}
+#[cfg(not(feature = "udp-seal"))]
+fn derive(_: &[u8], _: &[u8; 8]) -> io::Result<[u8; 32]> {
+    Err(unsupported())
+}
+#[cfg(not(feature = "udp-seal"))]
+fn encrypt(_: &[u8; 32], _: u64, _: &[u8], _: &[u8]) -> io::Result<Vec<u8>> {
+    let _ = nonce(0);
+    Err(unsupported())
+}
+#[cfg(not(feature = "udp-seal"))]
+fn decrypt(_: &[u8; 32], _: u64, _: &[u8], _: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
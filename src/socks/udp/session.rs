@@ -0,0 +1,133 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ruisutil::bytes::{self, BytesCut};
+
+use crate::socks::msg::Compress;
+
+/*
+  1: OPEN   客户端请求建立会话 nonce(8) + 支持的压缩算法
+            相同 nonce 重复发送返回同一个 id, nonce 变化(客户端重启)时重建会话
+  2: ACK    服务端返回会话 id(8) + 支持的压缩算法
+  3: CLOSE  任意一方关闭会话 id(8)
+  压缩算法: n(1) + n*flags(1), 旧版本没有此字段按不支持压缩处理
+  建立会话后数据包带 PACKET_FLAG_SESS 和会话 id, 与当前会话不一致的包丢弃
+*/
+pub const CTRL_OPEN: u16 = 1;
+pub const CTRL_ACK: u16 = 2;
+pub const CTRL_CLOSE: u16 = 3;
+
+pub fn is_ctrl(ctrl: u16) -> bool {
+    ctrl == CTRL_OPEN || ctrl == CTRL_ACK || ctrl == CTRL_CLOSE
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SessionClose {
+    //对端发送 CLOSE
+    Peer,
+    //本地调用 close_session
+    Local,
+    //同一地址以新的 nonce 重新打开, 旧会话的状态被清理
+    Replaced,
+    //空闲超时
+    Idle,
+}
+
+#[derive(Clone, Debug)]
+pub struct UdpSession {
+    pub id: u64,
+    pub addrs: SocketAddr,
+    pub created: Instant,
+    pub uptm: Instant,
+    //对端支持的压缩算法
+    pub cmps: Vec<Compress>,
+    pub(crate) nonce: u64,
+}
+impl UdpSession {
+    pub(crate) fn new(addrs: SocketAddr) -> Self {
+        let now = Instant::now();
+        Self {
+            id: new_id(),
+            addrs: addrs,
+            created: now,
+            uptm: now,
+            cmps: Vec::new(),
+            nonce: 0,
+        }
+    }
+    pub fn idle(&self) -> Duration {
+        self.uptm.elapsed()
+    }
+}
+
+static SESS_SEQ: AtomicU64 = AtomicU64::new(1);
+fn new_id() -> u64 {
+    let tms = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(v) => v.as_nanos() as u64,
+        Err(_) => 0,
+    };
+    let seq = SESS_SEQ.fetch_add(1, Ordering::SeqCst);
+    let id = (tms << 16) ^ seq;
+    if id == 0 {
+        seq
+    } else {
+        id
+    }
+}
+
+pub fn id_parse(mut buf: bytes::Bytes) -> io::Result<u64> {
+    let bts = buf.cuts(8)?;
+    Ok(ruisutil::byte_2i(&bts[..]) as u64)
+}
+pub fn id_fmts(id: u64) -> Vec<u8> {
+    ruisutil::i2_byte(id as i64, 8)
+}
+
+//OPEN: nonce(8) + 压缩算法, 旧版本没有 nonce 时为 0
+pub fn open_parse(buf: &[u8]) -> (u64, Vec<Compress>) {
+    if buf.len() < 8 {
+        return (0, Vec::new());
+    }
+    (ruisutil::byte_2i(&buf[..8]) as u64, cmps_parse(&buf[8..]))
+}
+pub fn open_fmts(nonce: u64, cmps: &[Compress]) -> Vec<u8> {
+    let mut rt = ruisutil::i2_byte(nonce as i64, 8);
+    rt.extend(cmps_fmts(cmps));
+    rt
+}
+//兼容旧版本: 字段缺失或不完整时只取已读到的部分
+pub fn cmps_parse(buf: &[u8]) -> Vec<Compress> {
+    let mut rt = Vec::new();
+    if buf.len() < 1 {
+        return rt;
+    }
+    let n = buf[0] as usize;
+    for it in buf[1..].iter().take(n) {
+        if let Ok(v) = Compress::from_flags(*it) {
+            if v != Compress::None && !rt.contains(&v) {
+                rt.push(v);
+            }
+        }
+    }
+    rt
+}
+pub fn cmps_fmts(ls: &[Compress]) -> Vec<u8> {
+    let mut rt = Vec::with_capacity(ls.len() + 1);
+    rt.push(ls.len() as u8);
+    for it in ls {
+        rt.push(it.flags());
+    }
+    rt
+}
+//发送方设置的算法对端支持时才压缩
+pub fn cmp_for(cmp: Compress, peers: &[Compress]) -> Compress {
+    if cmp != Compress::None && cmp.is_supported() && peers.contains(&cmp) {
+        cmp
+    } else {
+        Compress::None
+    }
+}
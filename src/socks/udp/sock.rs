@@ -0,0 +1,156 @@
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+};
+
+use ruisutil::asyncs::net::UdpSocket;
+use ruisutil::bytes;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::udps::UdpConfig;
+
+//按配置设置收发缓冲区后绑定
+pub async fn bind(addrs: &str, cfg: &UdpConfig) -> io::Result<UdpSocket> {
+    if cfg.rcvbuf <= 0 && cfg.sndbuf <= 0 {
+        return UdpSocket::bind(addrs).await;
+    }
+    let addr = match addrs.to_socket_addrs()?.next() {
+        None => return Err(ruisutil::ioerr(format!("addr err:{}", addrs), None)),
+        Some(v) => v,
+    };
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if cfg.rcvbuf > 0 {
+        sock.set_recv_buffer_size(cfg.rcvbuf)?;
+    }
+    if cfg.sndbuf > 0 {
+        sock.set_send_buffer_size(cfg.sndbuf)?;
+    }
+    sock.set_nonblocking(true)?;
+    sock.bind(&addr.into())?;
+    let conn: std::net::UdpSocket = sock.into();
+    #[cfg(feature = "tokios")]
+    return UdpSocket::from_std(conn);
+    #[cfg(not(feature = "tokios"))]
+    return Ok(UdpSocket::from(conn));
+}
+
+//非阻塞地继续读取已到达的包(recvmmsg), 无数据返回空
+#[cfg(all(target_os = "linux", feature = "udp-mmsg"))]
+pub fn recv_batch(conn: &UdpSocket, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+    use std::{mem, os::unix::io::AsRawFd, ptr};
+    let n = bufs.len();
+    if n <= 0 {
+        return Ok(Vec::new());
+    }
+    let mut iovs: Vec<libc::iovec> = Vec::with_capacity(n);
+    for it in bufs.iter_mut() {
+        iovs.push(libc::iovec {
+            iov_base: it.as_mut_ptr() as *mut libc::c_void,
+            iov_len: it.len(),
+        });
+    }
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; n];
+    let mut hdrs: Vec<libc::mmsghdr> = Vec::with_capacity(n);
+    for i in 0..n {
+        let mut hd: libc::mmsghdr = unsafe { mem::zeroed() };
+        hd.msg_hdr.msg_name = &mut addrs[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+        hd.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hd.msg_hdr.msg_iov = &mut iovs[i] as *mut libc::iovec;
+        hd.msg_hdr.msg_iovlen = 1;
+        hdrs.push(hd);
+    }
+    let rc = unsafe {
+        libc::recvmmsg(
+            conn.as_raw_fd(),
+            hdrs.as_mut_ptr(),
+            n as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if rc < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::WouldBlock {
+            return Ok(Vec::new());
+        }
+        return Err(e);
+    }
+    let mut rt = Vec::with_capacity(rc as usize);
+    for i in 0..rc as usize {
+        let addr = unsafe { socket2::SockAddr::new(addrs[i], hdrs[i].msg_hdr.msg_namelen) };
+        if let Some(src) = addr.as_socket() {
+            rt.push((hdrs[i].msg_len as usize, src));
+        }
+    }
+    Ok(rt)
+}
+#[cfg(not(all(target_os = "linux", feature = "udp-mmsg")))]
+pub fn recv_batch(_: &UdpSocket, _: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+    Ok(Vec::new())
+}
+
+//批量发送(sendmmsg), 返回已发送数量
+#[cfg(all(target_os = "linux", feature = "udp-mmsg"))]
+fn send_mmsg(conn: &UdpSocket, pkts: &[bytes::Bytes], dist: Option<&SocketAddr>) -> io::Result<usize> {
+    use std::{mem, os::unix::io::AsRawFd, ptr};
+    let n = pkts.len();
+    let addr = dist.map(|v| socket2::SockAddr::from(v.clone()));
+    let mut iovs: Vec<libc::iovec> = Vec::with_capacity(n);
+    for it in pkts {
+        iovs.push(libc::iovec {
+            iov_base: it[..].as_ptr() as *mut libc::c_void,
+            iov_len: it.len(),
+        });
+    }
+    let mut hdrs: Vec<libc::mmsghdr> = Vec::with_capacity(n);
+    for i in 0..n {
+        let mut hd: libc::mmsghdr = unsafe { mem::zeroed() };
+        if let Some(v) = &addr {
+            hd.msg_hdr.msg_name = v.as_ptr() as *mut libc::c_void;
+            hd.msg_hdr.msg_namelen = v.len();
+        } else {
+            hd.msg_hdr.msg_name = ptr::null_mut();
+        }
+        hd.msg_hdr.msg_iov = &mut iovs[i] as *mut libc::iovec;
+        hd.msg_hdr.msg_iovlen = 1;
+        hdrs.push(hd);
+    }
+    let rc = unsafe {
+        libc::sendmmsg(
+            conn.as_raw_fd(),
+            hdrs.as_mut_ptr(),
+            n as libc::c_uint,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if rc < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::WouldBlock {
+            return Ok(0);
+        }
+        return Err(e);
+    }
+    Ok(rc as usize)
+}
+
+pub async fn send_batch(
+    conn: &UdpSocket,
+    pkts: &[bytes::Bytes],
+    dist: Option<&SocketAddr>,
+) -> io::Result<()> {
+    #[allow(unused_mut)]
+    let mut sent = 0;
+    #[cfg(all(target_os = "linux", feature = "udp-mmsg"))]
+    if pkts.len() > 1 {
+        sent = send_mmsg(conn, pkts, dist)?;
+    }
+    // 未批量发出的(缓冲区满或非 linux)逐个等待发送
+    for it in &pkts[sent..] {
+        if let Some(addr) = dist {
+            conn.send_to(&it[..], addr).await?;
+        } else {
+            conn.send(&it[..]).await?;
+        }
+    }
+    Ok(())
+}
@@ -1,15 +1,33 @@
-use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 
 use ruisutil::asyncs::BoxFuture;
 use ruisutil::asyncs::{
+    make_channel,
     net::{ToSocketAddrs, UdpSocket},
-    sync::RwLock,
+    sync::Mutex,
+    Receiver, Sender,
 };
-use ruisutil::bytes;
+use ruisutil::bytes::{self, BytesCut};
 
 use crate::socks::msg;
 
-use super::udps::UdpMsgParse;
+use super::{
+    call,
+    limit::{DropReason, DropStats, Limiter},
+    nat::{self, NatLink, NatState},
+    reliable::{self, LossSim, RelReceiver, RelSender},
+    seal::UdpSeal,
+    sock,
+    session::{self, SessionClose, UdpSession},
+    udps::{self, FragHead, UdpConfig, UdpMsgParse},
+};
 // #[cfg(unix)]
 // use std::os::unix::prelude::*;
 
@@ -22,9 +40,35 @@ struct Inner {
     addrs: String,
     conn: Option<UdpSocket>,
     shuted: bool,
+    //解析任务队列, 按来源地址分配, 同一来源的包按到达顺序处理
+    works: Vec<Sender<(bytes::Bytes, SocketAddr)>>,
+    inflight: AtomicUsize,
+    limit: Limiter,
 
-    mutis: RwLock<HashMap<SocketAddr, UdpMsgParse>>,
+    cfg: UdpConfig,
+    mutis: Mutex<HashMap<SocketAddr, UdpMsgParse>>,
+    msgid: AtomicU32,
     recver: Box<dyn IUMsgerServ + Send + Sync>,
+
+    opts: msg::MsgOpts,
+    //ack/nack 等控制包使用的 token
+    ctrl_tks: Option<String>,
+    loss: Option<LossSim>,
+    sealer: Option<UdpSeal>,
+    pmtus: Mutex<HashMap<SocketAddr, usize>>,
+
+    rel_snds: Mutex<HashMap<SocketAddr, RelSender>>,
+    rel_rcvs: Mutex<HashMap<SocketAddr, RelReceiver>>,
+
+    sessions: Mutex<HashMap<SocketAddr, UdpSession>>,
+
+    //发出的 call 等待应答, id 独立于分片消息 id
+    callid: AtomicU32,
+    calls: Mutex<HashMap<(SocketAddr, u32), Sender<io::Result<msg::Messageu>>>>,
+    //收到的 call 应答缓存, None 为处理中
+    call_rets: Mutex<HashMap<(SocketAddr, u32), (Instant, Option<bytes::Bytes>)>>,
+
+    nat: Mutex<NatState>,
 }
 
 impl UMsgerServ {
@@ -38,13 +82,137 @@ impl UMsgerServ {
                 addrs: addrs,
                 conn: None,
                 shuted: false,
+                works: Vec::new(),
+                inflight: AtomicUsize::new(0),
+                limit: Limiter::new(),
 
-                mutis: RwLock::new(HashMap::new()),
+                cfg: UdpConfig::default(),
+                mutis: Mutex::new(HashMap::new()),
+                msgid: AtomicU32::new(1),
                 recver: Box::new(recver),
+
+                opts: msg::MsgOpts::default(),
+                ctrl_tks: None,
+                loss: None,
+                sealer: None,
+                pmtus: Mutex::new(HashMap::new()),
+
+                rel_snds: Mutex::new(HashMap::new()),
+                rel_rcvs: Mutex::new(HashMap::new()),
+
+                sessions: Mutex::new(HashMap::new()),
+
+                callid: AtomicU32::new(1),
+                calls: Mutex::new(HashMap::new()),
+                call_rets: Mutex::new(HashMap::new()),
+
+                nat: Mutex::new(NatState::default()),
             }),
         }
     }
 
+    pub fn set_config(&self, cfg: UdpConfig) {
+        unsafe { self.inner.muts().cfg = cfg };
+    }
+    pub fn set_ctrl_token(&self, tks: Option<String>) {
+        unsafe { self.inner.muts().ctrl_tks = tks };
+    }
+    //测试用: 按概率丢弃发出的包
+    pub fn set_loss_sim(&self, loss: Option<LossSim>) {
+        unsafe { self.inner.muts().loss = loss };
+    }
+    //设置 psk 后收发均为加密包, 未加密的包直接丢弃; 需启用 udp-seal
+    pub fn set_psk(&self, psk: Option<&[u8]>) -> io::Result<()> {
+        let sealer = match psk {
+            None => None,
+            Some(v) => Some(UdpSeal::new(v)?),
+        };
+        unsafe { self.inner.muts().sealer = sealer };
+        Ok(())
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.inner.conn {
+            Some(v) => v.local_addr(),
+            None => Err(ruisutil::ioerr("conn not run", None)),
+        }
+    }
+    //body 大于 min 且对端会话支持 cmp 时压缩, 接收端自动解压
+    pub fn set_compress(&self, cmp: msg::Compress, min: usize) {
+        let ins = unsafe { self.inner.muts() };
+        ins.opts.cmp = cmp;
+        ins.opts.cmp_min = min;
+    }
+    //仅当对端会话声明支持时才按 set_compress 压缩, 无会话不压缩
+    async fn peer_cmp(&self, dist: Option<&SocketAddr>) -> msg::Compress {
+        if self.inner.opts.cmp == msg::Compress::None {
+            return msg::Compress::None;
+        }
+        let dist = match dist {
+            None => return msg::Compress::None,
+            Some(v) => v,
+        };
+        let lkv = self.inner.sessions.lock().await;
+        match lkv.get(dist) {
+            None => msg::Compress::None,
+            Some(v) => session::cmp_for(self.inner.opts.cmp, &v.cmps),
+        }
+    }
+    //发送 PACKET_VER_CRC 版本的包
+    pub fn set_checksum(&self, crc: bool) {
+        unsafe { self.inner.muts().opts.crc = crc };
+    }
+    //对端路径 MTU, None 恢复使用 cfg.mtu
+    pub async fn set_path_mtu(&self, dist: &SocketAddr, mtu: Option<usize>) {
+        let mut lkv = self.inner.pmtus.lock().await;
+        match mtu {
+            None => lkv.remove(dist),
+            Some(v) => lkv.insert(dist.clone(), v),
+        };
+    }
+    //包头/token/会话/校验/加密 的开销
+    fn overhead(&self, tks: &Option<String>, sess: bool) -> usize {
+        udps::packet_overhead(tks, sess, self.inner.opts.crc, self.inner.sealer.is_some())
+    }
+    //解压后的 body 不超过重组上限
+    fn msg_parse(&self, data: bytes::Bytes) -> io::Result<msg::Messageu> {
+        msg::udps::msg_parse_max(data, self.inner.cfg.frag_max_bytes as u64)
+    }
+    //发往 dist 的单包数据大小
+    pub async fn payload_size(&self, tks: &Option<String>, dist: Option<&SocketAddr>) -> usize {
+        let (mtu, v6) = match dist {
+            Some(v) => {
+                let lkv = self.inner.pmtus.lock().await;
+                (lkv.get(v).cloned(), v.is_ipv6())
+            }
+            None => match &self.inner.conn {
+                Some(conn) => match conn.peer_addr() {
+                    Ok(v) => (self.inner.pmtus.lock().await.get(&v).cloned(), v.is_ipv6()),
+                    Err(_) => (None, false),
+                },
+                None => (None, false),
+            },
+        };
+        let sess = self.sess_id(dist).await.is_some();
+        self.inner.cfg.payload_size(mtu, v6, self.overhead(tks, sess))
+    }
+    fn packet_head(&self, ctrl: u16, tks: &Option<String>) -> io::Result<bytes::ByteBoxBuf> {
+        udps::packet_head(self.inner.opts.crc, ctrl, tks, None)
+    }
+    //数据包带上 dist 当前会话的 id
+    fn packet_head_sess(
+        &self,
+        ctrl: u16,
+        tks: &Option<String>,
+        sess: Option<u64>,
+    ) -> io::Result<bytes::ByteBoxBuf> {
+        udps::packet_head(self.inner.opts.crc, ctrl, tks, sess)
+    }
+    async fn sess_id(&self, dist: Option<&SocketAddr>) -> Option<u64> {
+        let dist = dist?;
+        let lkv = self.inner.sessions.lock().await;
+        lkv.get(dist).map(|v| v.id)
+    }
+
     pub fn stop(&self) {
         if !self.inner.shuted {
             println!("udp_msger conn will stop");
@@ -57,6 +225,7 @@ impl UMsgerServ {
                 std::mem::drop(v);
             } */
             ins.conn = None;
+            ins.works.clear();
         }
     }
 
@@ -92,18 +261,104 @@ impl UMsgerServ {
     }
     pub async fn run(&self) -> io::Result<()> {
         let ins = unsafe { self.inner.muts() };
-        let conn = UdpSocket::bind(self.inner.addrs.as_str()).await?;
+        let conn = sock::bind(self.inner.addrs.as_str(), &self.inner.cfg).await?;
         ins.conn = Some(conn);
+        let mut works = Vec::new();
+        for _ in 0..self.inner.cfg.recv_workers.max(1) {
+            let (tx, rx) = make_channel(self.inner.cfg.recv_queue.max(1));
+            works.push(tx);
+            let c = self.clone();
+            ruisutil::asyncs::task::spawn(async move {
+                c.run_work(rx).await;
+            });
+        }
+        ins.works = works;
+        let c = self.clone();
+        ruisutil::asyncs::task::spawn(async move {
+            c.run_check().await;
+        });
+        let c = self.clone();
+        ruisutil::asyncs::task::spawn(async move {
+            c.run_reliable().await;
+        });
         self.run_recv().await;
         self.stop();
         println!("udp_msger end run check");
         Ok(())
     }
+    async fn run_check(&self) {
+        while !self.inner.ctx.done() {
+            ruisutil::asyncs::sleep(Duration::from_millis(500)).await;
+            let mut lkv = self.inner.mutis.lock().await;
+            lkv.retain(|_, v| {
+                v.clean(&self.inner.cfg);
+                !v.is_empty() || v.idle() < self.inner.cfg.frag_timeout
+            });
+            std::mem::drop(lkv);
+            let mut lkv = self.inner.rel_rcvs.lock().await;
+            lkv.retain(|_, v| v.idle() < self.inner.cfg.rel_idle);
+            std::mem::drop(lkv);
+            // 发送端同样按空闲清理, 重建后以新 epoch 通知对端重置
+            let mut lkv = self.inner.rel_snds.lock().await;
+            lkv.retain(|_, v| v.idle() < self.inner.cfg.rel_idle);
+            std::mem::drop(lkv);
+            if let Some(v) = &self.inner.sealer {
+                v.clean(self.inner.cfg.sess_idle);
+            }
+            self.inner.limit.clean(&self.inner.cfg);
+            let mut lkv = self.inner.call_rets.lock().await;
+            lkv.retain(|_, v| v.0.elapsed() < self.inner.cfg.call_cache);
+            std::mem::drop(lkv);
+            self.check_sessions().await;
+            self.check_nat().await;
+        }
+    }
+    async fn run_reliable(&self) {
+        while !self.inner.ctx.done() {
+            ruisutil::asyncs::sleep(Duration::from_millis(10)).await;
+            let now = Instant::now();
+            let mut sends = Vec::new();
+            {
+                let mut lkv = self.inner.rel_snds.lock().await;
+                let mut rms = Vec::new();
+                for (k, v) in lkv.iter_mut() {
+                    match v.tick(&self.inner.cfg, now) {
+                        Err(e) => {
+                            println!("reliable send to {} err:{}", k, e);
+                            rms.push(k.clone());
+                        }
+                        Ok(ls) => {
+                            for it in ls {
+                                sends.push((k.clone(), it));
+                            }
+                            for it in v.pull_sendable(&self.inner.cfg, now) {
+                                sends.push((k.clone(), it));
+                            }
+                        }
+                    }
+                }
+                for k in &rms {
+                    lkv.remove(k);
+                }
+            }
+            let mut dists: HashMap<SocketAddr, Vec<bytes::Bytes>> = HashMap::new();
+            for (k, v) in sends {
+                dists.entry(k).or_insert_with(Vec::new).push(v);
+            }
+            for (k, v) in &dists {
+                if let Err(e) = self.send_raws(v, Some(k)).await {
+                    println!("reliable send to {} err:{}", k, e);
+                }
+            }
+        }
+    }
     async fn run_recv(&self) {
+        // 接收缓冲区循环复用, 多读1字节用于判断超长包
+        let ln = self.inner.cfg.dgram_max + 1;
+        let mut bufs = vec![vec![0u8; ln]; self.inner.cfg.recv_batch.max(1)];
         while !self.inner.ctx.done() {
             if let Some(conn) = &self.inner.conn {
-                let mut buf = vec![0u8; 1500];
-                match conn.recv_from(&mut buf[..]).await {
+                match conn.recv_from(&mut bufs[0][..]).await {
                     Err(e) => {
                         println!("udp_msger recv err:{}", e);
                         // self.stop();
@@ -111,143 +366,329 @@ impl UMsgerServ {
                     }
                     Ok((n, src)) => {
                         // println!("udp_msger recv_from({}):{}", src.to_string().as_str(), n);
-                        if n <= 1472 {
-                            let c = self.clone();
-                            ruisutil::asyncs::task::spawn(async move {
-                                let bts = bytes::bytes_with_len(buf, n);
-                                if let Err(e) = c.run_parse(bts, src.clone()).await {
-                                    println!("run_parse from {} err:{}", src.to_string(), e);
+                        self.dispatch(&bufs[0][..n], src).await;
+                        if bufs.len() > 1 {
+                            match sock::recv_batch(conn, &mut bufs[1..]) {
+                                Err(e) => println!("udp_msger recv batch err:{}", e),
+                                Ok(ls) => {
+                                    for (i, (n, src)) in ls.into_iter().enumerate() {
+                                        let n = n.min(ln);
+                                        self.dispatch(&bufs[i + 1][..n], src).await;
+                                    }
                                 }
-                            });
-                        } else {
-                            self.inner.recver.packet_err(&src).await;
+                            }
                         }
                     }
                 }
+            } else {
+                break;
             }
         }
     }
+    async fn dispatch(&self, buf: &[u8], src: SocketAddr) {
+        if buf.len() > self.inner.cfg.dgram_max {
+            self.inner.recver.packet_err(&src).await;
+            return;
+        }
+        if self.inner.works.is_empty() {
+            return;
+        }
+        if let Some(v) = self.inner.limit.check(&self.inner.cfg, &src, buf.len()) {
+            return self.drops(&src, v).await;
+        }
+        let max = self.inner.cfg.max_inflight;
+        if max > 0 && self.inner.inflight.load(Ordering::SeqCst) >= max {
+            return self.drops(&src, DropReason::Inflight).await;
+        }
+        let mut hs = DefaultHasher::new();
+        src.hash(&mut hs);
+        let i = (hs.finish() as usize) % self.inner.works.len();
+        let bts = bytes::Bytes::from(buf.to_vec());
+        self.inner.inflight.fetch_add(1, Ordering::SeqCst);
+        if let Err(_) = self.inner.works[i].try_send((bts, src.clone())) {
+            self.inner.inflight.fetch_sub(1, Ordering::SeqCst);
+            self.drops(&src, DropReason::Queue).await;
+        }
+    }
+    async fn drops(&self, src: &SocketAddr, reason: DropReason) {
+        let n = self.inner.limit.count(reason);
+        if self.inner.limit.notify(reason) {
+            self.inner.recver.on_drop(src, reason, n).await;
+        }
+    }
+    async fn run_work(&self, rx: Receiver<(bytes::Bytes, SocketAddr)>) {
+        let mut rx = rx;
+        while let Ok((bts, src)) = ruisutil::asyncs::channel_recv(&mut rx).await {
+            if let Err(e) = self.run_parse(bts, src.clone()).await {
+                println!("run_parse from {} err:{}", src.to_string(), e);
+            }
+            self.inner.inflight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    //解析队列满丢弃的包数
+    pub fn recv_dropped(&self) -> u64 {
+        self.inner.limit.stats().queue
+    }
+    pub fn drop_stats(&self) -> DropStats {
+        self.inner.limit.stats()
+    }
+    //手动封禁来源, 期间收到的包直接丢弃
+    pub fn ban(&self, src: &SocketAddr, tm: Duration) {
+        self.inner.limit.ban(src, tm);
+    }
+    pub fn unban(&self, src: &SocketAddr) -> bool {
+        self.inner.limit.unban(src)
+    }
+    pub fn bans(&self) -> Vec<(SocketAddr, Duration)> {
+        self.inner.limit.bans()
+    }
     async fn run_parse(&self, buf: bytes::Bytes, src: SocketAddr) -> io::Result<()> {
         /* if buf.len() < 10 {
             return Err(ruisutil::ioerr(format!("packet len err:{}",buf.len()), None));
         } */
+        let buf = match &self.inner.sealer {
+            None => buf,
+            Some(v) => {
+                let bts = v.open(&src, &buf[..], self.inner.cfg.frag_max_peers)?;
+                bytes::Bytes::from(bts)
+            }
+        };
         let pckt = msg::udps::packet_parse(buf)?;
         if !self.inner.recver.check_token(&src, &pckt.token).await {
+            self.inner.limit.strike(&self.inner.cfg, &src);
+            self.drops(&src, DropReason::Token).await;
             return Err(ruisutil::ioerr("packet token err!!!", None));
         }
+        let opened = match self.session_touch(&src, pckt.sess).await {
+            Err(e) if !session::is_ctrl(pckt.ctrl) => return Err(e),
+            Err(_) => true,
+            Ok(v) => v,
+        };
+        if !opened && self.inner.cfg.sess_required && !session::is_ctrl(pckt.ctrl) {
+            return Err(ruisutil::ioerr("session not open!!", None));
+        }
 
         // println!("parse packet ctrl:{}", pckt.ctrl);
         /* print!("datas:");
         ruisutil::print_hex(&pckt.data[..]);
         println!(";"); */
         /*
+          1-10: 会话控制
           10-20: 无需按顺序(包小于1400)
           20-30: 需要重组,无需按顺序(包可大于1400)
           30-40: 需要重组,需要按顺序(包可大于1400)
         */
         match pckt.ctrl {
+            session::CTRL_OPEN => {
+                self.session_open(&src, &pckt.token, &pckt.data[..]).await?;
+            }
+            session::CTRL_CLOSE => {
+                let id = session::id_parse(pckt.data.clone())?;
+                self.session_close(&src, Some(id), SessionClose::Peer).await?;
+            }
             11 => {
                 self.inner.recver.on_bts(&src, pckt.data.clone()).await?;
             }
             12 => {
-                let m = msg::udps::msg_parse(pckt.data.clone())?;
+                let m = self.msg_parse(pckt.data.clone())?;
                 self.inner.recver.on_msg(&src, m).await?;
             }
-            _ => {}
-        }
-        /*
-        let bts = buf.cuts(3)?;
-        if bts[0] != 0x48 || bts[1] != 0x42 {
-            return Err(ruisutil::ioerr(
-                format!("packet start err:[{},{}]", bts[0], bts[1]),
-                None,
-            ));
-        }
-        if bts[2] != 1 {
-            return Err(ruisutil::ioerr(
-                format!("packet version err:[{}]", bts[2]),
-                None,
-            ));
-        }
-        let bts = buf.cuts(2)?;
-        let ctrl = bts[0];
-        let id = bts[1];
-        let bts = buf.cuts(2)?;
-        let ind = ruisutil::byte_2i(&bts[..]) as u16;
-        let bts = buf.cuts(2)?;
-        let ln = ruisutil::byte_2i(&bts[..]) as u16;
-        let bts = buf.cuts(2)?;
-        let kn = ruisutil::byte_2i(&bts[..]) as usize;
-        let bts = buf.cuts(kn)?;
-        if let Some(vs) = &self.inner.keys {
-            if !vs.is_empty() {
-                let keys = match std::str::from_utf8(&bts[..]) {
-                    Err(e) => {
-                        return Err(ruisutil::ioerr(format!("packet keys err:[{}]", e), None))
+            21 => {
+                let mut data = pckt.data.clone();
+                let hd = udps::frag_parse(&mut data)?;
+                if let Some(bts) = self.merge(&src, hd, data).await? {
+                    let m = self.msg_parse(bts)?;
+                    self.inner.recver.on_msg(&src, m).await?;
+                }
+            }
+            reliable::CTRL_DATA => {
+                let mut data = pckt.data.clone();
+                let hd = reliable::data_parse(&mut data)?;
+                self.on_reliable(&src, hd, data).await?;
+            }
+            reliable::CTRL_ACK => {
+                let (epoch, cum, sacks) = reliable::ack_parse(pckt.data.clone())?;
+                let now = Instant::now();
+                let sends = {
+                    let mut lkv = self.inner.rel_snds.lock().await;
+                    match lkv.get_mut(&src) {
+                        Some(v) if v.epoch() == epoch => {
+                            v.on_ack(&self.inner.cfg, cum, &sacks[..], now);
+                            v.pull_sendable(&self.inner.cfg, now)
+                        }
+                        _ => Vec::new(),
+                    }
+                };
+                self.send_raws(&sends, Some(&src)).await?;
+            }
+            reliable::CTRL_NACK => {
+                let (epoch, seqs) = reliable::nack_parse(pckt.data.clone())?;
+                let sends = {
+                    let mut lkv = self.inner.rel_snds.lock().await;
+                    match lkv.get_mut(&src) {
+                        Some(v) if v.epoch() == epoch => v.on_nack(&seqs[..], Instant::now()),
+                        _ => Vec::new(),
                     }
-                    Ok(v) => v,
                 };
-                if !vs.eq(keys) {
-                    return Err(ruisutil::ioerr(format!("packet keys err:{}", keys), None));
+                self.send_raws(&sends, Some(&src)).await?;
+            }
+            nat::CTRL_REG..=nat::CTRL_RELAYED => {
+                self.on_nat(pckt.ctrl, &src, pckt.data.clone()).await?;
+            }
+            call::CTRL_CALL => {
+                let mut data = pckt.data.clone();
+                let id = call::id_parse(&mut data)?;
+                self.on_call(&src, id, data).await?;
+            }
+            call::CTRL_REPLY | call::CTRL_ERROR => {
+                let mut data = pckt.data.clone();
+                let id = call::id_parse(&mut data)?;
+                let tx = self.inner.calls.lock().await.remove(&(src.clone(), id));
+                if let Some(tx) = tx {
+                    let rt = if pckt.ctrl == call::CTRL_REPLY {
+                        self.msg_parse(data)
+                    } else {
+                        Err(call::err_parse(data))
+                    };
+                    let _ = tx.try_send(rt);
                 }
             }
+            _ => {}
         }
-        if id == 0 || ln <= 0 || ind >= ln {
-            return Err(ruisutil::ioerr(
-                format!("packet param err:id={},ind={},ln={}", id, ind, ln),
-                None,
-            ));
-        }
-        let mut pn = None;
-        {
-            let lkv = self.inner.mutis.read().await;
-            if let Some(v) = lkv.get(&src) {
-                pn = Some(v.clone());
+
+        Ok(())
+    }
+    async fn on_reliable(
+        &self,
+        src: &SocketAddr,
+        hd: reliable::DataHead,
+        data: bytes::Bytes,
+    ) -> io::Result<()> {
+        let mut lkv = self.inner.rel_rcvs.lock().await;
+        if !lkv.contains_key(src) {
+            if lkv.len() >= self.inner.cfg.frag_max_peers {
+                return Err(ruisutil::ioerr("reliable peers out limit!!", None));
             }
+            lkv.insert(src.clone(), RelReceiver::new());
         }
-        match pn {
-            Some(v) => {
-                v.ctrls(buf, ctrl, id, ind, ln).await;
-            }
-            None => {
-                let mut lkv = self.inner.mutis.write().await;
-                let v = UdpMsgParse::new(&self.inner.ctx, self.clone(), src.clone());
-                v.start().await;
-                v.ctrls(buf, ctrl, id, ind, ln).await;
-                lkv.insert(src, v);
-            }
-        } */
+        let rcv = match lkv.get_mut(src) {
+            None => return Ok(()),
+            Some(v) => v.push(&self.inner.cfg, hd, data)?,
+        };
+        // 同一来源的包由同一解析任务按序处理, 释放状态锁后投递不会乱序
+        std::mem::drop(lkv);
 
+        let mut bts = self.packet_head(reliable::CTRL_ACK, &self.inner.ctrl_tks)?;
+        bts.push(reliable::ack_fmts(rcv.epoch, rcv.cum, &rcv.sacks[..]));
+        self.send_packet(bts, Some(src)).await?;
+        if !rcv.nacks.is_empty() {
+            let mut bts = self.packet_head(reliable::CTRL_NACK, &self.inner.ctrl_tks)?;
+            bts.push(reliable::nack_fmts(rcv.epoch, &rcv.nacks[..]));
+            self.send_packet(bts, Some(src)).await?;
+        }
+        for it in rcv.msgs {
+            match self.msg_parse(it) {
+                Err(e) => println!("reliable msg from {} parse err:{}", src, e),
+                Ok(m) => {
+                    if let Err(e) = self.inner.recver.on_msg(src, m).await {
+                        println!("reliable on_msg from {} err:{}", src, e);
+                    }
+                }
+            }
+        }
         Ok(())
     }
+    async fn merge(
+        &self,
+        src: &SocketAddr,
+        hd: FragHead,
+        data: bytes::Bytes,
+    ) -> io::Result<Option<bytes::Bytes>> {
+        let mut lkv = self.inner.mutis.lock().await;
+        if !lkv.contains_key(src) {
+            if lkv.len() >= self.inner.cfg.frag_max_peers {
+                return Err(ruisutil::ioerr("frag peers out limit!!", None));
+            }
+            lkv.insert(src.clone(), UdpMsgParse::new(src.clone()));
+        }
+        let rt = match lkv.get_mut(src) {
+            None => return Ok(None),
+            Some(v) => v.push(&self.inner.cfg, hd, data)?,
+        };
+        if rt.is_none() {
+            udps::frag_evict(&mut lkv, self.inner.cfg.frag_max_total);
+        }
+        Ok(rt)
+    }
 
     pub async fn remove(&self, src: &SocketAddr) {
-        let mut lkv = self.inner.mutis.write().await;
-        if let Some(v) = lkv.get(src) {
-            v.stop();
-        }
+        let mut lkv = self.inner.mutis.lock().await;
         lkv.remove(src);
     }
 
+    fn seal(&self, bts: bytes::ByteBoxBuf) -> bytes::Bytes {
+        udps::packet_finish(bts, self.inner.opts.crc)
+    }
+    //批量发送, linux 启用 udp-mmsg 时使用 sendmmsg
+    async fn send_raws(&self, pkts: &[bytes::Bytes], dist: Option<&SocketAddr>) -> io::Result<()> {
+        let mut ls = Vec::with_capacity(pkts.len());
+        for it in pkts {
+            if let Some(v) = &self.inner.loss {
+                if v.drops() {
+                    continue;
+                }
+            }
+            match &self.inner.sealer {
+                None => ls.push(it.clone()),
+                Some(v) => ls.push(bytes::Bytes::from(v.seal(&it[..])?)),
+            }
+        }
+        if let Some(conn) = &self.inner.conn {
+            sock::send_batch(conn, &ls[..], dist).await?;
+        }
+        Ok(())
+    }
+    async fn send_raw(&self, bts: &[u8], dist: Option<&SocketAddr>) -> io::Result<()> {
+        if let Some(v) = &self.inner.loss {
+            if v.drops() {
+                return Ok(());
+            }
+        }
+        // 重传包每次重新加密, 避免 counter 重复被对端当作重放
+        let sealed;
+        let bts = match &self.inner.sealer {
+            None => bts,
+            Some(v) => {
+                sealed = v.seal(bts)?;
+                &sealed[..]
+            }
+        };
+        if let Some(conn) = &self.inner.conn {
+            if let Some(addr) = dist {
+                conn.send_to(bts, addr).await?;
+            } else {
+                conn.send(bts).await?;
+            }
+        }
+        Ok(())
+    }
+    async fn send_packet(&self, bts: bytes::ByteBoxBuf, dist: Option<&SocketAddr>) -> io::Result<()> {
+        let bts = self.seal(bts);
+        self.send_raw(&bts[..], dist).await
+    }
     pub async fn send1bts(
         &self,
         data: bytes::Bytes,
         tks: &Option<String>,
         dist: Option<&SocketAddr>,
     ) -> io::Result<()> {
-        if data.len() > 1200 {
+        if data.len() > self.payload_size(tks, dist).await {
             return Err(ruisutil::ioerr("data len out packet", None));
         }
-        let mut bts = msg::udps::packet_fmts(11, tks)?;
+        let sess = self.sess_id(dist).await;
+        let mut bts = self.packet_head_sess(11, tks, sess)?;
         bts.push(data);
-        if let Some(conn) = &self.inner.conn {
-            if let Some(addr) = dist {
-                conn.send_to(&bts.to_bytes()[..], addr).await?;
-            } else {
-                conn.send(&bts.to_bytes()[..]).await?;
-            }
-        }
-        Ok(())
+        self.send_packet(bts, dist).await
     }
     pub async fn send1msg(
         &self,
@@ -255,21 +696,658 @@ impl UMsgerServ {
         tks: &Option<String>,
         dist: Option<&SocketAddr>,
     ) -> io::Result<()> {
-        let datas = msg::udps::msg_fmts(data)?;
-        if datas.len() > 1200 {
+        let cmp = self.peer_cmp(dist).await;
+        let datas = msg::udps::msg_fmts_cmp(data, cmp, self.inner.opts.cmp_min)?;
+        if datas.len() > self.payload_size(tks, dist).await {
             return Err(ruisutil::ioerr("msg len out packet", None));
         }
-        let mut bts = msg::udps::packet_fmts(12, tks)?;
+        let sess = self.sess_id(dist).await;
+        let mut bts = self.packet_head_sess(12, tks, sess)?;
         bts.push_all(&datas);
-        if let Some(conn) = &self.inner.conn {
-            if let Some(addr) = dist {
-                conn.send_to(&bts.to_bytes()[..], addr).await?;
-            } else {
-                conn.send(&bts.to_bytes()[..]).await?;
+        self.send_packet(bts, dist).await
+    }
+    //超过单包大小时分片发送, 对端重组后回调 on_msg
+    pub async fn send_msg(
+        &self,
+        data: msg::Messageus,
+        tks: &Option<String>,
+        dist: Option<&SocketAddr>,
+    ) -> io::Result<()> {
+        let cmp = self.peer_cmp(dist).await;
+        let datas = msg::udps::msg_fmts_cmp(data, cmp, self.inner.opts.cmp_min)?;
+        let psz = self.payload_size(tks, dist).await;
+        let pkts = udps::msg_packets(
+            &self.inner.cfg,
+            self.inner.opts.crc,
+            tks,
+            self.sess_id(dist).await,
+            datas,
+            psz,
+            &self.inner.msgid,
+        )?;
+        self.send_raws(&pkts[..], dist).await
+    }
+}
+
+impl UMsgerServ {
+    //可靠有序发送: 丢包重传, 对端按发送顺序回调 on_msg
+    pub async fn send_reliable(
+        &self,
+        data: msg::Messageus,
+        tks: &Option<String>,
+        dist: &SocketAddr,
+    ) -> io::Result<()> {
+        let cmp = self.peer_cmp(Some(dist)).await;
+        let datas = msg::udps::msg_fmts_cmp(data, cmp, self.inner.opts.cmp_min)?;
+        let psz = self.payload_size(tks, Some(dist)).await;
+        if psz <= reliable::DATA_HEAD_LEN {
+            return Err(ruisutil::ioerr("payload size too small", None));
+        }
+        let fragsz = psz - reliable::DATA_HEAD_LEN;
+        let count = (datas.len() + fragsz - 1) / fragsz;
+        if count <= 0 || count > self.inner.cfg.frag_max_count as usize {
+            return Err(ruisutil::ioerr("msg len out frag limit", None));
+        }
+        let mut datas = datas.to_bytes();
+        let sess = self.sess_id(Some(dist)).await;
+        let now = Instant::now();
+        let sends = {
+            let mut lkv = self.inner.rel_snds.lock().await;
+            if !lkv.contains_key(dist) {
+                lkv.insert(dist.clone(), RelSender::new(&self.inner.cfg));
+            }
+            let snd = match lkv.get_mut(dist) {
+                None => return Err(ruisutil::ioerr("reliable sender err", None)),
+                Some(v) => v,
+            };
+            for i in 0..count {
+                let n = if datas.len() > fragsz {
+                    fragsz
+                } else {
+                    datas.len()
+                };
+                let hd = reliable::DataHead {
+                    epoch: snd.epoch(),
+                    seq: snd.next_seq(),
+                    ind: i as u16,
+                    count: count as u16,
+                };
+                let mut bts = self.packet_head_sess(reliable::CTRL_DATA, tks, sess)?;
+                bts.push(reliable::data_fmts(&hd));
+                bts.push(datas.cuts(n)?);
+                snd.queue(hd.seq, self.seal(bts));
+            }
+            snd.pull_sendable(&self.inner.cfg, now)
+        };
+        self.send_raws(&sends[..], Some(dist)).await
+    }
+    //待确认的包数量
+    pub async fn reliable_pending(&self, dist: &SocketAddr) -> usize {
+        let lkv = self.inner.rel_snds.lock().await;
+        match lkv.get(dist) {
+            None => 0,
+            Some(v) => v.waits(),
+        }
+    }
+}
+
+impl UMsgerServ {
+    //更新会话活跃时间, 返回是否已建立会话; 包带的会话 id 与当前会话不一致时返回错误
+    async fn session_touch(&self, src: &SocketAddr, sess: Option<u64>) -> io::Result<bool> {
+        let mut lkv = self.inner.sessions.lock().await;
+        match lkv.get_mut(src) {
+            None => Ok(false),
+            Some(v) => {
+                if let Some(id) = sess {
+                    if id != v.id {
+                        return Err(ruisutil::ioerr("packet session id mismatch!!", None));
+                    }
+                }
+                v.uptm = Instant::now();
+                Ok(true)
+            }
+        }
+    }
+    async fn session_open(
+        &self,
+        src: &SocketAddr,
+        tks: &Option<String>,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let (nonce, cmps) = session::open_parse(data);
+        let (sess, isnew, old) = {
+            let mut lkv = self.inner.sessions.lock().await;
+            // nonce 变化说明对端重启, 旧会话作废
+            let stale = match lkv.get(src) {
+                Some(v) => nonce != 0 && v.nonce != nonce,
+                None => false,
+            };
+            let old = if stale { lkv.remove(src) } else { None };
+            match lkv.get_mut(src) {
+                Some(v) => {
+                    v.cmps = cmps;
+                    (v.clone(), false, old)
+                }
+                None => {
+                    if old.is_none() && lkv.len() >= self.inner.cfg.sess_max {
+                        return Err(ruisutil::ioerr("sessions out limit!!", None));
+                    }
+                    let mut v = UdpSession::new(src.clone());
+                    v.cmps = cmps;
+                    v.nonce = nonce;
+                    lkv.insert(src.clone(), v.clone());
+                    (v, true, old)
+                }
+            }
+        };
+        if let Some(v) = &old {
+            self.session_clear(src).await;
+            self.inner.recver.on_session_close(v, SessionClose::Replaced).await;
+        }
+        if isnew {
+            self.inner.recver.on_session_open(&sess).await;
+        }
+        let mut bts = self.packet_head(session::CTRL_ACK, tks)?;
+        bts.push(session::id_fmts(sess.id));
+        bts.push(session::cmps_fmts(&msg::Compress::supported()));
+        self.send_packet(bts, Some(src)).await
+    }
+    //id 不为空时需与当前会话一致
+    async fn session_close(
+        &self,
+        src: &SocketAddr,
+        id: Option<u64>,
+        reason: SessionClose,
+    ) -> io::Result<Option<UdpSession>> {
+        let sess = {
+            let mut lkv = self.inner.sessions.lock().await;
+            match lkv.get(src) {
+                Some(v) if id.is_none() || id == Some(v.id) => lkv.remove(src),
+                _ => None,
+            }
+        };
+        if let Some(v) = &sess {
+            self.session_clear(src).await;
+            self.inner.recver.on_session_close(v, reason).await;
+        }
+        Ok(sess)
+    }
+    //清理该来源的分片和可靠传输状态
+    async fn session_clear(&self, src: &SocketAddr) {
+        self.inner.mutis.lock().await.remove(src);
+        self.inner.rel_snds.lock().await.remove(src);
+        self.inner.rel_rcvs.lock().await.remove(src);
+    }
+    async fn check_sessions(&self) {
+        let mut rms = Vec::new();
+        {
+            let mut lkv = self.inner.sessions.lock().await;
+            lkv.retain(|_, v| {
+                if v.idle() < self.inner.cfg.sess_idle {
+                    true
+                } else {
+                    rms.push(v.clone());
+                    false
+                }
+            });
+        }
+        for it in &rms {
+            if let Err(e) = self.session_notify(it).await {
+                println!("session close notify {} err:{}", it.addrs, e);
+            }
+            self.session_clear(&it.addrs).await;
+            self.inner.recver.on_session_close(it, SessionClose::Idle).await;
+        }
+    }
+    async fn session_notify(&self, sess: &UdpSession) -> io::Result<()> {
+        let mut bts = self.packet_head(session::CTRL_CLOSE, &self.inner.ctrl_tks)?;
+        bts.push(session::id_fmts(sess.id));
+        self.send_packet(bts, Some(&sess.addrs)).await
+    }
+
+    pub async fn sessions(&self) -> Vec<UdpSession> {
+        let lkv = self.inner.sessions.lock().await;
+        lkv.values().cloned().collect()
+    }
+    pub async fn session(&self, addrs: &SocketAddr) -> Option<UdpSession> {
+        let lkv = self.inner.sessions.lock().await;
+        lkv.get(addrs).cloned()
+    }
+    //关闭会话并通知对端, 会话不存在返回 false
+    pub async fn close_session(&self, addrs: &SocketAddr) -> io::Result<bool> {
+        match self.session_close(addrs, None, SessionClose::Local).await? {
+            None => Ok(false),
+            Some(v) => {
+                self.session_notify(&v).await?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+impl UMsgerServ {
+    //请求应答: 超时前按 cfg.call_retries 重传, 对端 on_call 的返回值作为结果
+    pub async fn call(
+        &self,
+        dist: &SocketAddr,
+        data: msg::Messageus,
+        tks: &Option<String>,
+        timeout: Duration,
+    ) -> io::Result<msg::Messageu> {
+        let cmp = self.peer_cmp(Some(dist)).await;
+        let datas = msg::udps::msg_fmts_cmp(data, cmp, self.inner.opts.cmp_min)?;
+        if datas.len() + call::CALL_HEAD_LEN > self.payload_size(tks, Some(dist)).await {
+            return Err(ruisutil::ioerr("call msg len out packet", None));
+        }
+        let id = self.inner.callid.fetch_add(1, Ordering::SeqCst);
+        let sess = self.sess_id(Some(dist)).await;
+        let mut bts = self.packet_head_sess(call::CTRL_CALL, tks, sess)?;
+        bts.push(call::id_fmts(id));
+        bts.push_all(&datas);
+        let pckt = self.seal(bts);
+
+        let k = (dist.clone(), id);
+        let (tx, mut rx) = make_channel(1);
+        self.inner.calls.lock().await.insert(k.clone(), tx);
+        let tries = self.inner.cfg.call_retries + 1;
+        let wait = timeout / tries;
+        let mut rt = Err(ruisutil::ioerr(
+            format!("call {} timeout", dist),
+            Some(io::ErrorKind::TimedOut),
+        ));
+        for _ in 0..tries {
+            if let Err(e) = self.send_raw(&pckt[..], Some(dist)).await {
+                rt = Err(e);
+                break;
+            }
+            match ruisutil::asyncs::timeouts(wait, ruisutil::asyncs::channel_recv(&mut rx)).await {
+                Ok(Ok(v)) => {
+                    rt = v;
+                    break;
+                }
+                Ok(Err(e)) => {
+                    rt = Err(e);
+                    break;
+                }
+                Err(_) => {}
+            }
+        }
+        self.inner.calls.lock().await.remove(&k);
+        rt
+    }
+    async fn on_call(&self, src: &SocketAddr, id: u32, data: bytes::Bytes) -> io::Result<()> {
+        let k = (src.clone(), id);
+        {
+            let mut lkv = self.inner.call_rets.lock().await;
+            match lkv.get(&k) {
+                // 重传的请求: 处理中忽略, 已完成直接回复缓存
+                Some((_, None)) => return Ok(()),
+                Some((_, Some(v))) => {
+                    let v = v.clone();
+                    std::mem::drop(lkv);
+                    return self.send_raw(&v[..], Some(src)).await;
+                }
+                None => {
+                    lkv.insert(k.clone(), (Instant::now(), None));
+                }
+            }
+        }
+        let rt = match self.msg_parse(data) {
+            Err(e) => Err(e),
+            Ok(m) => self.inner.recver.on_call(src, m).await,
+        };
+        let bts = match self.call_reply(src, id, rt).await {
+            Ok(v) => v,
+            Err(e) => {
+                // 去掉处理中标记, 否则重传的请求一直被忽略
+                self.inner.call_rets.lock().await.remove(&k);
+                return Err(e);
+            }
+        };
+        let pckt = self.seal(bts);
+        self.inner
+            .call_rets
+            .lock()
+            .await
+            .insert(k, (Instant::now(), Some(pckt.clone())));
+        self.send_raw(&pckt[..], Some(src)).await
+    }
+    async fn call_reply(
+        &self,
+        src: &SocketAddr,
+        id: u32,
+        rt: io::Result<msg::Messageus>,
+    ) -> io::Result<bytes::ByteBoxBuf> {
+        let v = match rt {
+            Err(e) => return self.call_err(id, &e),
+            Ok(v) => v,
+        };
+        let tks = &self.inner.ctrl_tks;
+        let cmp = self.peer_cmp(Some(src)).await;
+        let datas = msg::udps::msg_fmts_cmp(v, cmp, self.inner.opts.cmp_min)?;
+        if datas.len() + call::CALL_HEAD_LEN > self.payload_size(tks, Some(src)).await {
+            return self.call_err(id, &ruisutil::ioerr("reply len out packet", None));
+        }
+        let sess = self.sess_id(Some(src)).await;
+        let mut bts = self.packet_head_sess(call::CTRL_REPLY, tks, sess)?;
+        bts.push(call::id_fmts(id));
+        bts.push_all(&datas);
+        Ok(bts)
+    }
+    fn call_err(&self, id: u32, e: &io::Error) -> io::Result<bytes::ByteBoxBuf> {
+        let mut bts = self.packet_head(call::CTRL_ERROR, &self.inner.ctrl_tks)?;
+        bts.push(call::id_fmts(id));
+        let mut errs = e.to_string().into_bytes();
+        errs.truncate(512);
+        bts.push(errs);
+        Ok(bts)
+    }
+}
+
+impl UMsgerServ {
+    //作为中继(rendezvous)接受 peer 登记、交换地址并转发
+    pub async fn set_rendezvous(&self, on: bool) {
+        self.inner.nat.lock().await.rdv_on = on;
+    }
+    //中继: 已登记的 peer 及其公网地址
+    pub async fn nat_regs(&self) -> Vec<(String, SocketAddr)> {
+        let lkv = self.inner.nat.lock().await;
+        lkv.regs.iter().map(|(k, v)| (k.clone(), v.0.clone())).collect()
+    }
+    //peer: 向中继登记, 返回中继观察到的本端地址
+    pub async fn nat_register(
+        &self,
+        rdv: &SocketAddr,
+        name: &str,
+        timeout: Duration,
+    ) -> io::Result<SocketAddr> {
+        {
+            let mut lkv = self.inner.nat.lock().await;
+            lkv.rdv = Some(rdv.clone());
+            lkv.name = name.to_string();
+            lkv.public = None;
+        }
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            self.nat_send_ctrl(nat::CTRL_REG, &[name], None, rdv).await?;
+            for _ in 0..20 {
+                ruisutil::asyncs::sleep(Duration::from_millis(10)).await;
+                if let Some(v) = &self.inner.nat.lock().await.public {
+                    return Ok(v.clone());
+                }
+            }
+        }
+        Err(ruisutil::ioerr(
+            "nat register timeout",
+            Some(io::ErrorKind::TimedOut),
+        ))
+    }
+    //经中继交换地址后双方同时打洞, 成功返回对端地址; 失败可用 nat_relay 转发
+    pub async fn nat_connect(&self, name: &str, timeout: Duration) -> io::Result<SocketAddr> {
+        let (rdv, me) = self.nat_me().await?;
+        let start = Instant::now();
+        let mut reqtm: Option<Instant> = None;
+        while start.elapsed() < timeout {
+            if let Some(v) = self.nat_peer(name).await {
+                return Ok(v);
+            }
+            let req = match &reqtm {
+                None => true,
+                Some(v) => v.elapsed() > Duration::from_millis(500),
+            };
+            if req {
+                reqtm = Some(Instant::now());
+                self.nat_send_ctrl(nat::CTRL_PUNCH_REQ, &[me.as_str(), name], None, &rdv)
+                    .await?;
+            }
+            ruisutil::asyncs::sleep(Duration::from_millis(10)).await;
+        }
+        Err(ruisutil::ioerr(
+            format!("nat connect {} timeout", name),
+            Some(io::ErrorKind::TimedOut),
+        ))
+    }
+    //打洞成功的对端地址
+    pub async fn nat_peer(&self, name: &str) -> Option<SocketAddr> {
+        let lkv = self.inner.nat.lock().await;
+        match lkv.links.get(name) {
+            Some(v) if v.ok => Some(v.addr.clone()),
+            _ => None,
+        }
+    }
+    //直连可用时直接发送, 否则经中继转发(单包大小)
+    pub async fn nat_send(&self, name: &str, data: msg::Messageus) -> io::Result<()> {
+        match self.nat_peer(name).await {
+            Some(v) => {
+                let tks = self.inner.ctrl_tks.clone();
+                self.send_msg(data, &tks, Some(&v)).await
+            }
+            None => self.nat_relay(name, data).await,
+        }
+    }
+    pub async fn nat_relay(&self, name: &str, data: msg::Messageus) -> io::Result<()> {
+        let (rdv, me) = self.nat_me().await?;
+        // 中继转发到的对端是否支持未知, 不压缩
+        let datas = msg::udps::msg_fmts(data)?;
+        let bts = nat::names_fmts(&[me.as_str(), name])?;
+        // 转发时中继追加来源地址, 预留 64 字节
+        let tks = &self.inner.ctrl_tks;
+        if datas.len() + bts.len() + 64 > self.payload_size(tks, Some(&rdv)).await {
+            return Err(ruisutil::ioerr("relay msg len out packet", None));
+        }
+        self.nat_send_ctrl(nat::CTRL_RELAY, &[me.as_str(), name], Some(datas), &rdv)
+            .await
+    }
+    async fn nat_me(&self) -> io::Result<(SocketAddr, String)> {
+        let lkv = self.inner.nat.lock().await;
+        match &lkv.rdv {
+            Some(v) if !lkv.name.is_empty() => Ok((v.clone(), lkv.name.clone())),
+            _ => Err(ruisutil::ioerr("nat not register", None)),
+        }
+    }
+    async fn nat_send_ctrl(
+        &self,
+        ctrl: u16,
+        names: &[&str],
+        datas: Option<bytes::ByteBoxBuf>,
+        dist: &SocketAddr,
+    ) -> io::Result<()> {
+        let mut bts = self.packet_head(ctrl, &self.inner.ctrl_tks)?;
+        bts.push(nat::names_fmts(names)?);
+        if let Some(v) = &datas {
+            bts.push_all(v);
+        }
+        self.send_packet(bts, Some(dist)).await
+    }
+    async fn on_nat(&self, ctrl: u16, src: &SocketAddr, data: bytes::Bytes) -> io::Result<()> {
+        let mut data = data;
+        match ctrl {
+            nat::CTRL_REG => {
+                let name = nat::str_parse(&mut data)?;
+                {
+                    let mut lkv = self.inner.nat.lock().await;
+                    if !lkv.rdv_on {
+                        return Ok(());
+                    }
+                    if !lkv.regs.contains_key(&name) && lkv.regs.len() >= self.inner.cfg.sess_max {
+                        return Err(ruisutil::ioerr("nat regs out limit!!", None));
+                    }
+                    if !lkv.reg(&name, src, self.inner.cfg.nat_ttl) {
+                        return Err(ruisutil::ioerr(format!("nat name {} in use", name), None));
+                    }
+                }
+                let addr = src.to_string();
+                self.nat_send_ctrl(nat::CTRL_REG_ACK, &[addr.as_str()], None, src)
+                    .await?;
+            }
+            nat::CTRL_REG_ACK => {
+                let addr = nat::addr_parse(&mut data)?;
+                let mut lkv = self.inner.nat.lock().await;
+                if lkv.rdv.as_ref() == Some(src) {
+                    lkv.public = Some(addr);
+                    lkv.reg_tm = Some(Instant::now());
+                }
             }
+            nat::CTRL_PUNCH_REQ => {
+                let from = nat::str_parse(&mut data)?;
+                let to = nat::str_parse(&mut data)?;
+                let taddr = {
+                    let mut lkv = self.inner.nat.lock().await;
+                    if !lkv.rdv_on {
+                        return Ok(());
+                    }
+                    if !lkv.reg(&from, src, self.inner.cfg.nat_ttl) {
+                        return Err(ruisutil::ioerr(format!("nat name {} in use", from), None));
+                    }
+                    match lkv.regs.get(&to) {
+                        None => return Err(ruisutil::ioerr(format!("nat peer {} not found", to), None)),
+                        Some(v) => v.0.clone(),
+                    }
+                };
+                let ts = taddr.to_string();
+                let fs = src.to_string();
+                self.nat_send_ctrl(nat::CTRL_PEER_INFO, &[to.as_str(), ts.as_str()], None, src)
+                    .await?;
+                self.nat_send_ctrl(nat::CTRL_PEER_INFO, &[from.as_str(), fs.as_str()], None, &taddr)
+                    .await?;
+            }
+            nat::CTRL_PEER_INFO => {
+                let name = nat::str_parse(&mut data)?;
+                let addr = nat::addr_parse(&mut data)?;
+                {
+                    let mut lkv = self.inner.nat.lock().await;
+                    if lkv.rdv.as_ref() != Some(src) {
+                        return Ok(());
+                    }
+                    if let Some(v) = lkv.links.get(&name) {
+                        if v.ok {
+                            return Ok(());
+                        }
+                    }
+                    let now = Instant::now();
+                    lkv.links.insert(
+                        name.clone(),
+                        NatLink {
+                            addr: addr.clone(),
+                            ok: false,
+                            uptm: now,
+                            ping: now,
+                        },
+                    );
+                }
+                let c = self.clone();
+                ruisutil::asyncs::task::spawn(async move {
+                    if let Err(e) = c.nat_punch(&name, &addr).await {
+                        println!("nat punch {}({}) err:{}", name, addr, e);
+                    }
+                });
+            }
+            nat::CTRL_PUNCH => {
+                let name = nat::str_parse(&mut data)?;
+                let me = {
+                    let mut lkv = self.inner.nat.lock().await;
+                    if !lkv.link_ok(&name, src) {
+                        return Ok(());
+                    }
+                    lkv.name.clone()
+                };
+                self.nat_send_ctrl(nat::CTRL_PUNCH_ACK, &[me.as_str()], None, src)
+                    .await?;
+            }
+            nat::CTRL_PUNCH_ACK => {
+                let name = nat::str_parse(&mut data)?;
+                let _ = self.inner.nat.lock().await.link_ok(&name, src);
+            }
+            nat::CTRL_RELAY => {
+                let from = nat::str_parse(&mut data)?;
+                let to = nat::str_parse(&mut data)?;
+                let taddr = {
+                    let mut lkv = self.inner.nat.lock().await;
+                    if !lkv.rdv_on {
+                        return Ok(());
+                    }
+                    if !lkv.reg(&from, src, self.inner.cfg.nat_ttl) {
+                        return Err(ruisutil::ioerr(format!("nat name {} in use", from), None));
+                    }
+                    match lkv.regs.get(&to) {
+                        None => return Err(ruisutil::ioerr(format!("nat peer {} not found", to), None)),
+                        Some(v) => v.0.clone(),
+                    }
+                };
+                let fs = src.to_string();
+                let mut datas = bytes::ByteBoxBuf::new();
+                datas.push(data);
+                self.nat_send_ctrl(
+                    nat::CTRL_RELAYED,
+                    &[from.as_str(), fs.as_str()],
+                    Some(datas),
+                    &taddr,
+                )
+                .await?;
+            }
+            nat::CTRL_RELAYED => {
+                let _ = nat::str_parse(&mut data)?;
+                let addr = nat::addr_parse(&mut data)?;
+                if self.inner.nat.lock().await.rdv.as_ref() != Some(src) {
+                    return Ok(());
+                }
+                let m = self.msg_parse(data)?;
+                self.inner.recver.on_msg(&addr, m).await?;
+            }
+            _ => {}
         }
         Ok(())
     }
+    async fn nat_punch(&self, name: &str, addr: &SocketAddr) -> io::Result<()> {
+        let (_, me) = self.nat_me().await?;
+        let start = Instant::now();
+        while start.elapsed() < self.inner.cfg.nat_punch_time && !self.inner.ctx.done() {
+            if self.nat_peer(name).await.is_some() {
+                return Ok(());
+            }
+            self.nat_send_ctrl(nat::CTRL_PUNCH, &[me.as_str()], None, addr)
+                .await?;
+            ruisutil::asyncs::sleep(self.inner.cfg.nat_punch_interval).await;
+        }
+        Err(ruisutil::ioerr(
+            "nat punch timeout",
+            Some(io::ErrorKind::TimedOut),
+        ))
+    }
+    //保活: 定期重新登记, 向已连通的对端发送 PUNCH
+    async fn check_nat(&self) {
+        let cfg = &self.inner.cfg;
+        let (rdv, me, reg, pings) = {
+            let mut lkv = self.inner.nat.lock().await;
+            lkv.clean(cfg.nat_ttl);
+            let reg = match &lkv.reg_tm {
+                Some(v) => v.elapsed() > cfg.nat_keepalive,
+                None => false,
+            };
+            let mut pings = Vec::new();
+            for it in lkv.links.values_mut() {
+                if it.ok && it.ping.elapsed() > cfg.nat_keepalive {
+                    it.ping = Instant::now();
+                    pings.push(it.addr.clone());
+                }
+            }
+            (lkv.rdv.clone(), lkv.name.clone(), reg, pings)
+        };
+        let rdv = match rdv {
+            Some(v) if !me.is_empty() => v,
+            _ => return,
+        };
+        if reg {
+            if let Err(e) = self.nat_send_ctrl(nat::CTRL_REG, &[me.as_str()], None, &rdv).await {
+                println!("nat keepalive {} err:{}", rdv, e);
+            }
+        }
+        for it in &pings {
+            if let Err(e) = self.nat_send_ctrl(nat::CTRL_PUNCH, &[me.as_str()], None, it).await {
+                println!("nat keepalive {} err:{}", it, e);
+            }
+        }
+    }
 }
 
 pub trait IUMsgerServ {
@@ -278,4 +1356,23 @@ pub trait IUMsgerServ {
     fn on_bts(&self, addrs: &SocketAddr, msg: bytes::Bytes)
         -> BoxFuture<'static, io::Result<()>>;
     fn on_msg(&self, addrs: &SocketAddr, msg: msg::Messageu) -> BoxFuture<'static, io::Result<()>>;
+
+    fn on_session_open(&self, _sess: &UdpSession) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
+    //处理 call 请求, 返回值回复给调用方
+    fn on_call(
+        &self,
+        _addrs: &SocketAddr,
+        _msg: msg::Messageu,
+    ) -> BoxFuture<'static, io::Result<msg::Messageus>> {
+        Box::pin(async { Err(ruisutil::ioerr("call not support", None)) })
+    }
+    //丢包回调, 每个原因每秒最多一次, n 为该原因累计丢弃数
+    fn on_drop(&self, _addrs: &SocketAddr, _reason: DropReason, _n: u64) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
+    fn on_session_close(&self, _sess: &UdpSession, _reason: SessionClose) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
 }
@@ -1,9 +1,22 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
-use ruisutil::asyncs::net::UdpSocket;
 use ruisutil::asyncs::BoxFuture;
+use ruisutil::asyncs::{net::UdpSocket, sync::Mutex};
 use ruisutil::bytes;
 
+use crate::socks::msg;
+
+use super::{
+    seal::UdpSeal,
+    session, sock,
+    udps::{self, UdpConfig, UdpMsgParse},
+};
+
 #[derive(Clone)]
 pub struct UMsgerClient {
     inner: ruisutil::ArcMut<Inner>,
@@ -15,7 +28,21 @@ struct Inner {
     conn: Option<UdpSocket>,
     shuted: bool,
 
+    tks: Option<String>,
+    cfg: UdpConfig,
+    opts: msg::MsgOpts,
+    sealer: Option<UdpSeal>,
+    pmtu: Option<usize>,
+    frags: Mutex<Option<UdpMsgParse>>,
+    msgid: AtomicU32,
     recver: Box<dyn IUMsgerCli + Send + Sync>,
+
+    //会话 id, 0 表示未建立
+    sessid: AtomicU64,
+    //OPEN 携带的 nonce, 客户端重启或 close_session 后变化, 服务端据此重建会话
+    nonce: AtomicU64,
+    //服务端 ACK 声明支持的压缩算法
+    peer_cmps: Mutex<Vec<msg::Compress>>,
 }
 
 impl UMsgerClient {
@@ -23,11 +50,6 @@ impl UMsgerClient {
     where
         T: IUMsgerCli + Send + Sync + 'static,
     {
-        /* let (sx, rx) = if sndbufln > 0 {
-            channel::bounded::<msg::Messages>(sndbufln)
-        } else {
-            channel::unbounded::<msg::Messages>()
-        }; */
         Self {
             inner: ruisutil::ArcMut::new(Inner {
                 ctx: ruisutil::Context::background(Some(ctx.clone())),
@@ -36,45 +58,146 @@ impl UMsgerClient {
                 conn: None,
                 shuted: false,
 
+                tks: None,
+                cfg: UdpConfig::default(),
+                opts: msg::MsgOpts::default(),
+                sealer: None,
+                pmtu: None,
+                frags: Mutex::new(None),
+                msgid: AtomicU32::new(1),
                 recver: Box::new(recver),
+
+                sessid: AtomicU64::new(0),
+                nonce: AtomicU64::new(0),
+                peer_cmps: Mutex::new(Vec::new()),
             }),
         }
     }
 
+    //发送的包都带上此 token
+    pub fn set_token(&self, tks: Option<String>) {
+        unsafe { self.inner.muts().tks = tks };
+    }
+    pub fn set_config(&self, cfg: UdpConfig) {
+        unsafe { self.inner.muts().cfg = cfg };
+    }
+    //body 大于 min 且服务端会话 ACK 声明支持 cmp 时压缩
+    pub fn set_compress(&self, cmp: msg::Compress, min: usize) {
+        let ins = unsafe { self.inner.muts() };
+        ins.opts.cmp = cmp;
+        ins.opts.cmp_min = min;
+    }
+    pub fn set_checksum(&self, crc: bool) {
+        unsafe { self.inner.muts().opts.crc = crc };
+    }
+    //与服务端使用相同的 psk
+    pub fn set_psk(&self, psk: Option<&[u8]>) -> io::Result<()> {
+        let sealer = match psk {
+            None => None,
+            Some(v) => Some(UdpSeal::new(v)?),
+        };
+        unsafe { self.inner.muts().sealer = sealer };
+        Ok(())
+    }
+    //路径 MTU, None 使用 cfg.mtu
+    pub fn set_path_mtu(&self, mtu: Option<usize>) {
+        unsafe { self.inner.muts().pmtu = mtu };
+    }
+    //解压后的 body 不超过重组上限
+    fn msg_parse(&self, data: bytes::Bytes) -> io::Result<msg::Messageu> {
+        msg::udps::msg_parse_max(data, self.inner.cfg.frag_max_bytes as u64)
+    }
+    //单包数据大小, 扣除包头/token/校验/加密开销
+    pub fn payload_size(&self) -> usize {
+        let overhead = udps::packet_overhead(
+            &self.inner.tks,
+            self.session_id().is_some(),
+            self.inner.opts.crc,
+            self.inner.sealer.is_some(),
+        );
+        let v6 = match &self.inner.conn {
+            Some(v) => match v.peer_addr() {
+                Ok(addr) => addr.is_ipv6(),
+                Err(_) => false,
+            },
+            None => false,
+        };
+        self.inner.cfg.payload_size(self.inner.pmtu, v6, overhead)
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.inner.conn {
+            Some(v) => v.local_addr(),
+            None => Err(ruisutil::ioerr("conn not run", None)),
+        }
+    }
+
     pub fn stop(&self) {
         if !self.inner.shuted {
-            println!("msger conn will stop");
+            println!("udp_msger client will stop");
             let ins = unsafe { self.inner.muts() };
             ins.shuted = true;
             ins.conn = None;
             self.inner.ctx.stop();
         }
     }
-    pub async fn run(&self) -> async_std::io::Result<()> {
+    //绑定本地地址并连接远端, run 前可先调用以便立即发送
+    pub async fn connect(&self) -> io::Result<()> {
+        if self.inner.conn.is_some() {
+            return Ok(());
+        }
         let ins = unsafe { self.inner.muts() };
-        let conn = UdpSocket::bind(self.inner.addrs_lc.as_str()).await?;
+        let conn = sock::bind(self.inner.addrs_lc.as_str(), &self.inner.cfg).await?;
         conn.connect(self.inner.addrs_re.as_str()).await?;
         ins.conn = Some(conn);
+        Ok(())
+    }
+    pub async fn run(&self) -> io::Result<()> {
+        self.connect().await?;
+        let c = self.clone();
+        ruisutil::asyncs::task::spawn(async move {
+            c.run_check().await;
+        });
         self.run_recv().await;
         self.stop();
-        println!("UMsgerServ end run check");
+        println!("UMsgerClient end run check");
         Ok(())
     }
+    async fn run_check(&self) {
+        let mut ping = Instant::now();
+        while !self.inner.ctx.done() {
+            ruisutil::asyncs::sleep(Duration::from_millis(500)).await;
+            {
+                let mut lkv = self.inner.frags.lock().await;
+                if let Some(v) = &mut *lkv {
+                    v.clean(&self.inner.cfg);
+                }
+            }
+            if let Some(v) = &self.inner.sealer {
+                v.clean(self.inner.cfg.sess_idle);
+            }
+            // 会话保活, 重复 OPEN 服务端返回同一个 id
+            if self.session_id().is_some() && ping.elapsed() > self.inner.cfg.sess_idle / 3 {
+                ping = Instant::now();
+                if let Err(e) = self.send_ctrl(session::CTRL_OPEN, None).await {
+                    println!("session keepalive err:{}", e);
+                }
+            }
+        }
+    }
     async fn run_recv(&self) {
         while !self.inner.ctx.done() {
             if let Some(conn) = &self.inner.conn {
-                let mut buf = vec![0u8; 1500].into_boxed_slice();
+                let mut buf = vec![0u8; self.inner.cfg.dgram_max + 1];
                 match conn.recv_from(&mut buf[..]).await {
                     Err(e) => {
                         println!("udp recv err:{}", e);
-                        self.stop();
-                        async_std::task::sleep(Duration::from_millis(5)).await;
+                        ruisutil::asyncs::sleep(Duration::from_millis(5)).await;
                     }
                     Ok((n, src)) => {
-                        if n <= 1472 {
+                        if n <= self.inner.cfg.dgram_max {
                             let c = self.clone();
-                            async_std::task::spawn(async move {
-                                let bts = bytes::ByteBox::new(Arc::new(buf), 0, n);
+                            ruisutil::asyncs::task::spawn(async move {
+                                let bts = bytes::bytes_with_len(buf, n);
                                 if let Err(e) = c.run_parse(bts, src.clone()).await {
                                     println!("run_parse from {} err:{}", src.to_string(), e);
                                 }
@@ -84,17 +207,25 @@ impl UMsgerClient {
                         }
                     }
                 }
+            } else {
+                break;
             }
         }
     }
-    async fn run_parse(&self, buf: bytes::ByteBox, src: SocketAddr) -> io::Result<()> {
-        /* if buf.len() < 10 {
-            return Err(ruisutil::ioerr(format!("packet len err:{}",buf.len()), None));
-        } */
+    async fn run_parse(&self, buf: bytes::Bytes, src: SocketAddr) -> io::Result<()> {
+        let buf = match &self.inner.sealer {
+            None => buf,
+            Some(v) => bytes::Bytes::from(v.open(&src, &buf[..], self.inner.cfg.frag_max_peers)?),
+        };
         let pckt = msg::udps::packet_parse(buf)?;
         if !self.inner.recver.check_token(&src, &pckt.token).await {
             return Err(ruisutil::ioerr("packet token err!!!", None));
         }
+        if let (Some(id), Some(cur)) = (pckt.sess, self.session_id()) {
+            if id != cur {
+                return Err(ruisutil::ioerr("packet session id mismatch!!", None));
+            }
+        }
 
         /*
           10-20: 无需按顺序(包小于1400)
@@ -102,86 +233,180 @@ impl UMsgerClient {
           30-40: 需要重组,需要按顺序(包可大于1400)
         */
         match pckt.ctrl {
+            session::CTRL_ACK => {
+                let id = session::id_parse(pckt.data.clone())?;
+                *self.inner.peer_cmps.lock().await = session::cmps_parse(&pckt.data[8..]);
+                // 新会话不沿用旧会话的分片状态
+                if self.inner.sessid.swap(id, Ordering::SeqCst) != id {
+                    *self.inner.frags.lock().await = None;
+                }
+            }
+            session::CTRL_CLOSE => {
+                let id = session::id_parse(pckt.data.clone())?;
+                if self
+                    .inner
+                    .sessid
+                    .compare_exchange(id, 0, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    *self.inner.frags.lock().await = None;
+                }
+            }
             11 => {
                 self.inner.recver.on_bts(&src, pckt.data.clone()).await?;
             }
             12 => {
-                let m = msg::udps::msg_parse(pckt.data.clone())?;
+                let m = self.msg_parse(pckt.data.clone())?;
                 self.inner.recver.on_msg(&src, m).await?;
             }
+            21 => {
+                let mut data = pckt.data.clone();
+                let hd = udps::frag_parse(&mut data)?;
+                let bts = {
+                    let mut lkv = self.inner.frags.lock().await;
+                    let v = lkv.get_or_insert_with(|| UdpMsgParse::new(src.clone()));
+                    v.push(&self.inner.cfg, hd, data)?
+                };
+                if let Some(bts) = bts {
+                    let m = self.msg_parse(bts)?;
+                    self.inner.recver.on_msg(&src, m).await?;
+                }
+            }
             _ => {}
         }
-        /*
-        let bts = buf.cuts(3)?;
-        if bts[0] != 0x48 || bts[1] != 0x42 {
-            return Err(ruisutil::ioerr(
-                format!("packet start err:[{},{}]", bts[0], bts[1]),
-                None,
-            ));
-        }
-        if bts[2] != 1 {
-            return Err(ruisutil::ioerr(
-                format!("packet version err:[{}]", bts[2]),
-                None,
-            ));
-        }
-        let bts = buf.cuts(2)?;
-        let ctrl = bts[0];
-        let id = bts[1];
-        let bts = buf.cuts(2)?;
-        let ind = ruisutil::byte_2i(&bts[..]) as u16;
-        let bts = buf.cuts(2)?;
-        let ln = ruisutil::byte_2i(&bts[..]) as u16;
-        let bts = buf.cuts(2)?;
-        let kn = ruisutil::byte_2i(&bts[..]) as usize;
-        let bts = buf.cuts(kn)?;
-        if let Some(vs) = &self.inner.keys {
-            if !vs.is_empty() {
-                let keys = match std::str::from_utf8(&bts[..]) {
-                    Err(e) => {
-                        return Err(ruisutil::ioerr(format!("packet keys err:[{}]", e), None))
+        Ok(())
+    }
+
+    fn packet_head(&self, ctrl: u16) -> io::Result<bytes::ByteBoxBuf> {
+        udps::packet_head(self.inner.opts.crc, ctrl, &self.inner.tks, None)
+    }
+    //数据包带上当前会话的 id
+    fn packet_head_sess(&self, ctrl: u16) -> io::Result<bytes::ByteBoxBuf> {
+        let sess = self.session_id();
+        udps::packet_head(self.inner.opts.crc, ctrl, &self.inner.tks, sess)
+    }
+    async fn send_raws(&self, pkts: &[bytes::Bytes]) -> io::Result<()> {
+        let conn = match &self.inner.conn {
+            None => return Err(ruisutil::ioerr("conn not run", None)),
+            Some(v) => v,
+        };
+        let mut ls = Vec::with_capacity(pkts.len());
+        for it in pkts {
+            ls.push(udps::packet_seal(&self.inner.sealer, &it[..])?);
+        }
+        sock::send_batch(conn, &ls[..], None).await
+    }
+    async fn send_packet(&self, bts: bytes::ByteBoxBuf) -> io::Result<()> {
+        let bts = udps::packet_finish(bts, self.inner.opts.crc);
+        self.send_raws(&[bts]).await
+    }
+    async fn send_ctrl(&self, ctrl: u16, id: Option<u64>) -> io::Result<()> {
+        let mut bts = self.packet_head(ctrl)?;
+        if let Some(v) = id {
+            bts.push(session::id_fmts(v));
+        }
+        if ctrl == session::CTRL_OPEN {
+            let nonce = match self.inner.nonce.load(Ordering::SeqCst) {
+                0 => {
+                    let v = crate::trace::rand_id() | 1;
+                    match self
+                        .inner
+                        .nonce
+                        .compare_exchange(0, v, Ordering::SeqCst, Ordering::SeqCst)
+                    {
+                        Ok(_) => v,
+                        Err(v) => v,
                     }
-                    Ok(v) => v,
-                };
-                if !vs.eq(keys) {
-                    return Err(ruisutil::ioerr(format!("packet keys err:{}", keys), None));
                 }
-            }
+                v => v,
+            };
+            bts.push(session::open_fmts(nonce, &msg::Compress::supported()));
         }
-        if id == 0 || ln <= 0 || ind >= ln {
-            return Err(ruisutil::ioerr(
-                format!("packet param err:id={},ind={},ln={}", id, ind, ln),
-                None,
-            ));
-        }
-        let mut pn = None;
-        {
-            let lkv = self.inner.mutis.read().await;
-            if let Some(v) = lkv.get(&src) {
-                pn = Some(v.clone());
-            }
+        self.send_packet(bts).await
+    }
+    pub fn session_id(&self) -> Option<u64> {
+        match self.inner.sessid.load(Ordering::SeqCst) {
+            0 => None,
+            v => Some(v),
         }
-        match pn {
-            Some(v) => {
-                v.ctrls(buf, ctrl, id, ind, ln).await;
-            }
-            None => {
-                let mut lkv = self.inner.mutis.write().await;
-                let v = UdpMsgParse::new(&self.inner.ctx, self.clone(), src.clone());
-                v.start().await;
-                v.ctrls(buf, ctrl, id, ind, ln).await;
-                lkv.insert(src, v);
+    }
+    //握手建立会话, 需先 run 才能收到服务端应答
+    pub async fn open_session(&self, timeout: Duration) -> io::Result<u64> {
+        if let Some(v) = self.session_id() {
+            return Ok(v);
+        }
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            self.send_ctrl(session::CTRL_OPEN, None).await?;
+            for _ in 0..20 {
+                ruisutil::asyncs::sleep(Duration::from_millis(10)).await;
+                if let Some(v) = self.session_id() {
+                    return Ok(v);
+                }
             }
-        } */
-
+        }
+        Err(ruisutil::ioerr(
+            "open session timeout",
+            Some(io::ErrorKind::TimedOut),
+        ))
+    }
+    //未建立会话时不压缩
+    async fn peer_cmp(&self) -> msg::Compress {
+        if self.inner.opts.cmp == msg::Compress::None || self.session_id().is_none() {
+            return msg::Compress::None;
+        }
+        let lkv = self.inner.peer_cmps.lock().await;
+        session::cmp_for(self.inner.opts.cmp, &lkv[..])
+    }
+    pub async fn close_session(&self) -> io::Result<()> {
+        let id = self.inner.sessid.swap(0, Ordering::SeqCst);
+        self.inner.nonce.store(0, Ordering::SeqCst);
+        *self.inner.frags.lock().await = None;
+        if id != 0 {
+            self.send_ctrl(session::CTRL_CLOSE, Some(id)).await?;
+        }
         Ok(())
     }
+
+    pub async fn send1bts(&self, data: bytes::Bytes) -> io::Result<()> {
+        if data.len() > self.payload_size() {
+            return Err(ruisutil::ioerr("data len out packet", None));
+        }
+        let mut bts = self.packet_head_sess(11)?;
+        bts.push(data);
+        self.send_packet(bts).await
+    }
+    pub async fn send1msg(&self, data: msg::Messageus) -> io::Result<()> {
+        let cmp = self.peer_cmp().await;
+        let datas = msg::udps::msg_fmts_cmp(data, cmp, self.inner.opts.cmp_min)?;
+        if datas.len() > self.payload_size() {
+            return Err(ruisutil::ioerr("msg len out packet", None));
+        }
+        let mut bts = self.packet_head_sess(12)?;
+        bts.push_all(&datas);
+        self.send_packet(bts).await
+    }
+    //超过单包大小时分片发送
+    pub async fn send_msg(&self, data: msg::Messageus) -> io::Result<()> {
+        let cmp = self.peer_cmp().await;
+        let datas = msg::udps::msg_fmts_cmp(data, cmp, self.inner.opts.cmp_min)?;
+        let pkts = udps::msg_packets(
+            &self.inner.cfg,
+            self.inner.opts.crc,
+            &self.inner.tks,
+            self.session_id(),
+            datas,
+            self.payload_size(),
+            &self.inner.msgid,
+        )?;
+        self.send_raws(&pkts[..]).await
+    }
 }
 
 pub trait IUMsgerCli {
     fn packet_err(&self, addrs: &SocketAddr) -> BoxFuture<'static, ()>;
     fn check_token(&self, addrs: &SocketAddr, tks: &Option<String>) -> BoxFuture<'static, bool>;
-    fn on_bts(&self, addrs: &SocketAddr, msg: bytes::ByteBox)
+    fn on_bts(&self, addrs: &SocketAddr, msg: bytes::Bytes)
         -> BoxFuture<'static, io::Result<()>>;
     fn on_msg(&self, addrs: &SocketAddr, msg: msg::Messageu) -> BoxFuture<'static, io::Result<()>>;
 }
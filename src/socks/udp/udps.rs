@@ -1,213 +1,453 @@
-use std::{io, net::SocketAddr, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
 
-use ruisutil::asyncs::sync::{Mutex, RwLock};
-use ruisutil::bytes;
+use ruisutil::bytes::{self, BytesCut};
 
-use super::udp::UMsgerServ;
+use crate::socks::msg;
+
+use super::seal::{self, UdpSeal};
+
+// 分片头: msg id(4) + index(2) + count(2)
+pub const FRAG_HEAD_LEN: usize = 8;
 
 #[derive(Clone)]
-pub struct UdpMsgParse {
-    inner: ruisutil::ArcMut<Inner>,
-}
+pub struct UdpConfig {
+    //收发的最大数据报, 接收超过此大小的包回调 packet_err
+    pub dgram_max: usize,
+    //单包数据上限(send1bts/send1msg, 分片大小)
+    pub payload_max: usize,
+    //默认路径 MTU, 可用 set_path_mtu 按对端设置
+    pub mtu: usize,
+    //SO_RCVBUF/SO_SNDBUF, 0 为系统默认
+    pub rcvbuf: usize,
+    pub sndbuf: usize,
+    //每次最多批量读取的包数(recvmmsg, 需启用 udp-mmsg)
+    pub recv_batch: usize,
+    //解析任务数及每个任务的队列长度, 队列满时丢包
+    pub recv_workers: usize,
+    pub recv_queue: usize,
+    //处理中(排队+解析)的包上限, 0 不限制
+    pub max_inflight: usize,
 
-struct Inner {
-    prt: UMsgerServ,
-    ctx: ruisutil::Context,
-    addrs: SocketAddr,
+    //单个来源每秒包数/字节数上限, 0 不限制
+    pub lmt_pkts: u32,
+    pub lmt_bytes: u64,
+    //同时统计的来源数
+    pub lmt_max_srcs: usize,
+    //超限或 token 错误累计次数达到后封禁 ban_time, 0 不封禁
+    pub ban_strikes: u32,
+    pub ban_time: Duration,
 
-    curr: u8,
-    msgs: Vec<UdpMsgMerge>,
-    ctmout: ruisutil::Timer,
-}
+    //分片重组超时
+    pub frag_timeout: Duration,
+    //单个来源同时重组的消息数
+    pub frag_max_pending: usize,
+    //单个来源重组占用的最大内存
+    pub frag_max_bytes: usize,
+    //同时重组的来源数
+    pub frag_max_peers: usize,
+    //所有来源重组占用的总内存, 超过时从最早的消息开始丢弃
+    pub frag_max_total: usize,
+    pub frag_max_count: u16,
 
-impl UdpMsgParse {
-    pub fn new(ctx: &ruisutil::Context, prt: UMsgerServ, addrs: SocketAddr) -> Self {
-        let ctxs = ruisutil::Context::background(Some(ctx.clone()));
+    //可靠传输: 发送窗口, 最大重传次数, 重传超时(RTO)范围
+    pub rel_window: usize,
+    pub rel_max_retries: u32,
+    pub rel_rto_init: Duration,
+    pub rel_rto_min: Duration,
+    pub rel_rto_max: Duration,
+    //对端状态空闲清理
+    pub rel_idle: Duration,
+
+    //会话: 空闲超时, 最大会话数, 是否只接受已建立会话的来源
+    pub sess_idle: Duration,
+    pub sess_max: usize,
+    pub sess_required: bool,
+
+    //call: 超时内的重传次数, 应答缓存时间(重复请求直接返回缓存)
+    pub call_retries: u32,
+    pub call_cache: Duration,
+
+    //NAT: 保活间隔, 登记/连接过期时间, 打洞持续时间和发包间隔
+    pub nat_keepalive: Duration,
+    pub nat_ttl: Duration,
+    pub nat_punch_time: Duration,
+    pub nat_punch_interval: Duration,
+}
+impl Default for UdpConfig {
+    fn default() -> Self {
         Self {
-            inner: ruisutil::ArcMut::new(Inner {
-                prt: prt,
-                ctx: ctxs.clone(),
-                addrs: addrs,
+            dgram_max: 1472,
+            payload_max: 1200,
+            mtu: 1500,
+            rcvbuf: 0,
+            sndbuf: 0,
+            recv_batch: 32,
+            recv_workers: 8,
+            recv_queue: 1024,
+            max_inflight: 0,
 
-                curr: 0,
-                msgs: vec![UdpMsgMerge::new(&ctxs); 256],
-                ctmout: ruisutil::Timer::new(Duration::from_secs(30)),
-            }),
+            lmt_pkts: 0,
+            lmt_bytes: 0,
+            lmt_max_srcs: 65536,
+            ban_strikes: 3,
+            ban_time: Duration::from_secs(60),
+
+            frag_timeout: Duration::from_secs(5),
+            frag_max_pending: 32,
+            frag_max_bytes: 1024 * 1024 * 4, //4M
+            frag_max_peers: 1024,
+            frag_max_total: 1024 * 1024 * 64, //64M
+            frag_max_count: 4096,
+
+            rel_window: 64,
+            rel_max_retries: 10,
+            rel_rto_init: Duration::from_millis(300),
+            rel_rto_min: Duration::from_millis(20),
+            rel_rto_max: Duration::from_secs(5),
+            rel_idle: Duration::from_secs(60),
+
+            sess_idle: Duration::from_secs(120),
+            sess_max: 4096,
+            sess_required: false,
+
+            call_retries: 3,
+            call_cache: Duration::from_secs(10),
+
+            nat_keepalive: Duration::from_secs(15),
+            nat_ttl: Duration::from_secs(60),
+            nat_punch_time: Duration::from_secs(5),
+            nat_punch_interval: Duration::from_millis(100),
         }
     }
+}
+
+impl UdpConfig {
+    //按路径 MTU 计算单包数据大小, overhead 为包头/token/校验/加密的开销
+    pub fn payload_size(&self, mtu: Option<usize>, v6: bool, overhead: usize) -> usize {
+        let iphd = if v6 { 48 } else { 28 };
+        let mtu = mtu.unwrap_or(self.mtu);
+        let n = mtu.saturating_sub(iphd + overhead);
+        n.min(self.payload_max)
+            .min(self.dgram_max.saturating_sub(overhead))
+    }
+}
+
+//服务端与客户端共用的发包流程: 包头 -> 分片 -> 校验 -> 加密
 
-    pub fn stop(&self) {
-        self.inner.ctx.stop();
+//包头/token/会话/校验/加密的开销
+pub fn packet_overhead(tks: &Option<String>, sess: bool, crc: bool, sealed: bool) -> usize {
+    let mut rt = 7;
+    if let Some(v) = tks {
+        rt += v.len();
     }
-    pub async fn start(&self) {
-        let c = self.clone();
-        ruisutil::asyncs::task::spawn(async move {
-            c.run().await;
-        });
+    if sess {
+        rt += 8;
     }
+    if crc {
+        rt += 4;
+    }
+    if sealed {
+        rt += seal::SEAL_HEAD_LEN + seal::SEAL_TAG_LEN;
+    }
+    rt
+}
+//数据包带上已建立会话的 id, 对端据此丢弃旧会话的包
+pub fn packet_head(
+    crc: bool,
+    ctrl: u16,
+    tks: &Option<String>,
+    sess: Option<u64>,
+) -> io::Result<bytes::ByteBoxBuf> {
+    let ver = if crc {
+        msg::entity::PACKET_VER_CRC
+    } else {
+        msg::entity::PACKET_VER
+    };
+    msg::udps::packet_fmts_sess(ver, ctrl, tks, sess)
+}
+//追加校验, 加密在发送时进行(重传包每次重新加密)
+pub fn packet_finish(bts: bytes::ByteBoxBuf, crc: bool) -> bytes::Bytes {
+    let mut bts = bts;
+    if crc {
+        msg::udps::packet_crc(&mut bts);
+    }
+    bts.to_bytes()
+}
+pub fn packet_seal(sealer: &Option<UdpSeal>, bts: &[u8]) -> io::Result<bytes::Bytes> {
+    match sealer {
+        None => Ok(bytes::Bytes::from(bts.to_vec())),
+        Some(v) => Ok(bytes::Bytes::from(v.seal(bts)?)),
+    }
+}
+//单包放得下时为 12, 否则按 psz 分片为 21, 返回未加密的包
+pub fn msg_packets(
+    cfg: &UdpConfig,
+    crc: bool,
+    tks: &Option<String>,
+    sess: Option<u64>,
+    datas: bytes::ByteBoxBuf,
+    psz: usize,
+    msgid: &AtomicU32,
+) -> io::Result<Vec<bytes::Bytes>> {
+    if datas.len() <= psz {
+        let mut bts = packet_head(crc, 12, tks, sess)?;
+        bts.push_all(&datas);
+        return Ok(vec![packet_finish(bts, crc)]);
+    }
+    if psz <= FRAG_HEAD_LEN {
+        return Err(ruisutil::ioerr("payload size too small", None));
+    }
+    let fragsz = psz - FRAG_HEAD_LEN;
+    let count = (datas.len() + fragsz - 1) / fragsz;
+    if count > cfg.frag_max_count as usize {
+        return Err(ruisutil::ioerr("msg len out frag limit", None));
+    }
+    let id = msgid.fetch_add(1, Ordering::SeqCst);
+    let mut datas = datas.to_bytes();
+    let mut rt = Vec::with_capacity(count);
+    for i in 0..count {
+        let n = if datas.len() > fragsz {
+            fragsz
+        } else {
+            datas.len()
+        };
+        let hd = FragHead {
+            id: id,
+            ind: i as u16,
+            count: count as u16,
+        };
+        let mut bts = packet_head(crc, 21, tks, sess)?;
+        bts.push(frag_fmts(&hd));
+        bts.push(datas.cuts(n)?);
+        rt.push(packet_finish(bts, crc));
+    }
+    Ok(rt)
+}
+
+pub struct FragHead {
+    pub id: u32,
+    pub ind: u16,
+    pub count: u16,
+}
+
+pub fn frag_parse(buf: &mut bytes::Bytes) -> io::Result<FragHead> {
+    let bts = buf.cuts(FRAG_HEAD_LEN)?;
+    let rt = FragHead {
+        id: ruisutil::byte_2i(&bts[0..4]) as u32,
+        ind: ruisutil::byte_2i(&bts[4..6]) as u16,
+        count: ruisutil::byte_2i(&bts[6..8]) as u16,
+    };
+    if rt.count <= 0 || rt.ind >= rt.count {
+        return Err(ruisutil::ioerr(
+            format!(
+                "frag param err:id={},ind={},count={}",
+                rt.id, rt.ind, rt.count
+            ),
+            None,
+        ));
+    }
+    Ok(rt)
+}
+pub fn frag_fmts(hd: &FragHead) -> Vec<u8> {
+    let mut rt = Vec::with_capacity(FRAG_HEAD_LEN);
+    rt.extend(ruisutil::i2_byte(hd.id as i64, 4));
+    rt.extend(ruisutil::i2_byte(hd.ind as i64, 2));
+    rt.extend(ruisutil::i2_byte(hd.count as i64, 2));
+    rt
+}
+
+//单个来源的分片重组
+pub struct UdpMsgParse {
+    addrs: SocketAddr,
+    msgs: HashMap<u32, UdpMsgMerge>,
+    bytes: usize,
+    uptm: Instant,
+}
 
-    async fn run(&self) {
-        let ins = unsafe { self.inner.muts() };
-        self.inner.ctmout.reset();
-        for it in &self.inner.msgs {
-            it.set_prt(self.clone());
+impl UdpMsgParse {
+    pub fn new(addrs: SocketAddr) -> Self {
+        Self {
+            addrs: addrs,
+            msgs: HashMap::new(),
+            bytes: 0,
+            uptm: Instant::now(),
         }
-        let mut i = 0;
-        while !self.inner.ctx.done() {
-            if self.inner.ctmout.tmout() {
-                self.stop();
+    }
+
+    pub fn addrs(&self) -> &SocketAddr {
+        &self.addrs
+    }
+    pub fn is_empty(&self) -> bool {
+        self.msgs.is_empty()
+    }
+    //重组中占用的字节数
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+    //最早更新的未完成消息
+    fn oldest(&self) -> Option<(u32, Instant)> {
+        let mut old: Option<(u32, Instant)> = None;
+        for (k, v) in &self.msgs {
+            match &old {
+                Some((_, tm)) if *tm <= v.uptm => {}
+                _ => old = Some((*k, v.uptm)),
             }
+        }
+        old
+    }
 
-            i = self.inner.curr as usize;
-            loop {
-                i += 1;
-                if i >= 255 {
-                    i = 1;
-                }
-                let it = &self.inner.msgs[i];
-                if it.is_finished() {
-                    break;
-                }
-                if it.tick() {
-                    // send re
-                }
+    //清理超时的消息, 返回清理数量
+    pub fn clean(&mut self, cfg: &UdpConfig) -> usize {
+        let mut rms = Vec::new();
+        for (k, v) in &self.msgs {
+            if v.uptm.elapsed() > cfg.frag_timeout {
+                rms.push(*k);
             }
-            ruisutil::asyncs::sleep(Duration::from_millis(2)).await;
         }
-        self.inner.prt.remove(&self.inner.addrs).await;
+        for k in &rms {
+            self.drop_msg(*k);
+        }
+        rms.len()
+    }
+    fn drop_msg(&mut self, id: u32) {
+        if let Some(v) = self.msgs.remove(&id) {
+            self.bytes -= v.bytes;
+        }
     }
 
-    fn canuse_id(&self, id: u8) -> bool {
-        if self.inner.curr == 0 {
-            return true;
+    //收齐后返回完整数据
+    pub fn push(
+        &mut self,
+        cfg: &UdpConfig,
+        hd: FragHead,
+        data: bytes::Bytes,
+    ) -> io::Result<Option<bytes::Bytes>> {
+        self.uptm = Instant::now();
+        if hd.count > cfg.frag_max_count {
+            return Err(ruisutil::ioerr(
+                format!("frag count out limit:{}", hd.count),
+                None,
+            ));
         }
-        let tmp = (self.inner.curr as u16) + 100;
-        if id >= self.inner.curr {
-            if self.inner.curr - id < 100 {
-                return true;
+        if !self.msgs.contains_key(&hd.id) {
+            if self.msgs.len() >= cfg.frag_max_pending {
+                self.drop_oldest();
             }
-        } else if tmp >= 255 {
-            if id < (tmp - 254) as u8 {
-                return true;
+            self.msgs.insert(hd.id, UdpMsgMerge::new(hd.count));
+        }
+        let ln = data.len();
+        let it = match self.msgs.get_mut(&hd.id) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if it.total != hd.count {
+            self.drop_msg(hd.id);
+            return Err(ruisutil::ioerr(
+                format!("frag count changed:id={}", hd.id),
+                None,
+            ));
+        }
+        if !it.push(hd.ind, data) {
+            return Ok(None);
+        }
+        self.bytes += ln;
+        if self.bytes > cfg.frag_max_bytes {
+            self.drop_msg(hd.id);
+            return Err(ruisutil::ioerr("frag bytes out limit!!", None));
+        }
+        if !it.is_finished() {
+            return Ok(None);
+        }
+        let rt = match self.msgs.remove(&hd.id) {
+            None => return Ok(None),
+            Some(v) => {
+                self.bytes -= v.bytes;
+                v.merge()
             }
+        };
+        Ok(Some(rt))
+    }
+    fn drop_oldest(&mut self) {
+        if let Some((k, _)) = self.oldest() {
+            self.drop_msg(k);
         }
-
-        false
     }
-    pub async fn ctrls(&self, buf: bytes::Bytes, ctrl: u8, id: u8, ind: u16, ln: u16) {
-        self.inner.ctmout.reset();
-        match ctrl {
-            //客户端请求发送
-            11 => if self.canuse_id(id) {},
-            20 => {
-                if self.canuse_id(id) {
-                    self.parse(buf, id, ind, ln).await
+    pub fn idle(&self) -> Duration {
+        self.uptm.elapsed()
+    }
+}
+
+//所有来源重组总量超过 max 时, 跨来源丢弃最早的消息, 返回丢弃数量
+pub fn frag_evict(peers: &mut HashMap<SocketAddr, UdpMsgParse>, max: usize) -> usize {
+    let mut total: usize = peers.values().map(|v| v.bytes).sum();
+    let mut rt = 0;
+    while total > max {
+        let mut old: Option<(SocketAddr, Instant)> = None;
+        for (k, v) in peers.iter() {
+            if let Some((_, tm)) = v.oldest() {
+                match &old {
+                    Some((_, otm)) if *otm <= tm => {}
+                    _ => old = Some((k.clone(), tm)),
                 }
             }
-            _ => {}
-        }
-    }
-    pub async fn parse(&self, buf: bytes::Bytes, id: u8, ind: u16, ln: u16) {
-        if self.inner.curr == 0 {
-            unsafe { self.inner.muts().curr = id };
-        }
-        let it = &self.inner.msgs[id as usize];
-        it.push(buf, ind, ln).await;
-    }
-    /*
-    async fn check(&self) -> bool {
-        let lkv = self.inner.datas.read().await;
-        let itrs = lkv.iter();
-        let mut i = 0u32;
-        let mut inds = Vec::with_capacity(10);
-        for it in itrs {
-            if let None = it {
-                inds.push(i);
-                i += 1;
-            }
         }
-
-        // send needs
-
-        inds.len() <= 0
-    } */
-    async fn push(&self) {
-        // let ins = unsafe { self.inner.muts() };
+        let v = match old {
+            None => break,
+            Some((k, _)) => match peers.get_mut(&k) {
+                None => break,
+                Some(v) => v,
+            },
+        };
+        let ln = v.bytes;
+        v.drop_oldest();
+        total -= ln - v.bytes;
+        rt += 1;
     }
+    rt
 }
 
-#[derive(Clone)]
+//分片按序号稀疏存放, 只为已到达的分片占用内存
 struct UdpMsgMerge {
-    inner: ruisutil::ArcMut<Inners>,
-}
-
-struct Inners {
-    prt: Option<UdpMsgParse>,
-    ctx: ruisutil::Context,
-    finished: bool,
-
-    ln: u16,
-    count: u16,
-    tmout: ruisutil::Timer,
-    datas: RwLock<Vec<Option<bytes::Bytes>>>,
+    total: u16,
+    bytes: usize,
+    uptm: Instant,
+    datas: BTreeMap<u16, bytes::Bytes>,
 }
 impl UdpMsgMerge {
-    pub fn new(ctx: &ruisutil::Context) -> Self {
+    fn new(total: u16) -> Self {
         Self {
-            inner: ruisutil::ArcMut::new(Inners {
-                prt: None,
-                ctx: ctx.clone(),
-                finished: true,
-                ln: 0,
-                count: 0,
-                tmout: ruisutil::Timer::new(Duration::from_millis(5)),
-                datas: RwLock::new(Vec::new()),
-            }),
+            total: total,
+            bytes: 0,
+            uptm: Instant::now(),
+            datas: BTreeMap::new(),
         }
     }
 
-    pub fn set_prt(&self, a: UdpMsgParse) {
-        unsafe { self.inner.muts().prt = Some(a) };
-    }
-
-    pub async fn push(&self, buf: bytes::Bytes, ind: u16, ln: u16) {
-        let ins = unsafe { self.inner.muts() };
-        if self.inner.finished || self.inner.ln != ln {
-            {
-                let mut lkv = self.inner.datas.write().await;
-                *lkv = vec![None; ln as usize];
-            }
-            ins.ln = ln;
-            ins.count = 0;
-            ins.finished = false;
-        }
-        let mut lkv = self.inner.datas.write().await;
-        if let None = &lkv[ind as usize] {
-            lkv[ind as usize] = Some(buf);
-            ins.count += 1;
-        }
-        if ins.count >= ln {
-            ins.finished = true;
-            if let Some(v) = &self.inner.prt {
-                v.push().await;
-            }
-            {
-                let mut lkv = self.inner.datas.write().await;
-                *lkv = Vec::new();
-            }
+    //重复分片返回 false
+    fn push(&mut self, ind: u16, buf: bytes::Bytes) -> bool {
+        self.uptm = Instant::now();
+        if ind >= self.total || self.datas.contains_key(&ind) {
+            return false;
         }
-
-        self.inner.tmout.reset();
+        self.bytes += buf.len();
+        self.datas.insert(ind, buf);
+        true
     }
 
-    pub fn is_finished(&self) -> bool {
-        self.inner.finished
+    fn is_finished(&self) -> bool {
+        self.datas.len() >= self.total as usize
     }
-    pub fn tick(&self) -> bool {
-        if self.inner.finished {
-            false
-        } else {
-            self.inner.tmout.tick()
+    fn merge(self) -> bytes::Bytes {
+        let mut buf = bytes::ByteBoxBuf::new();
+        for (_, v) in self.datas {
+            buf.push(v);
         }
+        buf.to_bytes()
     }
 }
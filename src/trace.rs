@@ -0,0 +1,286 @@
+use std::{
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::ext::MsgExt;
+
+/*
+  W3C Trace Context, traceparent 为全小写十六进制:
+  version(2)-trace_id(32)-parent_id(16)-flags(2)
+  00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+  请求经 v3 扩展区携带, Messager 消息经 heads 前缀携带(帧头置 FLAG_TRACE)
+*/
+pub const TRACE_VER: u8 = 0;
+pub const TRACE_SAMPLED: u8 = 0x01;
+//tracestate 最大长度
+pub const TRACE_STATE_MAX: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceParent {
+    pub version: u8,
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub flags: u8,
+}
+impl TraceParent {
+    pub fn new_root() -> Self {
+        let mut trace_id = [0u8; 16];
+        trace_id[..8].copy_from_slice(&rand_id().to_be_bytes());
+        trace_id[8..].copy_from_slice(&rand_id().to_be_bytes());
+        Self {
+            version: TRACE_VER,
+            trace_id: trace_id,
+            span_id: rand_id().to_be_bytes(),
+            flags: TRACE_SAMPLED,
+        }
+    }
+    //同一 trace 下的新 span
+    pub fn child(&self) -> Self {
+        Self {
+            version: TRACE_VER,
+            trace_id: self.trace_id,
+            span_id: rand_id().to_be_bytes(),
+            flags: self.flags,
+        }
+    }
+    pub fn sampled(&self) -> bool {
+        self.flags & TRACE_SAMPLED != 0
+    }
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let ls: Vec<&str> = s.trim().splitn(5, '-').collect();
+        if ls.len() < 4 {
+            return Err(ruisutil::ioerr("traceparent fmt err", None));
+        }
+        let mut ver = [0u8; 1];
+        hex_parse(ls[0], &mut ver)?;
+        //00 版本不允许后缀, 未来版本只取前4段
+        if ver[0] == 0xff || (ver[0] == TRACE_VER && ls.len() > 4) {
+            return Err(ruisutil::ioerr("traceparent version err", None));
+        }
+        let mut rt = Self {
+            version: ver[0],
+            trace_id: [0u8; 16],
+            span_id: [0u8; 8],
+            flags: 0,
+        };
+        hex_parse(ls[1], &mut rt.trace_id)?;
+        hex_parse(ls[2], &mut rt.span_id)?;
+        let mut flags = [0u8; 1];
+        hex_parse(ls[3], &mut flags)?;
+        rt.flags = flags[0];
+        if rt.trace_id == [0u8; 16] || rt.span_id == [0u8; 8] {
+            return Err(ruisutil::ioerr("traceparent id zero err", None));
+        }
+        Ok(rt)
+    }
+}
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}-", self.version)?;
+        for v in &self.trace_id {
+            write!(f, "{:02x}", v)?;
+        }
+        f.write_str("-")?;
+        for v in &self.span_id {
+            write!(f, "{:02x}", v)?;
+        }
+        write!(f, "-{:02x}", self.flags)
+    }
+}
+
+//traceparent + tracestate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub parent: TraceParent,
+    pub state: Option<String>,
+}
+impl TraceContext {
+    pub fn new_root() -> Self {
+        Self {
+            parent: TraceParent::new_root(),
+            state: None,
+        }
+    }
+    //新 span, tracestate 原样传递
+    pub fn child(&self) -> Self {
+        Self {
+            parent: self.parent.child(),
+            state: self.state.clone(),
+        }
+    }
+    pub fn from_ext(ext: &MsgExt) -> io::Result<Option<Self>> {
+        let parent = match &ext.trace_parent {
+            None => return Ok(None),
+            Some(v) => TraceParent::parse(v)?,
+        };
+        //tracestate 不合法时丢弃, 不影响 traceparent
+        let state = match &ext.trace_state {
+            Some(v) if state_ok(v) => Some(v.clone()),
+            _ => None,
+        };
+        Ok(Some(Self {
+            parent: parent,
+            state: state,
+        }))
+    }
+    pub fn to_ext(&self, ext: &mut MsgExt) {
+        ext.trace_parent = Some(self.parent.to_string());
+        ext.trace_state = self.state.clone();
+    }
+
+    //Messager 消息没有扩展区: 帧头置 FLAG_TRACE 时, heads 前部为只含追踪信息的扩展区, 之后为原 heads
+    pub(crate) fn heads_inject(&self, hds: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        let mut ext = MsgExt::default();
+        self.to_ext(&mut ext);
+        let exts = ext.encode()?;
+        let hds = hds.unwrap_or_default();
+        let mut rt = Vec::with_capacity(exts.len() + hds.len());
+        rt.extend_from_slice(&exts[..]);
+        rt.extend_from_slice(hds);
+        Ok(rt)
+    }
+    //返回追踪信息及剩余 heads 的起始位置
+    pub(crate) fn heads_extract(hds: &[u8]) -> io::Result<(Self, usize)> {
+        let (_, ln) = MsgExt::decode_head(hds)?;
+        let ext = MsgExt::decode(hds)?;
+        let pos = crate::ext::EXT_HEAD_LEN + ln;
+        match Self::from_ext(&ext)? {
+            None => Err(ruisutil::ioerr("heads trace err", None)),
+            Some(v) => Ok((v, pos)),
+        }
+    }
+}
+
+fn state_ok(s: &str) -> bool {
+    s.len() <= TRACE_STATE_MAX && s.bytes().all(|c| c >= 0x20 && c <= 0x7e)
+}
+fn hex_parse(s: &str, out: &mut [u8]) -> io::Result<()> {
+    let lower = s
+        .bytes()
+        .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c));
+    if s.len() != out.len() * 2 || !lower {
+        return Err(ruisutil::ioerr(format!("trace hex err:{}", s), None));
+    }
+    for i in 0..out.len() {
+        out[i] = match u8::from_str_radix(&s[i * 2..i * 2 + 2], 16) {
+            Ok(v) => v,
+            Err(e) => return Err(ruisutil::ioerr(e, None)),
+        };
+    }
+    Ok(())
+}
+//非零随机 id, 不引入额外依赖: RandomState 每次取新种子, 再混入时间和序号
+pub(crate) fn rand_id() -> u64 {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    loop {
+        let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+        h.write_u64(SEQ.fetch_add(1, Ordering::Relaxed));
+        h.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        let v = h.finish();
+        if v != 0 {
+            return v;
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::str::FromStr;
+
+    use opentelemetry::{
+        global,
+        trace::{
+            FutureExt, SpanContext, SpanId, SpanKind, Status, TraceContextExt, TraceFlags, TraceId,
+            TraceState, Tracer,
+        },
+        KeyValue,
+    };
+
+    use super::{TraceContext, TraceParent, TRACE_VER};
+
+    impl TraceContext {
+        //作为远端父 span 的 OpenTelemetry Context
+        pub fn to_otel(&self) -> opentelemetry::Context {
+            let state = match &self.state {
+                Some(v) => TraceState::from_str(v).unwrap_or_default(),
+                None => TraceState::default(),
+            };
+            let sc = SpanContext::new(
+                TraceId::from_bytes(self.parent.trace_id),
+                SpanId::from_bytes(self.parent.span_id),
+                TraceFlags::new(self.parent.flags),
+                true,
+                state,
+            );
+            opentelemetry::Context::new().with_remote_span_context(sc)
+        }
+        pub fn from_otel(cx: &opentelemetry::Context) -> Option<Self> {
+            let span = cx.span();
+            let sc = span.span_context();
+            if !sc.is_valid() {
+                return None;
+            }
+            let state = sc.trace_state().header();
+            Some(Self {
+                parent: TraceParent {
+                    version: TRACE_VER,
+                    trace_id: sc.trace_id().to_bytes(),
+                    span_id: sc.span_id().to_bytes(),
+                    flags: sc.trace_flags().to_u8(),
+                },
+                state: if state.is_empty() { None } else { Some(state) },
+            })
+        }
+    }
+
+    //处理函数外层的 server span, 父 span 取自请求; Context::span 随之改为该 span
+    pub(crate) fn start(c: &crate::Context) -> opentelemetry::Context {
+        let parent = match c.trace() {
+            Some(v) => v.to_otel(),
+            None => opentelemetry::Context::new(),
+        };
+        let tracer = global::tracer("hbtp");
+        let name = if c.command().is_empty() {
+            format!("hbtp {}", c.control())
+        } else {
+            format!("hbtp {} {}", c.control(), c.command())
+        };
+        let span = tracer
+            .span_builder(name)
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("hbtp.control", c.control() as i64),
+                KeyValue::new("hbtp.version", c.version() as i64),
+            ])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+        if let Some(v) = TraceContext::from_otel(&cx) {
+            c.set_span(v);
+        }
+        cx
+    }
+    pub(crate) async fn run<F>(cx: &opentelemetry::Context, ft: F) -> F::Output
+    where
+        F: std::future::Future,
+    {
+        ft.with_context(cx.clone()).await
+    }
+    pub(crate) fn end(cx: &opentelemetry::Context, err: Option<String>) {
+        let span = cx.span();
+        if let Some(v) = err {
+            span.set_status(Status::error(v));
+        }
+        span.end();
+    }
+}
+#[cfg(feature = "otel")]
+pub(crate) use otel::{end as otel_end, run as otel_run, start as otel_start};
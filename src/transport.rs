@@ -0,0 +1,248 @@
+use std::{io, net::SocketAddr};
+
+use ruisutil::asyncs::{
+    net::{TcpListener, TcpStream},
+    BoxFuture,
+};
+
+#[cfg(feature = "tokios")]
+pub use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(not(feature = "tokios"))]
+pub use async_std::io::{Read as AsyncRead, Write as AsyncWrite};
+
+#[cfg(all(unix, feature = "tokios"))]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(all(unix, not(feature = "tokios")))]
+use async_std::os::unix::net::{UnixListener, UnixStream};
+
+/*
+  传输层抽象: Engine/Context/Request/Response/Messager 只依赖 Stream,
+  内置 TCP 与 unix socket 实现, 其他(内存管道、TLS)实现 Stream 即可接入
+*/
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync {
+    //非 IP 连接返回 Unsupported
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(ruisutil::ioerr(
+            "stream no ip addr",
+            Some(io::ErrorKind::Unsupported),
+        ))
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(ruisutil::ioerr(
+            "stream no ip addr",
+            Some(io::ErrorKind::Unsupported),
+        ))
+    }
+    //对端进程身份, 仅 unix socket 支持
+    fn peer_cred(&self) -> io::Result<PeerCred> {
+        Err(ruisutil::ioerr(
+            "stream no peer cred",
+            Some(io::ErrorKind::Unsupported),
+        ))
+    }
+}
+pub type BoxStream = Box<dyn Stream>;
+
+pub trait Listener: Send + Sync {
+    fn accept(&self) -> BoxFuture<'_, io::Result<BoxStream>>;
+}
+
+impl Stream for BoxStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        (**self).local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+    fn peer_cred(&self) -> io::Result<PeerCred> {
+        (**self).peer_cred()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+    //部分平台取不到
+    pub pid: Option<i32>,
+}
+
+//已是 BoxStream 时不再二次装箱
+pub(crate) fn boxed<S: Stream + 'static>(conn: S) -> BoxStream {
+    let mut conn = Some(conn);
+    let anys: &mut dyn std::any::Any = &mut conn;
+    match anys.downcast_mut::<Option<BoxStream>>() {
+        Some(v) => v.take().unwrap(),
+        None => Box::new(conn.unwrap()),
+    }
+}
+
+//关闭写方向, 对端读到 EOF
+pub(crate) async fn shutdownw(conn: &mut BoxStream) -> io::Result<()> {
+    #[cfg(feature = "tokios")]
+    {
+        tokio::io::AsyncWriteExt::shutdown(conn).await
+    }
+    #[cfg(not(feature = "tokios"))]
+    {
+        async_std::io::WriteExt::close(conn).await
+    }
+}
+
+impl Stream for TcpStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+impl Listener for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let (conn, _) = TcpListener::accept(self).await?;
+            Ok(Box::new(conn) as BoxStream)
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn peer_cred(&self) -> io::Result<PeerCred> {
+        use std::os::unix::io::AsRawFd;
+        unix_cred(self.as_raw_fd())
+    }
+}
+#[cfg(unix)]
+impl Listener for UnixListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let (conn, _) = UnixListener::accept(self).await?;
+            Ok(Box::new(conn) as BoxStream)
+        })
+    }
+}
+
+#[cfg(all(unix, feature = "unix-cred"))]
+fn unix_cred(fd: std::os::unix::io::RawFd) -> io::Result<PeerCred> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+        let mut ln = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let rt = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut ln,
+            )
+        };
+        if rt != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCred {
+            uid: cred.uid,
+            gid: cred.gid,
+            pid: Some(cred.pid),
+        })
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let mut uid = 0;
+        let mut gid = 0;
+        if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCred {
+            uid: uid,
+            gid: gid,
+            pid: None,
+        })
+    }
+}
+#[cfg(all(unix, not(feature = "unix-cred")))]
+fn unix_cred(_: std::os::unix::io::RawFd) -> io::Result<PeerCred> {
+    Err(ruisutil::ioerr(
+        "peer cred need feature unix-cred",
+        Some(io::ErrorKind::Unsupported),
+    ))
+}
+
+//绑定前清理残留的 socket 文件(非 socket 文件不动, 仍有进程监听的不动)
+//指定权限时先在 0700 的临时目录中绑定并设置权限, 再移到目标路径, 避免权限生效前被连接
+#[cfg(unix)]
+pub(crate) async fn unix_bind(
+    path: &std::path::Path,
+    mode: Option<u32>,
+) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    if let Ok(v) = std::fs::symlink_metadata(path) {
+        if !v.file_type().is_socket() {
+            return Err(ruisutil::ioerr(
+                format!("unix path exist and not socket:{}", path.display()),
+                Some(io::ErrorKind::AddrInUse),
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(ruisutil::ioerr(
+                format!("unix path in use:{}", path.display()),
+                Some(io::ErrorKind::AddrInUse),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let mode = match mode {
+        None => return unix_listen(path).await,
+        Some(v) => v,
+    };
+    //每次调用使用不同的临时目录, 同一进程并发绑定互不影响
+    let name = format!(".hbtp{}.{:x}", std::process::id(), crate::trace::rand_id());
+    let dir = match path.parent() {
+        Some(v) if !v.as_os_str().is_empty() => v.join(name),
+        _ => std::path::PathBuf::from(name),
+    };
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("s");
+    //目录创建之后的任何错误都走到下面的清理
+    let rt = async {
+        let lsr = unix_listen(&tmp).await?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&tmp, path)?;
+        Ok(lsr)
+    }
+    .await;
+    let _ = std::fs::remove_file(&tmp);
+    let _ = std::fs::remove_dir(&dir);
+    rt
+}
+#[cfg(unix)]
+async fn unix_listen(path: &std::path::Path) -> io::Result<UnixListener> {
+    #[cfg(feature = "tokios")]
+    let lsr = UnixListener::bind(path)?;
+    #[cfg(not(feature = "tokios"))]
+    let lsr = UnixListener::bind(async_std::path::Path::new(path.as_os_str())).await?;
+    Ok(lsr)
+}
+#[cfg(unix)]
+pub(crate) async fn unix_connect(path: &str) -> io::Result<BoxStream> {
+    let conn = UnixStream::connect(path).await?;
+    Ok(Box::new(conn))
+}
+#[cfg(not(unix))]
+pub(crate) async fn unix_connect(path: &str) -> io::Result<BoxStream> {
+    Err(ruisutil::ioerr(
+        format!("unix socket not supported:{}", path),
+        Some(io::ErrorKind::Unsupported),
+    ))
+}
+
+//监听结束时删除 socket 文件
+#[cfg(unix)]
+pub(crate) struct UnixGuard(pub std::path::PathBuf);
+#[cfg(unix)]
+impl Drop for UnixGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -4,7 +4,7 @@
 cmds="$1"
 
 if [ -z $cmds];then
-    cmds="hbtp_request"
+    cmds="hbtp_roundtrip"
 fi
 
 cargo test --features asyncs --no-default-features -- "tests::$cmds" --exact --nocapture